@@ -11,8 +11,6 @@ use std::thread;
 use log::{error, info};
 
 use mj::*;
-use sdfs::interface::get_file;
-use sdfs::put_file;
 
 fn main() {
     setup_logger();
@@ -197,8 +195,7 @@ fn handle_maple_req(
     let maple_worker_idxs = get_available_workers(num_maples)?;
 
     // Find input size - examine input file using SDFS interface
-    if let Err(err) = get_file(
-        LEADER_VM_IDX,
+    if let Err(err) = sdfs().get(
         &sdfs_src_filename,
         (MJ_FILES.to_string() + &sdfs_src_filename).as_str(),
     ) {
@@ -322,16 +319,15 @@ fn handle_maple_reply(
             // Check if entry is for this job
             if inter_file.eq(inter) {
                 to_remove.push((inter.clone(), key.clone()));
+                // Each key's file goes in the job's directory, where juice lists them
                 let final_keyfile_name = inter_file.clone() + "_" + key.as_str();
+                let sdfs_keyfile_name = inter_file.clone() + "/" + key.as_str();
                 let mut final_file =
                     File::create(String::from(MJ_FILES) + final_keyfile_name.as_str()).unwrap();
                 for sdfs_file in files {
-                    get_file(
-                        LEADER_VM_IDX,
-                        &sdfs_file,
-                        &(String::from(MJ_FILES) + &sdfs_file),
-                    )
-                    .unwrap();
+                    sdfs()
+                        .get(&sdfs_file, &(String::from(MJ_FILES) + &sdfs_file))
+                        .unwrap();
                     final_file
                         .write(
                             fs::read_to_string(String::from(MJ_FILES) + sdfs_file)
@@ -344,13 +340,13 @@ fn handle_maple_reply(
                         .output()
                         .unwrap();
                 }
-                keys_vec.push(final_keyfile_name.clone());
-                put_file(
-                    LEADER_VM_IDX,
-                    &(String::from(MJ_FILES) + &final_keyfile_name),
-                    &final_keyfile_name,
-                )
-                .unwrap();
+                keys_vec.push(sdfs_keyfile_name.clone());
+                sdfs()
+                    .put(
+                        &(String::from(MJ_FILES) + &final_keyfile_name),
+                        &sdfs_keyfile_name,
+                    )
+                    .unwrap();
             }
         }
 
@@ -364,6 +360,14 @@ fn handle_maple_reply(
     }
 }
 
+// Key files of a maple job stored in SDFS, in the job's directory
+fn list_job_keyfiles(inter_file_prefix: &str) -> Option<Vec<String>> {
+    match sdfs().list_prefix(&(String::from(inter_file_prefix) + "/")) {
+        Ok(keyfiles) if !keyfiles.is_empty() => Some(keyfiles),
+        _ => None,
+    }
+}
+
 // TODO: handle juice - partition input (by sets of keys), delegate to available workers, send tasks to workers, listen for response and send response to client when done. delete intermediate files when done if specified
 fn handle_juice_req(
     mut stream: &mut TcpStream,
@@ -389,12 +393,15 @@ fn handle_juice_req(
     // Find num_juice available workers
     let juice_worker_idxs = get_available_workers(num_juices)?;
 
-    // Find input size - use job_keys dict
+    // Find input size - use job_keys dict, else the key files in the job's SDFS directory
+    // (e.g. for a maple run before the leader restarted)
     let keys_list: Vec<String>;
     {
         let job_keys_locked = job_keys.lock().unwrap();
         if let Some(val) = job_keys_locked.get(&inter_file_prefix) {
             keys_list = val.to_vec();
+        } else if let Some(val) = list_job_keyfiles(&inter_file_prefix) {
+            keys_list = val;
         } else {
             error!(
                 "Coudn't find any keys for this juice task: {}",
@@ -509,7 +516,7 @@ fn handle_juice_reply(
             File::create(String::from(MJ_FILES) + &output_filename).unwrap();
         for worker_output in output_vec {
             let local_workeroutput = String::from(MJ_FILES) + worker_output;
-            get_file(LEADER_VM_IDX, &worker_output, &local_workeroutput).unwrap();
+            sdfs().get(&worker_output, &local_workeroutput).unwrap();
             local_finaloutput
                 .write(
                     fs::read_to_string(local_workeroutput.clone())
@@ -519,12 +526,12 @@ fn handle_juice_reply(
                 .unwrap();
             Command::new("rm").arg(local_workeroutput).output().unwrap();
         }
-        put_file(
-            LEADER_VM_IDX,
-            &(String::from(MJ_FILES) + &output_filename),
-            &output_filename,
-        )
-        .unwrap();
+        sdfs()
+            .put(
+                &(String::from(MJ_FILES) + &output_filename),
+                &output_filename,
+            )
+            .unwrap();
     }
 }
 
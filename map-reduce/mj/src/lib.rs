@@ -2,6 +2,9 @@ use log4rs;
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::sync::OnceLock;
+
+use sdfs::SdfsClient;
 
 // <----------------- COMMON UTILITIES ----------------- >
 // Addresses of all servers
@@ -30,6 +33,12 @@ pub const WORKER_TEMP_OUTPUT_SORTED: &str = "mj/files/sorted-out";
 pub const LEADER_VM_ID: &str = VM_LIST[0];
 pub const LEADER_VM_IDX: u8 = 0;
 
+// Client of SDFS, which holds the jobs' inputs, executables and outputs
+pub fn sdfs() -> &'static SdfsClient {
+    static CLIENT: OnceLock<SdfsClient> = OnceLock::new();
+    CLIENT.get_or_init(|| SdfsClient::new(0))
+}
+
 pub const SUCCESS_STATUS_CODE: u8 = 0;
 pub const ERROR_STATUS_CODE: u8 = 1;
 
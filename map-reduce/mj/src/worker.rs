@@ -6,7 +6,6 @@ use std::thread;
 
 use log::info;
 use mj::*;
-use sdfs::{get_file, put_file};
 
 fn main() {
     let leader_listener = thread::spawn(move || leader_listen());
@@ -57,11 +56,11 @@ fn handle_maple_req(mut stream: &mut TcpStream) {
 
     // Get data file + executable
     let localfile_path = String::from(MJ_FILES) + &sdfs_file;
-    get_file(LEADER_VM_IDX, &sdfs_file, &localfile_path).unwrap();
+    sdfs().get(&sdfs_file, &localfile_path).unwrap();
     let localfile = File::open(localfile_path).unwrap();
     let buf_read = BufReader::new(localfile);
     let local_exe_path = String::from(MJ_FILES) + &maple_exe;
-    get_file(LEADER_VM_IDX, &maple_exe, &local_exe_path).unwrap();
+    sdfs().get(&maple_exe, &local_exe_path).unwrap();
 
     // Command to exec each time
     let mut command = get_command(
@@ -138,12 +137,9 @@ fn handle_maple_req(mut stream: &mut TcpStream) {
     println!("Key files: {:?}", output_files);
 
     for (_, filename) in output_files.iter() {
-        put_file(
-            LEADER_VM_IDX,
-            &(String::from(MJ_FILES) + filename.as_str()),
-            &filename,
-        )
-        .unwrap();
+        sdfs()
+            .put(&(String::from(MJ_FILES) + filename.as_str()), &filename)
+            .unwrap();
     }
 
     // Construct reply to leader
@@ -193,12 +189,12 @@ fn handle_juice_req(mut stream: &mut TcpStream) {
 
     // Get executable
     let local_exe_path = String::from(MJ_FILES) + &juice_exe;
-    get_file(LEADER_VM_IDX, &juice_exe, &local_exe_path).unwrap();
+    sdfs().get(&juice_exe, &local_exe_path).unwrap();
 
     for key_filename in key_filenames {
-        // Get data file
-        let localfile_path = String::from(MJ_FILES) + &key_filename;
-        get_file(LEADER_VM_IDX, &key_filename, &localfile_path).unwrap();
+        // Get data file, kept flat locally
+        let localfile_path = String::from(MJ_FILES) + &key_filename.replace('/', "_");
+        sdfs().get(&key_filename, &localfile_path).unwrap();
 
         // Command to exec each time
         let empty: Vec<String> = Vec::new();
@@ -222,7 +218,9 @@ fn handle_juice_req(mut stream: &mut TcpStream) {
     // Write the output to sdfs
     let worker_id = get_vm_id();
     let sdfs_output_filename = output_filename.clone() + "_" + worker_id.to_string().as_str();
-    put_file(LEADER_VM_IDX, WORKER_TEMP_OUTPUT, &sdfs_output_filename).unwrap();
+    sdfs()
+        .put(WORKER_TEMP_OUTPUT, &sdfs_output_filename)
+        .unwrap();
 
     Command::new("rm").arg(WORKER_TEMP_OUTPUT).output().unwrap();
 
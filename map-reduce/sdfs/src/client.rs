@@ -1,11 +1,10 @@
 extern crate chrono;
 
+use chrono::{Local, TimeZone};
 use std::fs;
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::io;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::Read;
 
 use sdfs::*;
 
@@ -16,7 +15,8 @@ fn main() {
     let _ = id_file.read_to_string(&mut id_buf);
     let client_id: u8 = id_buf.trim().parse().unwrap();
 
-    let master = get_master();
+    let client = SdfsClient::new(client_id);
+    let mut master = client.master();
     println!("Master found by C: {}", master);
 
     loop {
@@ -35,72 +35,112 @@ fn main() {
         }
 
         let arguments: Vec<&str> = input.split_whitespace().collect();
+        if arguments.is_empty() {
+            continue;
+        }
 
         if arguments[0] == "put" && arguments.len() == 3 {
-            println!(
-                "[SDFS] {}",
-                put_file(master, client_id, arguments[1], arguments[2])
-            )
+            println!("[SDFS] {}", put_file(&client, arguments[1], arguments[2]))
         } else if arguments[0] == "get" && arguments.len() == 3 {
-            println!(
-                "[SDFS] {}",
-                get_file(master, client_id, arguments[1], arguments[2])
-            )
+            println!("[SDFS] {}", get_file(&client, arguments[1], arguments[2]))
+        } else if arguments[0] == "get-versions" && arguments.len() == 4 {
+            match arguments[2].parse::<u8>() {
+                Ok(num_versions) if num_versions > 0 => println!(
+                    "[SDFS] {}",
+                    get_versions(&client, arguments[1], num_versions, arguments[3])
+                ),
+                _ => println!("Usage: get-versions sdfsfilename num-versions localfilename"),
+            }
+        } else if arguments[0] == "delete" && arguments.len() == 2 {
+            println!("[SDFS] {}", delete_file(&client, arguments[1]))
+        } else if arguments[0] == "multiread" && arguments.len() >= 4 {
+            match parse_nodes(&arguments[3..]) {
+                Some(nodes) => multiread_file(&client, arguments[1], arguments[2], nodes),
+                None => println!("Usage: multiread sdfsfilename localfilename VMi VMj ..."),
+            }
         } else if arguments[0] == "ls" && arguments.len() == 2 {
-            list_file(master, arguments[1]);
+            list_file(&client, arguments[1]);
+        } else if arguments[0] == "list" && arguments.len() <= 2 {
+            list_files(&client, arguments.get(1).copied().unwrap_or(""));
+        } else if arguments[0] == "glob" && arguments.len() == 2 {
+            glob_files(&client, arguments[1]);
+        } else if arguments[0] == "lsdir" && arguments.len() <= 2 {
+            list_dir(&client, arguments.get(1).copied().unwrap_or(""));
+        } else if arguments[0] == "mkdir" && arguments.len() == 2 {
+            println!("[SDFS] {}", make_dir(&client, arguments[1]))
+        } else if arguments[0] == "mv" && arguments.len() == 3 {
+            println!("[SDFS] {}", rename(&client, arguments[1], arguments[2]))
+        } else if arguments[0] == "stat" && arguments.len() == 2 {
+            stat_file(&client, arguments[1]);
         } else if arguments[0] == "store" && arguments.len() == 1 {
             list_local_store();
+        } else if arguments[0] == "repairs" && arguments.len() == 1 {
+            list_replication_status(&client);
         } else {
             println!("Unrecognized command, please try again.");
         }
+
+        // The master may have failed over during the command
+        if client.master() != master {
+            master = client.master();
+            println!("Master found by C: {}", master);
+        }
     }
 }
 
 // <----------- Main command functions: ----------->
-fn put_file(master: u8, client_id: u8, localfilename: &str, sdfsfilename: &str) -> String {
-    let free_datanodes_res = send_write_request(master, client_id, 4, sdfsfilename);
-    if let Err(err) = free_datanodes_res {
-        return "Error in receiving request to put file: ".to_string() + &err;
+fn put_file(client: &SdfsClient, localfilename: &str, sdfsfilename: &str) -> String {
+    match client.put(localfilename, sdfsfilename) {
+        Ok(version) => format!("Successfully put file as version {}", version),
+        Err(err) => "Error in putting file: ".to_string() + &err.to_string(),
     }
-    let free_datanodes = free_datanodes_res.unwrap();
-
-    println!("Datanodes assigned: {:?}", free_datanodes);
+}
 
-    match write_to_datanode(localfilename, sdfsfilename, free_datanodes) {
-        Ok(output) => {
-            println!("Datanodes written: {:?}", output);
-            return "Successfully put file".to_string();
-        }
-        Err(err) => {
-            return "Error in putting file: ".to_string() + &err;
-        }
+fn get_file(client: &SdfsClient, sdfsfilename: &str, localfilename: &str) -> String {
+    match client.get(sdfsfilename, localfilename) {
+        Ok(_) => "Successfully got file".to_string(),
+        Err(err) => "Error in getting file: ".to_string() + &err.to_string(),
     }
 }
 
-fn get_file(master: u8, client_id: u8, sdfsfilename: &str, localfilename: &str) -> String {
-    let to_read_datanode_res = send_read_request(master, client_id, sdfsfilename);
-    if let Err(err) = to_read_datanode_res {
-        return "Error in receiving request to get file: ".to_string() + &err;
+// Writes the newest num_versions versions into localfilename, newest first, each after a
+// delimiter line naming it
+fn get_versions(
+    client: &SdfsClient,
+    sdfsfilename: &str,
+    num_versions: u8,
+    localfilename: &str,
+) -> String {
+    match client.get_versions(sdfsfilename, num_versions, localfilename) {
+        Ok(versions) => format!("Successfully got versions {:?}", versions),
+        Err(err) => "Error in getting versions: ".to_string() + &err.to_string(),
     }
-    let to_read_datanode = to_read_datanode_res.unwrap();
-    if to_read_datanode == 0 {
-        return "Error in getting file: file not found in SDFS".to_string();
+}
+
+fn delete_file(client: &SdfsClient, sdfsfilename: &str) -> String {
+    match client.delete(sdfsfilename) {
+        Ok(_) => "Successfully deleted file".to_string(),
+        Err(err) => "Error in deleting file: ".to_string() + &err.to_string(),
     }
-    println!("Datanode assigned to read from: {:?}", to_read_datanode);
+}
 
-    match get_from_datanode(sdfsfilename, localfilename, &to_read_datanode) {
-        Ok(output) => {
-            println!("Datanodes read from: {:?}", output);
-            return output;
-        }
-        Err(err) => {
-            return "Error in getting file: ".to_string() + &err;
+fn multiread_file(client: &SdfsClient, sdfsfilename: &str, localfilename: &str, nodes: Vec<u8>) {
+    match client.multiread(sdfsfilename, localfilename, &nodes) {
+        Ok(results) => {
+            for (node, fetched) in results {
+                if fetched {
+                    println!("    >VM{}: fetched {}", node, sdfsfilename);
+                } else {
+                    println!("    >VM{}: failed to fetch {}", node, sdfsfilename);
+                }
+            }
         }
+        Err(err) => println!("[SDFS] Error in multiread: {}", err),
     }
 }
 
-fn list_file(master: u8, sdfsfilename: &str) {
-    match send_ls_request(master, sdfsfilename) {
+fn list_file(client: &SdfsClient, sdfsfilename: &str) {
+    match client.ls(sdfsfilename) {
         Ok(list) => {
             println!("{} is stored on {:?}", sdfsfilename, list);
         }
@@ -110,219 +150,115 @@ fn list_file(master: u8, sdfsfilename: &str) {
     }
 }
 
-fn list_local_store() {
-    if let Ok(entries) = fs::read_dir(FILES_PATH) {
-        println!("Locally stored files: ");
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-                    if let Some(file_name) = entry.file_name().to_str() {
-                        println!("    >{}", file_name);
-                    }
-                }
+// Lists the files whose paths start with prefix, every file if it is empty
+fn list_files(client: &SdfsClient, prefix: &str) {
+    match client.list_prefix(prefix) {
+        Ok(filenames) => {
+            println!("Files in SDFS: ");
+            for filename in filenames {
+                println!("    >{}", filename);
             }
         }
-    } else {
-        println!("Error reading directory");
+        Err(err) => println!("Failed to list files, error: {}", err),
     }
 }
 
-// <----------- Utility functions: ----------->
-
-// Query the first available datanode for the current master
-// Note that the servers are numbered [1 - 10]!
-fn get_master() -> u8 {
-    let membership = get_membership();
-    for i in 0..membership.len() {
-        if membership[i] != 1 {
-            continue;
-        }
-
-        let sock_addr = String::from(VM_LIST[i]) + ":" + CD_PORT;
-        if let Ok(mut stream) = TcpStream::connect(sock_addr) {
-            stream.write(&[1]).unwrap();
-            let mut master: [u8; 1] = [0];
-            stream.read_exact(&mut master).unwrap();
-            return master[0];
+fn glob_files(client: &SdfsClient, pattern: &str) {
+    match client.glob(pattern) {
+        Ok(filenames) => {
+            println!("Files matching {}: ", pattern);
+            for filename in filenames {
+                println!("    >{}", filename);
+            }
         }
+        Err(err) => println!("Failed to list files, error: {}", err),
     }
-
-    return 0;
 }
 
-fn send_write_request(
-    master: u8,
-    client_id: u8,
-    no_datanodes: u8,
-    remote_filename: &str,
-) -> Result<Vec<u8>, String> {
-    // Connect to master
-    let sock_addr = String::from(VM_LIST[(master - 1) as usize]) + ":" + CM_PORT;
-    let mut stream = match TcpStream::connect(sock_addr) {
-        Ok(stream) => stream,
-        Err(e) => {
-            return Err(e.to_string());
+fn list_dir(client: &SdfsClient, path: &str) {
+    match client.list_dir(path) {
+        Ok(entries) => {
+            println!("Directory /{}: ", path.trim_matches('/'));
+            for entry in entries {
+                if entry.is_dir {
+                    println!("    >{}/", entry.name);
+                    continue;
+                }
+                let mtime = match Local.timestamp_opt(entry.mtime as i64, 0).single() {
+                    Some(mtime) => mtime.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    None => String::from("-"),
+                };
+                println!(
+                    "    >{}: {} bytes, version {}, {} replicas, modified {}",
+                    entry.name, entry.size, entry.version, entry.replicas, mtime
+                );
+            }
         }
-    };
-    // Write put request to stream
-    let to_send: [u8; 3] = [CM_PUT_REQ, client_id, no_datanodes];
-    stream.write(&to_send).unwrap();
-
-    let mut filename_bytes = String::from(remote_filename).into_bytes();
-    filename_bytes.insert(0, filename_bytes.len() as u8);
-    stream.write(&filename_bytes).unwrap();
-
-    // Read reply from master
-    let mut num_given: [u8; 1] = [0];
-    stream.read_exact(&mut num_given).unwrap();
-    let mut buf: Vec<u8> = vec![0; num_given[0] as usize];
-    stream.read_exact(&mut buf).unwrap();
-    return Ok(buf);
+        Err(SdfsError::NotFound(_)) => println!("{} is not a directory in SDFS", path),
+        Err(err) => println!("Failed to list {}, error: {}", path, err),
+    }
 }
 
-fn send_read_request(master: u8, client_id: u8, remote_filename: &str) -> Result<u8, String> {
-    // Connect to master
-    let sock_addr = String::from(VM_LIST[(master - 1) as usize]) + ":" + CM_PORT;
-    let mut stream = match TcpStream::connect(sock_addr) {
-        Ok(stream) => stream,
-        Err(e) => {
-            return Err(e.to_string());
-        }
-    };
-    // Write get request to stream
-    let to_send: [u8; 2] = [CM_GET_REQ, client_id];
-    stream.write(&to_send).unwrap();
-
-    // Write filename to master
-    stream
-        .write(&generate_filename_bytes(&remote_filename))
-        .unwrap();
-
-    // Read reply from master
-    let mut buf: Vec<u8> = vec![0; 1];
-    stream.read_exact(&mut buf).unwrap();
-    return Ok(buf[0]);
+fn make_dir(client: &SdfsClient, path: &str) -> String {
+    match client.mkdir(path) {
+        Ok(_) => "Successfully made directory".to_string(),
+        Err(err) => "Error in making directory: ".to_string() + &err.to_string(),
+    }
 }
 
-fn send_ls_request(master: u8, remote_filename: &str) -> Result<Vec<u8>, String> {
-    // Connect to master
-    let sock_addr = String::from(VM_LIST[(master - 1) as usize]) + ":" + CM_PORT;
-    let mut stream = match TcpStream::connect(sock_addr) {
-        Ok(stream) => stream,
-        Err(e) => {
-            return Err(e.to_string());
-        }
-    };
-    // Write get request to stream
-    let to_send: [u8; 1] = [CM_LS_REQ];
-    if let Err(err) = stream.write(&to_send) {
-        return Err(err.to_string());
-    };
-
-    // Write filename to master
-    if let Err(err) = stream.write(&generate_filename_bytes(&remote_filename)) {
-        return Err(err.to_string());
-    };
-
-    // Read reply from master
-    let mut num_given: [u8; 1] = [0];
-    if let Err(err) = stream.read_exact(&mut num_given) {
-        return Err(err.to_string());
-    };
-    let mut buf: Vec<u8> = vec![0; num_given[0] as usize];
-    if let Err(err) = stream.read_exact(&mut buf) {
-        return Err(err.to_string());
-    };
-    return Ok(buf);
+fn rename(client: &SdfsClient, from: &str, to: &str) -> String {
+    match client.rename(from, to) {
+        Ok(_) => format!("Successfully moved {} to {}", from, to),
+        Err(err) => "Error in moving: ".to_string() + &err.to_string(),
+    }
 }
 
-// Writes to set of datanodes. Returns the vector of the successful writes
-fn write_to_datanode(
-    local_filename: &str,
-    remote_filename: &str,
-    datanodes: Vec<u8>,
-) -> Result<Vec<u8>, String> {
-    // Open streams and write filename
-    let mut streams: Vec<(u8, TcpStream)> = Vec::new();
-    for node in datanodes {
-        let sock_addr = String::from(VM_LIST[(node - 1) as usize]) + ":" + CD_PORT;
-        if let Ok(mut stream) = TcpStream::connect(sock_addr) {
-            stream.write(&[CD_WRITE_FILE]).unwrap();
-            stream
-                .write(&generate_filename_bytes(remote_filename))
-                .unwrap();
-            streams.push((node, stream));
-        }
+fn stat_file(client: &SdfsClient, sdfsfilename: &str) {
+    match client.stat(sdfsfilename) {
+        Ok(stat) => println!(
+            "{}: versions {:?}, {} blocks, stored on {:?}",
+            sdfsfilename, stat.versions, stat.blocks, stat.datanodes
+        ),
+        Err(SdfsError::NotFound(_)) => println!("{} is not in SDFS", sdfsfilename),
+        Err(err) => println!("Failed to stat {}, error: {}", sdfsfilename, err),
     }
+}
 
-    // Actually write to file (writes to all streams)
-    // Vec of index of streams to remove (just-in-case)
-    let mut to_remove: Vec<usize> = Vec::new();
-
-    for i in 0..streams.len() {
-        let (_, stream) = &mut streams[i];
-        // Open file
-        let file = match File::open(local_filename.clone()) {
-            Ok(file) => file,
-            Err(e) => {
-                return Err(e.to_string());
-            }
-        };
-        if let Err(_e) = send_file_over_stream(stream, file) {
-            to_remove.push(i);
-        }
-    }
-    // Remove if connection fails
-    for index in to_remove.iter().rev() {
-        streams.remove(*index);
+fn list_replication_status(client: &SdfsClient) {
+    match client.replication_status() {
+        Ok(report) => print!("{}", report),
+        Err(err) => println!("Failed to get re-replication progress, error: {}", err),
     }
+}
 
-    // Checks streams for 0xDEADBEEF confirmations
-    let mut output: Vec<u8> = Vec::new();
-    for (node, stream) in streams.iter_mut() {
-        let mut confirmation: [u8; 4] = [0; 4];
-        if let Ok(_) = stream.read(&mut confirmation) {
-            if confirmation == CONFIRMATION {
-                output.push(*node);
+fn list_local_store() {
+    if let Ok(entries) = fs::read_dir(FILES_PATH) {
+        println!("Locally stored files: ");
+        for entry in entries {
+            if let Ok(entry) = entry {
+                if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                    if let Some(file_name) = entry.file_name().to_str() {
+                        println!("    >{}", stored_block_name(file_name));
+                    }
+                }
             }
         }
+    } else {
+        println!("Error reading directory");
     }
-    Ok(output)
 }
 
-fn get_from_datanode(
-    remote_filename: &str,
-    local_filename: &str,
-    to_read_datanode: &u8,
-) -> Result<String, String> {
-    // Open file, or create it if it doesn't exist
-    let file = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(local_filename.clone())
-    {
-        Ok(file) => file,
-        Err(e) => {
-            return Err(e.to_string());
-        }
-    };
-
-    let sock_addr = String::from(VM_LIST[(to_read_datanode - 1) as usize]) + ":" + CD_PORT;
-    let stream_res = TcpStream::connect(sock_addr);
-    if let Err(err) = stream_res {
-        return Err(err.to_string());
-    }
-    let mut stream = stream_res.unwrap();
-    stream.write(&[CD_READ_FILE]).unwrap();
-    let res = stream.write(&generate_filename_bytes(remote_filename));
-    if let Err(err) = res {
-        return Err(err.to_string());
-    }
+// <----------- Utility functions: ----------->
 
-    // Actually read from datanode into local_filename
-    if let Err(err) = read_file_from_stream(&mut stream, file) {
-        return Err(err.to_string());
+// Reads VM numbers given as "vm3" or "3"
+fn parse_nodes(arguments: &[&str]) -> Option<Vec<u8>> {
+    let mut nodes: Vec<u8> = Vec::new();
+    for argument in arguments {
+        let number = argument.strip_prefix("vm").unwrap_or(argument);
+        match number.parse::<u8>() {
+            Ok(node) if node >= 1 && node as usize <= VM_LIST.len() => nodes.push(node),
+            _ => return None,
+        }
     }
-    Ok("Successfully got file".to_string())
+    Some(nodes)
 }
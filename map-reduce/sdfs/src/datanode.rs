@@ -1,8 +1,10 @@
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use sdfs::*;
 
@@ -12,6 +14,9 @@ fn main() {
         let _ = fs::create_dir(FILES_PATH);
     }
 
+    // Drop the blocks left half received by a previous run
+    remove_temp_files();
+
     // Open file to read datanode ID
     let mut id_buf = String::new();
     let mut id_file = File::open(VM_ID_PATH).unwrap();
@@ -19,17 +24,44 @@ fn main() {
     let datanode_id: u8 = id_buf.trim().parse().unwrap();
     println!("Read datanode_id {}", datanode_id);
 
-    let master = find_master();
-    println!("Master found by D: {}", master);
+    // Join the gossip group, so that the master can tell whether this datanode is alive
+    let membership = start_membership(DATANODE_GOSSIP_PORT, ROLE_DATANODE);
+
+    let master = Arc::new(Mutex::new(0));
 
-    let master = Arc::new(Mutex::new(master));
+    // Follow master elections
+    let shared_master_follower = master.clone();
+    let follower = thread::spawn(move || follow_master(membership, shared_master_follower));
 
     // Listen for incoming connections
     let shared_master = master.clone();
     let client_listener = thread::spawn(move || client_listen(shared_master));
 
+    // Report the stored blocks to the master now and periodically, to find lost, corrupt and
+    // orphaned ones
+    let shared_master_reporter = master.clone();
+    let reporter = thread::spawn(move || report_blocks(datanode_id, shared_master_reporter));
+
     // Block indefinitely
     client_listener.join().unwrap();
+    follower.join().unwrap();
+    reporter.join().unwrap();
+}
+
+// Keeps track of the elected master, as seen through gossip
+fn follow_master(membership: Membership, shared_master: Arc<Mutex<u8>>) {
+    loop {
+        let leader = get_leader(&membership);
+        {
+            let mut master = shared_master.lock().unwrap();
+            if *master != leader {
+                *master = leader;
+                println!("Master found by D: {}", leader);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
 }
 
 // Listener for client connections
@@ -38,27 +70,51 @@ fn client_listen(master: Arc<Mutex<u8>>) {
     let listener = TcpListener::bind(listen_addr).unwrap();
 
     for conn_res in listener.incoming() {
-        if let Ok(mut stream) = conn_res {
-            let mut opcode: [u8; 1] = [0];
-            if stream.read_exact(&mut opcode).is_err() {
-                continue;
+        if let Ok(stream) = conn_res {
+            let (mut conn, request) = match accept_request(stream) {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("Rejected connection: {}", err);
+                    continue;
+                }
             };
 
-            match opcode[0] {
+            match request.kind {
                 // Query on current leader
                 CD_GET_MASTER => {
                     let shared_master = master.clone();
-                    thread::spawn(move || give_leader(stream, shared_master));
+                    thread::spawn(move || serve(conn, request, |_| give_leader(&shared_master)));
                 }
                 CD_WRITE_FILE => {
                     let shared_master = master.clone();
-                    thread::spawn(move || receive_file(stream, shared_master));
+                    thread::spawn(move || receive_file(conn, request, shared_master));
                 }
                 CD_READ_FILE => {
                     let shared_master = master.clone();
-                    thread::spawn(move || send_file(stream, shared_master));
+                    thread::spawn(move || send_file(conn, request, shared_master));
+                }
+                CD_REPLICATE => {
+                    thread::spawn(move || serve(conn, request, replicate_file));
+                }
+                CD_BLOCK_REPORT => {
+                    thread::spawn(move || serve(conn, request, send_block_report));
+                }
+                CD_DELETE_FILE => {
+                    thread::spawn(move || serve(conn, request, delete_file));
+                }
+                CD_FETCH_FILE => {
+                    thread::spawn(move || serve(conn, request, fetch_file));
+                }
+                CD_LIST_BLOCKS => {
+                    thread::spawn(move || serve(conn, request, list_blocks));
+                }
+                CD_RENAME_FILE => {
+                    thread::spawn(move || serve(conn, request, rename_file));
+                }
+                kind => {
+                    let unknown = SdfsError::BadRequest(format!("unknown message {}", kind));
+                    let _ = conn.reply(request.id, Err(unknown));
                 }
-                _ => {}
             }
         } else {
             continue;
@@ -67,98 +123,524 @@ fn client_listen(master: Arc<Mutex<u8>>) {
 }
 
 // CD Message 1 - Query for master node
-fn give_leader(mut stream: TcpStream, shared_master: Arc<Mutex<u8>>) {
+// Replies with the master, 0 if none is elected yet
+fn give_leader(shared_master: &Arc<Mutex<u8>>) -> Result<Vec<u8>, SdfsError> {
     let master: u8;
     {
         master = *shared_master.lock().unwrap();
     }
-    let to_write: [u8; 1] = [master];
-    stream.write(&to_write).unwrap();
+    let mut reply = Encoder::new();
+    reply.node(master);
+    Ok(reply.into_bytes())
 }
 
-// CD Message 2 - Receive file write from client
-fn receive_file(mut stream: TcpStream, shared_master: Arc<Mutex<u8>>) {
-    let filename = receive_filename(&mut stream).unwrap();
-    println!("Receiving {}...", filename);
+// CD Message 2 - Receive a block write from a client (or another datanode), with its checksum
+// size and the rest of its chain of datanodes, then the block as data frames. The block is
+// forwarded to the next reachable datanode of the chain while it is stored. Once the rest of
+// the chain is done, replies with the datanodes that stored the block from here down. A
+// block that does not match its size or checksum is dropped, failing with ChecksumMismatch,
+// as the rest of the chain was sent the same data
+// The block is received into TEMP_PATH and only moved into place once complete, so readers
+// are never served a partial block, and of two writes of a block one wins whole
+fn receive_file(mut conn: Connection, request: Frame, shared_master: Arc<Mutex<u8>>) {
+    let id = request.id;
+    let header = receive_write_header(&mut Decoder::new(request.body));
+    let (filename, size, checksum, chain) = match header {
+        Ok(header) => header,
+        Err(err) => {
+            let _ = conn.reply(id, Err(err));
+            return;
+        }
+    };
+    println!("Receiving {}, forwarding to {:?}...", filename, chain);
 
     // Read file from client
-    let file: File = File::create(String::from(FILES_PATH) + &filename).unwrap();
-    if let Err(_) = read_file_from_stream(&mut stream, file) {
+    let temp_path = temp_block_path(&filename);
+    let file: File = match File::create(&temp_path) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("Failed to store {}: {}", filename, err);
+            let _ = conn.reply(id, Err(storage_error(err)));
+            return;
+        }
+    };
+
+    let downstream = if chain.is_empty() {
+        None
+    } else {
+        match open_block_write(&filename, size, checksum, &chain) {
+            Ok((downstream, downstream_id, _)) => Some((downstream, downstream_id)),
+            Err(err) => {
+                println!("Failed to forward {}: {}", filename, err);
+                None
+            }
+        }
+    };
+    let mut writer = ChainWriter {
+        local: ChecksumWriter::new(file),
+        downstream,
+    };
+    if let Err(err) = conn.receive_data(id, &mut writer) {
+        println!("Failed to receive {}: {}", filename, err);
+        let _ = fs::remove_file(&temp_path);
         return;
     }
+    let ChainWriter { local, downstream } = writer;
+    let stored_here = store_block(local, &temp_path, &filename, size, checksum);
+    if let Err(err) = &stored_here {
+        println!("Failed to store {}, dropping it: {}", filename, err);
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    // Wait for the rest of the chain
+    let mut stored: Vec<u8> = Vec::new();
+    if stored_here.is_ok() {
+        stored.push(get_vm_id());
+    }
+    if let Some((mut downstream, downstream_id)) = downstream {
+        let acks = downstream
+            .send(&Frame::end(downstream_id))
+            .and_then(|_| read_block_acks(&mut downstream, downstream_id));
+        match acks {
+            Ok(acks) => stored.extend_from_slice(&acks),
+            Err(err) => println!("Failed to forward {}: {}", filename, err),
+        }
+    }
 
-    // Send confirmation code to client
-    if let Err(_) = stream.write(&CONFIRMATION) {
+    // Send the acknowledgement back up the chain
+    let reply = stored_here.map(|_| {
+        let mut acks = Encoder::new();
+        acks.nodes(&stored);
+        acks.into_bytes()
+    });
+    let stored_here = reply.is_ok();
+    if conn.reply(id, reply).is_err() || !stored_here {
         return;
     }
+    prune_versions(&filename);
 
     // Tell master that it received a file
-    let master: u8;
-    {
-        master = *shared_master.lock().unwrap();
+    if let Err(err) = notify_master(&shared_master, DM_FILE_RECEIVED, &filename) {
+        println!("Failed to report {} received: {}", filename, err);
+    }
+}
+
+// Reads the block a write is of, its size, its checksum and the rest of its chain
+fn receive_write_header(request: &mut Decoder) -> Result<(String, u64, u32, Vec<u8>), SdfsError> {
+    Ok((
+        request.str()?,
+        request.u64()?,
+        request.u32()?,
+        request.nodes()?,
+    ))
+}
+
+// Temporary path a block is received at, unique to the write so that concurrent writes of
+// the block do not mix
+fn temp_block_path(block: &str) -> String {
+    static NEXT_WRITE: AtomicU64 = AtomicU64::new(0);
+    let write = NEXT_WRITE.fetch_add(1, Ordering::Relaxed);
+    let file_name = block_path(block)[FILES_PATH.len()..].to_string();
+    format!("{}{}.{}.tmp", TEMP_PATH, file_name, write)
+}
+
+// Moves a block received at temp_path into place, once it has the size and checksum it was
+// sent with and is synced to disk. The rename replaces any previous copy atomically
+fn store_block(
+    local: ChecksumWriter<File>,
+    temp_path: &str,
+    block: &str,
+    size: u64,
+    checksum: u32,
+) -> Result<(), SdfsError> {
+    if local.written() != size || local.checksum() != checksum {
+        return Err(SdfsError::ChecksumMismatch(String::from(block)));
+    }
+    let file = local.into_inner();
+    file.sync_all().map_err(storage_error)?;
+    if file.metadata()?.len() != size {
+        return Err(SdfsError::ChecksumMismatch(String::from(block)));
     }
-    let sock_addr = String::from(VM_LIST[(master - 1) as usize]) + ":" + DM_PORT;
-    let mut master_stream = TcpStream::connect(sock_addr).unwrap();
-    master_stream.write(&[DM_FILE_RECEIVED]).unwrap();
+    fs::rename(temp_path, block_path(block))?;
+    // Sync the rename itself
+    File::open(FILES_PATH)?.sync_all()?;
+    Ok(())
+}
 
-    // Write the filename
-    master_stream
-        .write(&generate_filename_bytes(&filename))
-        .unwrap();
+// Empties TEMP_PATH of blocks whose write never finished, e.g. as the datanode crashed
+fn remove_temp_files() {
+    let _ = fs::create_dir_all(TEMP_PATH);
+    if let Ok(entries) = fs::read_dir(TEMP_PATH) {
+        for entry in entries.flatten() {
+            println!("Removing unfinished {:?}...", entry.file_name());
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
 
-    // Write self node id
-    let id_str = fs::read_to_string(VM_ID_PATH).unwrap();
-    let id: u8 = id_str.trim().parse().unwrap();
-    master_stream.write(&[id]).unwrap();
+// Stores the chunks of a block while forwarding them to the next datanode of its chain, as
+// data frames of its write there
+struct ChainWriter {
+    local: ChecksumWriter<File>,
+    downstream: Option<(Connection, u64)>,
+}
+
+impl Write for ChainWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.local.write_all(buf)?;
+        // A datanode that fails mid-block is dropped, with the rest of the chain after it
+        if let Some((downstream, id)) = &mut self.downstream {
+            if let Err(err) = downstream.send(&Frame::data(*id, buf)) {
+                println!("Failed to forward block: {}", err);
+                self.downstream = None;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.local.flush()
+    }
 }
 
 // CD Message 3 - Send file write to client
-fn send_file(mut stream: TcpStream, shared_master: Arc<Mutex<u8>>) {
-    let filename = receive_filename(&mut stream).unwrap();
+// Replies with the file as data frames, or fails with NotFound if it is not stored here
+fn send_file(mut conn: Connection, request: Frame, shared_master: Arc<Mutex<u8>>) {
+    let id = request.id;
+    let filename = match Decoder::new(request.body).str() {
+        Ok(filename) => filename,
+        Err(err) => {
+            let _ = conn.reply(id, Err(err));
+            return;
+        }
+    };
     println!("Sending {}...", filename);
 
     // Open file
-    let file = match File::open(FILES_PATH.to_owned() + &filename) {
+    let file = match File::open(block_path(&filename)) {
         Ok(file) => file,
         Err(_) => {
+            let _ = conn.reply(id, Err(SdfsError::NotFound(filename)));
             return;
         }
     };
 
     // Actually send file to client
-    if let Err(_) = send_file_over_stream(&mut stream, file) {
+    if conn.send_data(id, file).is_err() {
         return;
     }
 
     // Tell master that it sent a file
+    if let Err(err) = notify_master(&shared_master, DM_FILE_SENT, &filename) {
+        println!("Failed to report {} sent: {}", filename, err);
+    }
+}
+
+// DM Message 1 or 2 - Tells the master this datanode received or sent a block
+fn notify_master(shared_master: &Arc<Mutex<u8>>, opcode: u8, block: &str) -> Result<(), SdfsError> {
     let master: u8;
     {
         master = *shared_master.lock().unwrap();
     }
-    let sock_addr = String::from(VM_LIST[(master - 1) as usize]) + ":" + DM_PORT;
-    let mut master_stream = TcpStream::connect(sock_addr).unwrap();
-    master_stream.write(&[DM_FILE_SENT]).unwrap();
+    // No master elected yet, the next master learns of the file from block reports
+    if master == 0 {
+        return Ok(());
+    }
+    let mut request = Encoder::new();
+    request.str(block).node(get_vm_id());
+    Connection::open(master, DM_PORT)?.call(opcode, request.into_bytes())?;
+    Ok(())
+}
+
+// Error for a block that could not be stored, NoCapacity if the disk is full
+fn storage_error(err: io::Error) -> SdfsError {
+    match err.kind() {
+        ErrorKind::StorageFull => SdfsError::NoCapacity,
+        _ => err.into(),
+    }
+}
+
+// CD Message 4 - Copy a stored block to another datanode, as asked by the master, with the
+// checksum the target checks it against. Replies once the target confirmed the block
+fn replicate_file(request: &mut Decoder) -> Result<Vec<u8>, SdfsError> {
+    let block = request.str()?;
+    let target = request.node()?;
+    let checksum = request.u32()?;
+    println!("Copying {} to datanode {}...", block, target);
+
+    copy_to_datanode(&block, checksum, target)?;
+    Ok(Vec::new())
+}
+
+// Writes a stored block to another datanode, the same way a client does
+fn copy_to_datanode(filename: &str, checksum: u32, target: u8) -> Result<(), SdfsError> {
+    let file = match File::open(block_path(filename)) {
+        Ok(file) => file,
+        Err(_) => {
+            return Err(SdfsError::NotFound(String::from(filename)));
+        }
+    };
+
+    let size = file.metadata()?.len();
+    let (mut conn, id, _) = open_block_write(filename, size, checksum, &[target])?;
+    conn.send_data(id, file)?;
+
+    if !read_block_acks(&mut conn, id)?.contains(&target) {
+        return Err(SdfsError::Io("target did not confirm file".to_string()));
+    }
+    Ok(())
+}
+
+// CD Message 5 - List stored files, for a newly elected master to rebuild its metadata
+// Replies with the number of files, then each filename
+fn send_block_report(_request: &mut Decoder) -> Result<Vec<u8>, SdfsError> {
+    let mut filenames: Vec<String> = Vec::new();
+    for entry in fs::read_dir(FILES_PATH)?.flatten() {
+        if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+            if let Some(file_name) = entry.file_name().to_str() {
+                filenames.push(stored_block_name(file_name));
+            }
+        }
+    }
+
+    let mut reply = Encoder::new();
+    reply.count(filenames.len());
+    for filename in filenames.iter() {
+        reply.str(filename);
+    }
+    Ok(reply.into_bytes())
+}
+
+// CD Message 6 - Remove every block of every version of a stored file, as asked by the
+// master. Replies once the file is gone (including if it was never stored here)
+fn delete_file(request: &mut Decoder) -> Result<Vec<u8>, SdfsError> {
+    let filename = request.str()?;
+    println!("Deleting {}...", filename);
+
+    let mut failed: Option<io::Error> = None;
+    for (block, _) in stored_blocks(&filename) {
+        match fs::remove_file(block_path(&block)) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                println!("Failed to delete {}: {}", block, err);
+                failed = Some(err);
+            }
+        }
+    }
+    match failed {
+        Some(err) => Err(err.into()),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Blocks of a file stored here, with the version each belongs to
+fn stored_blocks(filename: &str) -> Vec<(String, u32)> {
+    let mut blocks: Vec<(String, u32)> = Vec::new();
+    if let Ok(entries) = fs::read_dir(FILES_PATH) {
+        for entry in entries.flatten() {
+            if let Some(file_name) = entry.file_name().to_str() {
+                let stored_name = stored_block_name(file_name);
+                if let Some((name, version, _)) = parse_block_name(&stored_name) {
+                    if name == filename {
+                        blocks.push((stored_name, version));
+                    }
+                }
+            }
+        }
+    }
+    blocks
+}
+
+// Deletes the blocks of all but the newest MAX_VERSIONS versions stored here of the file a
+// new block belongs to
+fn prune_versions(block: &str) {
+    let filename = match parse_block_name(block) {
+        Some((filename, _, _)) => filename,
+        None => {
+            return;
+        }
+    };
+    let blocks = stored_blocks(&filename);
+    let mut versions: Vec<u32> = blocks.iter().map(|(_, version)| *version).collect();
+    versions.sort_unstable();
+    versions.dedup();
+    if versions.len() <= MAX_VERSIONS {
+        return;
+    }
+    let oldest_kept = versions[versions.len() - MAX_VERSIONS];
+    for (block, version) in blocks {
+        if version < oldest_kept {
+            let _ = fs::remove_file(block_path(&block));
+        }
+    }
+}
+
+// CD Message 7 - Fetch every block of a file into a local file, each from the first of the
+// datanodes given for it that sends it intact, as asked by the master for a multiread
+// Replies 1 or 0 for each block, whether it arrived
+fn fetch_file(request: &mut Decoder) -> Result<Vec<u8>, SdfsError> {
+    let local_filename = request.str()?;
+    let no_blocks = request.count(20)?;
+    let mut blocks: Vec<BlockLocation> = Vec::with_capacity(no_blocks);
+    for _ in 0..no_blocks {
+        blocks.push(BlockLocation {
+            block: request.str()?,
+            checksum: request.u32()?,
+            nodes: request.nodes()?,
+        });
+    }
+    println!(
+        "Fetching {} blocks into {}...",
+        blocks.len(),
+        local_filename
+    );
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&local_filename)
+        .map_err(storage_error)?;
+    let results = read_blocks(&local_filename, 0, &blocks);
+    let mut reply: Vec<u8> = Vec::new();
+    for (location, result) in blocks.iter().zip(results) {
+        match result {
+            Ok(_) => reply.push(1),
+            Err(err) => {
+                println!("Failed to fetch {}: {}", location.block, err);
+                reply.push(0);
+            }
+        }
+    }
+    Ok(reply)
+}
 
-    // Write the filename
-    master_stream
-        .write(&generate_filename_bytes(&filename))
-        .unwrap();
+// CD Message 8 - List the blocks of a file stored here, of every version, for a reader
+// checking its quorum
+// Replies with the number of blocks, then the name of each, in name order
+fn list_blocks(request: &mut Decoder) -> Result<Vec<u8>, SdfsError> {
+    let filename = request.str()?;
+    let mut blocks: Vec<String> = stored_blocks(&filename)
+        .into_iter()
+        .map(|(block, _)| block)
+        .collect();
+    blocks.sort_unstable();
 
-    // Write self node id
-    let id_str = fs::read_to_string(VM_ID_PATH).unwrap();
-    let id: u8 = id_str.trim().parse().unwrap();
-    master_stream.write(&[id]).unwrap();
+    let mut reply = Encoder::new();
+    reply.count(blocks.len());
+    for block in blocks {
+        reply.str(&block);
+    }
+    Ok(reply.into_bytes())
 }
 
-// Routine to find master using lowest numbered number
-// Note that the servers are numbered [1 - 10]!
-fn find_master() -> u8 {
-    for i in 0..VM_LIST.len() {
-        let sock_addr: String = String::from(VM_LIST[i]) + ":" + DM_PORT;
-        if let Ok(_) = TcpStream::connect(sock_addr) {
-            return (i + 1) as u8;
+// CD Message 9 - Rename every block of every version of a stored file to the same block of
+// another, as asked by the master when the file is moved. Replies once renamed (including if
+// none is stored here)
+fn rename_file(request: &mut Decoder) -> Result<Vec<u8>, SdfsError> {
+    let filename = request.str()?;
+    let moved = request.str()?;
+    println!("Moving {} to {}...", filename, moved);
+
+    for (block, _) in stored_blocks(&filename) {
+        let (_, version, index) = parse_block_name(&block).unwrap();
+        let renamed = fs::rename(
+            block_path(&block),
+            block_path(&block_name(&moved, version, index)),
+        );
+        if let Err(err) = renamed {
+            println!("Failed to move {}: {}", block, err);
+            return Err(err.into());
         }
     }
+    Ok(Vec::new())
+}
+
+// Sends the master a block report (see send_block_inventory) when the datanode starts, then
+// every BLOCK_REPORT_INTERVAL, computing the checksum of every stored block. Deletes the
+// blocks the master finds corrupt, which it has copied again from other replicas, or
+// orphaned
+fn report_blocks(datanode_id: u8, shared_master: Arc<Mutex<u8>>) {
+    loop {
+        // No master elected yet, check again shortly
+        let master: u8;
+        {
+            master = *shared_master.lock().unwrap();
+        }
+        if master == 0 {
+            thread::sleep(BLOCK_REPORT_RETRY);
+            continue;
+        }
 
-    return 0;
+        // Blocks stored after the listing starts may be missed, the master leaves them be
+        let scan_started = Instant::now();
+        let mut inventory: Vec<StoredBlock> = Vec::new();
+        if let Ok(entries) = fs::read_dir(FILES_PATH) {
+            for entry in entries.flatten() {
+                let block = match entry.file_name().to_str() {
+                    Some(file_name) => stored_block_name(file_name),
+                    None => continue,
+                };
+                if parse_block_name(&block).is_none() {
+                    continue;
+                }
+                let stored = File::open(entry.path())
+                    .and_then(|file| Ok((file.metadata()?.len(), block_checksum(file)?)));
+                if let Ok((size, checksum)) = stored {
+                    inventory.push(StoredBlock {
+                        block,
+                        size,
+                        checksum,
+                    });
+                }
+            }
+        }
+
+        match send_block_inventory(datanode_id, master, scan_started, &inventory) {
+            Ok(deleted) => {
+                for block in deleted {
+                    println!("Deleting corrupt or orphaned {}...", block);
+                    let _ = fs::remove_file(block_path(&block));
+                }
+            }
+            Err(err) => {
+                println!("Failed to send block report: {}", err);
+                thread::sleep(BLOCK_REPORT_RETRY);
+                continue;
+            }
+        }
+
+        thread::sleep(BLOCK_REPORT_INTERVAL);
+    }
+}
+
+// DM Message 3 - Sends how long ago the scan started and the name, size and checksum of every
+// stored block. Returns the blocks to delete
+fn send_block_inventory(
+    datanode_id: u8,
+    master: u8,
+    scan_started: Instant,
+    inventory: &[StoredBlock],
+) -> Result<Vec<String>, SdfsError> {
+    let mut request = Encoder::new();
+    request
+        .node(datanode_id)
+        .u64(scan_started.elapsed().as_millis() as u64)
+        .count(inventory.len());
+    for stored in inventory {
+        request
+            .str(&stored.block)
+            .u64(stored.size)
+            .u32(stored.checksum);
+    }
+
+    let mut reply =
+        Connection::open(master, DM_PORT)?.call(DM_BLOCK_REPORT, request.into_bytes())?;
+    let no_deleted = reply.count(8)?;
+    let mut deleted: Vec<String> = Vec::with_capacity(no_deleted);
+    for _ in 0..no_deleted {
+        deleted.push(reply.str()?);
+    }
+    Ok(deleted)
 }
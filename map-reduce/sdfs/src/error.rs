@@ -0,0 +1,97 @@
+use std::fmt;
+use std::io;
+
+use crate::*;
+
+/********************* Error section *********************/
+
+// Why an SDFS operation failed. A server that fails a request replies with the status of its
+// error (see Frame::reply), which the client turns back into the error (see Frame::into_reply)
+#[derive(Debug, Clone, PartialEq)]
+pub enum SdfsError {
+    // The file (or its block) is not in SDFS, or not on the datanode asked
+    NotFound(String),
+    // The lease the file was read or written under is no longer held (e.g. it expired)
+    Locked(String),
+    // Too few datanodes are alive to store the file
+    NoCapacity,
+    // A block did not match the checksum recorded for it at put time
+    ChecksumMismatch(String),
+    // The server asked is standing by, the client should look for the master again
+    NotMaster,
+    // Too few datanodes confirmed a put, or hold the version to read
+    NoQuorum(String),
+    // A message that does not follow the protocol
+    BadRequest(String),
+    // The network or a disk failed
+    Io(String),
+}
+
+impl SdfsError {
+    // Status sent in place of STATUS_OK for this error
+    pub fn status(&self) -> u8 {
+        match self {
+            SdfsError::NotFound(_) => STATUS_NOT_FOUND,
+            SdfsError::Locked(_) => STATUS_LOCKED,
+            SdfsError::NoCapacity => STATUS_NO_CAPACITY,
+            SdfsError::ChecksumMismatch(_) => STATUS_CHECKSUM_MISMATCH,
+            SdfsError::NotMaster => STATUS_NOT_MASTER,
+            SdfsError::NoQuorum(_) => STATUS_NO_QUORUM,
+            SdfsError::BadRequest(_) => STATUS_BAD_REQUEST,
+            SdfsError::Io(_) => STATUS_IO,
+        }
+    }
+
+    // What is sent with the status: the file, block or reason the error is about
+    pub fn detail(&self) -> &str {
+        match self {
+            SdfsError::NotFound(detail)
+            | SdfsError::Locked(detail)
+            | SdfsError::ChecksumMismatch(detail)
+            | SdfsError::NoQuorum(detail)
+            | SdfsError::BadRequest(detail)
+            | SdfsError::Io(detail) => detail,
+            SdfsError::NoCapacity | SdfsError::NotMaster => "",
+        }
+    }
+
+    // The error a status and its detail stand for. An unknown status is a protocol error
+    pub fn from_status(status: u8, detail: String) -> Self {
+        match status {
+            STATUS_NOT_FOUND => SdfsError::NotFound(detail),
+            STATUS_LOCKED => SdfsError::Locked(detail),
+            STATUS_NO_CAPACITY => SdfsError::NoCapacity,
+            STATUS_CHECKSUM_MISMATCH => SdfsError::ChecksumMismatch(detail),
+            STATUS_NOT_MASTER => SdfsError::NotMaster,
+            STATUS_NO_QUORUM => SdfsError::NoQuorum(detail),
+            STATUS_BAD_REQUEST => SdfsError::BadRequest(detail),
+            STATUS_IO => SdfsError::Io(detail),
+            _ => SdfsError::BadRequest(format!("unknown status {}: {}", status, detail)),
+        }
+    }
+}
+
+impl fmt::Display for SdfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SdfsError::NotFound(name) => write!(f, "{} not found in SDFS", name),
+            SdfsError::Locked(name) => write!(f, "lease on {} is no longer held", name),
+            SdfsError::NoCapacity => write!(f, "not enough datanodes alive to store the file"),
+            SdfsError::ChecksumMismatch(block) => {
+                write!(f, "{} does not match its checksum", block)
+            }
+            SdfsError::NotMaster => write!(f, "server is not the master"),
+            SdfsError::NoQuorum(reason) => write!(f, "no quorum: {}", reason),
+            SdfsError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            SdfsError::Io(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SdfsError {}
+
+impl From<io::Error> for SdfsError {
+    fn from(err: io::Error) -> Self {
+        SdfsError::Io(err.to_string())
+    }
+}
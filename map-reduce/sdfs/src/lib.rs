@@ -1,13 +1,28 @@
-pub mod interface;
-pub use interface::*;
+pub mod error;
+pub use error::*;
+
+pub mod rpc;
+pub use rpc::*;
+
+pub mod sdfs_client;
+pub use sdfs_client::*;
+
+pub mod wal;
+pub use wal::*;
+
+pub use gossip::{Config, Member, Membership};
 
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::Sender;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /********************* Metadata section *********************/
 
@@ -18,192 +33,563 @@ pub enum FileState {
     Write,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RequestType {
     Read,
     Write,
 }
 
+// A grant of a file to a read or write, held until every datanode it involves reports its
+// transfer done, it is released, or it expires without being renewed (e.g. because the
+// client crashed mid-transfer)
+#[derive(Debug)]
+pub struct Lease {
+    pub id: u64,
+    pub kind: RequestType,
+    // Datanodes still to report their transfer done, once assigned
+    pub nodes: Vec<u8>,
+    pub expires: Instant,
+}
+
+// Source of lease ids, unique across files
+static NEXT_LEASE: AtomicU64 = AtomicU64::new(1);
+
+// Reader-writer scheduler of a file. Requests are let in in arrival order, except that
+// readers can go ahead of a waiting writer, at most max_readers_before_writer of them in a
+// row. Writers hold the file alone, and at most max_readers read it at once
+// Each request let in gets a lease on the file, sent on its channel
 #[derive(Debug)]
 pub struct FileSync {
-    pub ops: Vec<u8>,
-    pub state: FileState,
-    pub queue: VecDeque<(RequestType, Sender<u8>)>,
+    pub leases: Vec<Lease>,
+    queue: VecDeque<(RequestType, Sender<u64>)>,
+    max_readers: usize,
+    max_readers_before_writer: usize,
+    // Readers let in ahead of a waiting writer since a writer last got the file
+    readers_ahead_of_writer: usize,
+}
+
+impl Default for FileSync {
+    fn default() -> Self {
+        FileSync::new()
+    }
 }
 
 impl FileSync {
     pub fn new() -> Self {
+        FileSync::with_limits(MAX_CONCURRENT_READERS, MAX_READERS_BEFORE_WRITER)
+    }
+
+    pub fn with_limits(max_readers: usize, max_readers_before_writer: usize) -> Self {
         FileSync {
-            ops: Vec::new(),
-            state: FileState::Free,
+            leases: Vec::new(),
             queue: VecDeque::new(),
+            max_readers: max_readers.max(1),
+            max_readers_before_writer,
+            readers_ahead_of_writer: 0,
         }
     }
 
-    // Start a write operation
-    pub fn add_writer(&mut self, mut datanodes: Vec<u8>) {
-        self.state = FileState::Write;
-        self.ops.append(&mut datanodes);
+    pub fn state(&self) -> FileState {
+        match self.leases.first() {
+            None => FileState::Free,
+            Some(lease) if lease.kind == RequestType::Write => FileState::Write,
+            Some(_) => FileState::Read,
+        }
     }
 
-    // Start a read operation
-    pub fn add_reader(&mut self, datanode: u8) {
-        self.state = FileState::Read;
-        self.ops.push(datanode);
+    // Requests waiting to be let in
+    pub fn waiting(&self) -> usize {
+        self.queue.len()
     }
 
-    // Called when datanode confirms write is complete
-    pub fn write_complete(&mut self, datanode: u8) {
-        let ops = &mut self.ops;
-        for i in 0..ops.len() {
-            if ops[i] == datanode {
-                ops.remove(i);
-                break;
-            }
+    // Datanodes yet to report a transfer done, over every lease
+    pub fn ops(&self) -> usize {
+        self.leases.iter().map(|lease| lease.nodes.len()).sum()
+    }
+
+    // Queues a read or write of the file. The lease is sent on the returned channel once it
+    // is let in, which may be right away
+    pub fn request(&mut self, kind: RequestType) -> Receiver<u64> {
+        let (tx, rx) = mpsc::channel::<u64>();
+        self.queue.push_back((kind, tx));
+        self.advance();
+        rx
+    }
+
+    // Takes a read lease on the given datanodes only if one would be let in right away with
+    // nobody waiting, as for copies the master starts in the background
+    pub fn try_read(&mut self, datanodes: Vec<u8>) -> Option<u64> {
+        if !self.queue.is_empty() || self.state() == FileState::Write {
+            return None;
+        }
+        if self.leases.len() >= self.max_readers {
+            return None;
         }
+        let id = self.grant(RequestType::Read);
+        self.assign(id, datanodes);
+        Some(id)
+    }
 
-        if ops.is_empty() {
-            self.state = FileState::Free;
-            match self.queue.pop_front() {
-                Some((_, tx)) => {
-                    tx.send(1).unwrap();
-                }
-                _ => {}
+    // Sets the datanodes a lease waits on to report their transfer done. With none, the
+    // lease is only given up by release or expiry
+    pub fn assign(&mut self, lease: u64, datanodes: Vec<u8>) -> bool {
+        match self.leases.iter_mut().find(|held| held.id == lease) {
+            Some(held) => {
+                held.nodes = datanodes;
+                true
             }
+            None => false,
         }
     }
 
+    fn grant(&mut self, kind: RequestType) -> u64 {
+        let id = NEXT_LEASE.fetch_add(1, Ordering::SeqCst);
+        self.leases.push(Lease {
+            id,
+            kind,
+            nodes: Vec::new(),
+            expires: Instant::now() + LEASE_DURATION,
+        });
+        id
+    }
+
+    // Called when datanode confirms write is complete
+    pub fn write_complete(&mut self, datanode: u8) {
+        self.complete(RequestType::Write, datanode);
+    }
+
     // Called when datanode confirms read is complete
     pub fn read_complete(&mut self, datanode: u8) {
-        // Remove datanode from ops vector
-        let ops = &mut self.ops;
-        for i in 0..ops.len() {
-            if ops[i] == datanode {
-                ops.remove(i);
-                break;
+        self.complete(RequestType::Read, datanode);
+    }
+
+    // Removes the datanode from the oldest lease of the kind waiting on it. A report that
+    // matches no lease (e.g. one that expired) changes nothing
+    fn complete(&mut self, kind: RequestType, datanode: u8) {
+        let position = self
+            .leases
+            .iter()
+            .position(|lease| lease.kind == kind && lease.nodes.contains(&datanode));
+        if let Some(i) = position {
+            let nodes = &mut self.leases[i].nodes;
+            let j = nodes.iter().position(|node| *node == datanode).unwrap();
+            nodes.remove(j);
+            if nodes.is_empty() {
+                self.leases.remove(i);
             }
+            self.advance();
         }
+    }
 
-        // Check if we can let in another reader
-        let mut pop_next: bool = false;
-        match self.queue.front() {
-            // Next in queue is write, do nothing
-            Some((RequestType::Write, _)) => {}
+    // Extends a lease by LEASE_DURATION from now. Returns false if it is no longer held
+    pub fn renew(&mut self, lease: u64) -> bool {
+        match self.leases.iter_mut().find(|held| held.id == lease) {
+            Some(held) => {
+                held.expires = Instant::now() + LEASE_DURATION;
+                true
+            }
+            None => false,
+        }
+    }
 
-            // Next in queue is read
-            Some((RequestType::Read, _)) => {
-                pop_next = true;
+    // Gives up a lease, e.g. once the client is done, or if there turned out to be nothing
+    // to do. Returns false if it was not held
+    pub fn release(&mut self, lease: u64) -> bool {
+        match self.leases.iter().position(|held| held.id == lease) {
+            Some(i) => {
+                self.leases.remove(i);
+                self.advance();
+                true
             }
+            None => false,
+        }
+    }
 
-            // None left in queue, only set state if no one else reading
-            None => {
-                // Current invocation is last in ops
-                if self.ops.is_empty() {
-                    self.state = FileState::Free;
+    // Releases the leases that expired by now. Returns them
+    pub fn expire_leases(&mut self, now: Instant) -> Vec<Lease> {
+        let (expired, held): (Vec<Lease>, Vec<Lease>) = self
+            .leases
+            .drain(..)
+            .partition(|lease| lease.expires <= now);
+        self.leases = held;
+        if !expired.is_empty() {
+            self.advance();
+        }
+        expired
+    }
+
+    // Lets in every waiting request that can go now: the writer at the front once the file
+    // is free, else the readers in arrival order, while there is room and they do not pass
+    // a waiting writer too many times. A request that stopped waiting is skipped
+    fn advance(&mut self) {
+        loop {
+            if let Some((RequestType::Write, _)) = self.queue.front() {
+                if self.leases.is_empty() {
+                    let (_, tx) = self.queue.pop_front().unwrap();
+                    if self.let_in(RequestType::Write, tx) {
+                        self.readers_ahead_of_writer = 0;
+                    }
+                    continue;
                 }
             }
+
+            let next_reader = self
+                .queue
+                .iter()
+                .position(|(kind, _)| *kind == RequestType::Read);
+            let index = match next_reader {
+                Some(index) => index,
+                None => return,
+            };
+            let passes_writer = index > 0;
+            if self.state() == FileState::Write
+                || self.leases.len() >= self.max_readers
+                || (passes_writer && self.readers_ahead_of_writer >= self.max_readers_before_writer)
+            {
+                return;
+            }
+            let (_, tx) = self.queue.remove(index).unwrap();
+            if self.let_in(RequestType::Read, tx) && passes_writer {
+                self.readers_ahead_of_writer += 1;
+            }
         }
+    }
 
-        // Actually pop from queue and send message down tx
-        if pop_next {
-            let (_, tx) = self.queue.pop_front().unwrap();
-            tx.send(1).unwrap();
+    // Grants a lease to a waiting request. Returns false if it stopped waiting
+    fn let_in(&mut self, kind: RequestType, tx: Sender<u64>) -> bool {
+        let id = self.grant(kind);
+        if tx.send(id).is_ok() {
+            return true;
         }
+        self.leases.pop();
+        false
     }
 }
 
+// A datanode-to-datanode copy started by the master to restore a block's replicas
+// The copy holds a read lease on the file (at the source) until the target reports it
+// received the block
+#[derive(Debug, Clone)]
+pub struct Replication {
+    pub filename: String,
+    pub block: String,
+    // Checksum the target checks the copy against
+    pub checksum: u32,
+    pub source: u8,
+    pub target: u8,
+    pub started: Instant,
+    // Read lease on the file held by the copy
+    pub lease: u64,
+}
+
+// A put in progress. Its version is added once the client commits it, with every block
+// stored on at least WRITE_QUORUM datanodes
+#[derive(Debug, Clone)]
+pub struct PendingWrite {
+    pub version: u32,
+    // Size of the file and checksum of each block, as given by the client
+    pub size: u64,
+    pub checksums: Vec<u32>,
+}
+
+// A block as a datanode reports holding it. Its file and version are those of its name
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredBlock {
+    pub block: String,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+// What the master made of a datanode's block report
+#[derive(Debug, Default, PartialEq)]
+pub struct Reconciliation {
+    // Replicas recorded on the datanode that it no longer holds, dropped to be copied again
+    pub missing: Vec<String>,
+    // Intact copies the datanode holds that were not recorded, now added as replicas
+    pub found: Vec<String>,
+    // Copies that do not match their block's size or checksum, for the datanode to delete.
+    // Those that were recorded are dropped, to be copied again
+    pub corrupt: Vec<String>,
+    // Blocks in no file's kept versions (e.g. of a deleted file, or a put that never
+    // completed), for the datanode to delete
+    pub orphaned: Vec<String>,
+}
+
+// A file or directory in a directory. A directory has no size, version, replicas or mtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    // Name within the directory
+    pub name: String,
+    pub is_dir: bool,
+    // Size of the latest version, in bytes
+    pub size: u64,
+    pub version: u32,
+    // Fewest datanodes storing a block of the latest version
+    pub replicas: u32,
+    // Seconds since the epoch at which the latest version was added
+    pub mtime: u64,
+}
+
 #[derive(Debug)]
 pub struct Metadata {
-    // Key is filename, Value is vector of vm numbers (1-indexed)
-    pub files_storage: BTreeMap<String, Vec<u8>>,
+    // Where each block is stored, and the versions of each file
+    pub namespace: Namespace,
+    // Key is vm number, Value is the blocks it stores
     pub datanode_usage: BTreeMap<u8, Vec<String>>,
     pub files_sync: HashMap<String, FileSync>,
+    pub replications: Vec<Replication>,
+    // Key is the versioned filename (see versioned_filename). Not logged, a put in progress
+    // when the master fails is lost
+    pub writes: HashMap<String, PendingWrite>,
+    // Key is vm number, Value is when each of its replicas was added. Not logged, only used to
+    // leave replicas added while a block report was being scanned for the next one
+    pub replicas_added: BTreeMap<u8, HashMap<String, Instant>>,
+    // Where changes to the namespace are made durable, if anywhere
+    pub log: Option<MetadataLog>,
 }
 
 impl Metadata {
-    // Initialise from a metadata file
-    pub fn from(metadata_path: &str) -> Self {
+    // Builds metadata around a namespace, without a log
+    pub fn from_namespace(namespace: Namespace) -> Self {
         // Default is minimal datanode_usage with 10 VMs
         let mut datanode_usage: BTreeMap<u8, Vec<String>> = BTreeMap::new();
         for i in 1..11 {
             datanode_usage.insert(i, Vec::new());
         }
-
-        // Attempt to open file, return empty if file does not exist
-        let mut file = if let Ok(file) = File::open(metadata_path) {
-            file
-        } else {
-            return Metadata {
-                files_storage: BTreeMap::new(),
-                datanode_usage,
-                files_sync: HashMap::new(),
-            };
-        };
-
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        let mut files_storage: BTreeMap<String, Vec<u8>> = BTreeMap::new();
         let mut files_sync: HashMap<String, FileSync> = HashMap::new();
 
-        // Parse the metadata file
-        for line in data.lines() {
-            let (name, node) = if let Some((prefix, suffix)) = line.split_once(":") {
-                (prefix, suffix)
-            } else {
-                continue;
-            };
-
-            let node: u8 = if let Ok(num) = node.parse() {
-                num
-            } else {
-                continue;
-            };
-
-            // Add to file_storage
-            files_storage
-                .entry(String::from(name))
-                .and_modify(|datanodes| datanodes.push(node))
-                .or_insert(vec![node]);
-
-            // Add to datanode_usage
-            datanode_usage
-                .entry(node)
-                .and_modify(|files| files.push(String::from(name)))
-                .or_insert(vec![String::from(name)]);
+        for (name, block) in namespace.blocks.iter() {
+            for node in block.nodes.iter() {
+                // Add to datanode_usage
+                datanode_usage.entry(*node).or_default().push(name.clone());
+            }
 
             // Add to writer_access_info
-            files_sync
-                .entry(String::from(name))
-                .or_insert(FileSync::new());
+            if let Some((filename, _, _)) = parse_block_name(name) {
+                files_sync.entry(filename).or_default();
+            }
+        }
+        for filename in namespace.files_versions.keys() {
+            files_sync.entry(filename.clone()).or_default();
         }
 
         Metadata {
-            files_storage,
+            namespace,
             datanode_usage,
             files_sync,
+            replications: Vec::new(),
+            writes: HashMap::new(),
+            replicas_added: BTreeMap::new(),
+            log: None,
+        }
+    }
+
+    // Recovers metadata from a checkpoint and log, and keeps logging to them
+    pub fn recover(checkpoint_path: &str, log_path: &str) -> Self {
+        let (log, namespace) = MetadataLog::open(checkpoint_path, log_path).unwrap();
+        let mut metadata = Metadata::from_namespace(namespace);
+        metadata.log = Some(log);
+        metadata
+    }
+
+    // Sequence number of the last change logged, 0 without a log
+    pub fn log_seq(&self) -> u64 {
+        match &self.log {
+            Some(log) => log.seq(),
+            None => 0,
         }
     }
 
+    // Adds the replicas the datanodes report storing, as (node, stored names), that the
+    // metadata is missing. Used by a newly elected master, whose log may be behind. Only
+    // blocks the metadata already knows of are added, since a stored block does not say
+    // how many blocks its version has
+    pub fn merge_block_reports(&mut self, reports: Vec<(u8, Vec<String>)>) {
+        for (node, stored_names) in reports {
+            for stored_name in stored_names {
+                if self.namespace.blocks.contains_key(&stored_name) {
+                    self.add_replica(stored_name, node);
+                }
+            }
+        }
+    }
+
+    // Logs a change, then applies it
+    fn record(&mut self, record: LogRecord) {
+        if let Some(log) = &mut self.log {
+            log.append(&record).expect("failed to write metadata log");
+        }
+        self.apply(&record);
+        self.checkpoint_if_needed();
+    }
+
+    fn checkpoint_if_needed(&mut self) {
+        if let Some(log) = &mut self.log {
+            if log.needs_checkpoint() {
+                log.checkpoint(&self.namespace)
+                    .expect("failed to write metadata checkpoint");
+            }
+        }
+    }
+
+    // Applies a change to the in-memory metadata only
+    pub fn apply(&mut self, record: &LogRecord) {
+        record.apply(&mut self.namespace);
+        match record {
+            LogRecord::AddReplica { block, node } => {
+                let blocks = self.datanode_usage.entry(*node).or_default();
+                if !blocks.contains(block) {
+                    blocks.push(block.clone());
+                }
+                self.replicas_added
+                    .entry(*node)
+                    .or_default()
+                    .insert(block.clone(), Instant::now());
+                if let Some((filename, _, _)) = parse_block_name(block) {
+                    self.files_sync.entry(filename).or_default();
+                }
+            }
+            LogRecord::RemoveDatanode { node } => {
+                if let Some(blocks) = self.datanode_usage.get_mut(node) {
+                    blocks.clear();
+                }
+                self.replicas_added.remove(node);
+            }
+            LogRecord::RemoveReplica { block, node } => {
+                if let Some(blocks) = self.datanode_usage.get_mut(node) {
+                    blocks.retain(|stored| stored != block);
+                }
+            }
+            // Blocks of versions no longer kept are dropped
+            LogRecord::AddVersion { filename, .. } => {
+                self.files_sync.entry(filename.clone()).or_default();
+                self.forget_dropped_blocks();
+            }
+            LogRecord::RemoveFile { filename } => {
+                self.forget_dropped_blocks();
+                self.forget_unused_lock(filename);
+            }
+            LogRecord::MakeDir { .. } => {}
+            LogRecord::Rename { from, to } => {
+                for blocks in self.datanode_usage.values_mut() {
+                    for block in blocks.iter_mut() {
+                        if let Some((filename, version, index)) = parse_block_name(block) {
+                            if let Some(moved) = renamed_path(&filename, from, to) {
+                                *block = block_name(&moved, version, index);
+                            }
+                        }
+                    }
+                }
+                let moved_from: Vec<String> = self
+                    .files_sync
+                    .keys()
+                    .filter(|filename| renamed_path(filename, from, to).is_some())
+                    .cloned()
+                    .collect();
+                for filename in moved_from {
+                    self.forget_unused_lock(&filename);
+                }
+                for filename in self.namespace.files_under(to) {
+                    self.files_sync.entry(filename).or_default();
+                }
+            }
+        }
+    }
+
+    // Drops the lock of a file that is gone. It is kept while others wait on it, they find
+    // the file gone
+    fn forget_unused_lock(&mut self, filename: &str) {
+        let unused = match self.files_sync.get(filename) {
+            Some(file_sync) => file_sync.state() == FileState::Free && file_sync.waiting() == 0,
+            None => false,
+        };
+        if unused {
+            self.files_sync.remove(filename);
+        }
+    }
+
+    // Drops blocks that are no longer in the namespace from datanode_usage
+    fn forget_dropped_blocks(&mut self) {
+        let blocks = &self.namespace.blocks;
+        for node_blocks in self.datanode_usage.values_mut() {
+            node_blocks.retain(|block| blocks.contains_key(block));
+        }
+    }
+
+    // Logs and applies a change shipped from the master. Returns false if earlier changes
+    // were missed, in which case the master should send a snapshot
+    pub fn apply_replicated(&mut self, seq: u64, record: LogRecord) -> bool {
+        let log = match &mut self.log {
+            Some(log) => log,
+            None => return false,
+        };
+        // Already have it
+        if seq <= log.seq() {
+            return true;
+        }
+        if log.append_at(seq, &record).is_err() {
+            return false;
+        }
+        self.apply(&record);
+        self.checkpoint_if_needed();
+        true
+    }
+
+    // Replaces the namespace with a snapshot from the master, as of change seq
+    pub fn install_snapshot(&mut self, seq: u64, namespace: Namespace) {
+        let mut metadata = Metadata::from_namespace(namespace);
+        metadata.log = self.log.take();
+        if let Some(log) = &mut metadata.log {
+            log.checkpoint_at(seq, &metadata.namespace)
+                .expect("failed to write metadata checkpoint");
+        }
+        *self = metadata;
+    }
+
     // Sort the datanodes by load, return the n lowest
-    // Taking into membership
-    pub fn get_n_free_nodes(&self, mut n: usize, membership: Vec<u8>) -> Vec<u8> {
-        let num_nodes = self.datanode_usage.len();
-        if n > num_nodes {
-            n = num_nodes;
+    // Taking into membership, only datanodes that are alive (not suspected) are used
+    pub fn get_n_free_nodes(&self, n: usize, membership: Vec<u8>) -> Vec<u8> {
+        self.least_loaded(n, &membership, &BTreeMap::new())
+    }
+
+    // Places each of the given number of blocks on n datanodes, the least loaded counting
+    // the blocks placed before it
+    pub fn place_blocks(&self, blocks: usize, n: usize, membership: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut placed: BTreeMap<u8, usize> = BTreeMap::new();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..blocks {
+            let nodes = self.least_loaded(n, &membership, &placed);
+            for node in nodes.iter() {
+                *placed.entry(*node).or_default() += 1;
+            }
+            output.push(nodes);
         }
+        output
+    }
 
+    // The n alive datanodes storing the fewest blocks, with extra blocks added to some
+    fn least_loaded(
+        &self,
+        mut n: usize,
+        membership: &[u8],
+        extra: &BTreeMap<u8, usize>,
+    ) -> Vec<u8> {
         let mut to_sort: Vec<(u8, usize)> = Vec::new();
 
-        for i in 0..membership.len() {
-            if membership[i] != 1 {
+        for (i, status) in membership.iter().enumerate() {
+            if *status != NODE_ALIVE {
                 continue;
             }
             let datanode_num: u8 = (i + 1) as u8;
             let entry = self.datanode_usage.get(&datanode_num).unwrap();
-            to_sort.push((datanode_num, entry.len()));
+            let load = entry.len() + extra.get(&datanode_num).unwrap_or(&0);
+            to_sort.push((datanode_num, load));
         }
 
         to_sort.sort_by_key(|val| val.1);
+        if n > to_sort.len() {
+            n = to_sort.len();
+        }
 
         let mut output: Vec<u8> = Vec::new();
         for pair in &to_sort[0..n] {
@@ -213,63 +599,719 @@ impl Metadata {
         output
     }
 
-    // Returns all datanodes storing specified file, or just [0] if file not in SDFS
-    pub fn get_nodes_for_file(&self, filename: String) -> Vec<u8> {
-        if let Some(nodes) = self.files_storage.get(&filename) {
-            return nodes.to_vec();
+    // Whether SDFS has any version or block of a file
+    pub fn has_file(&self, filename: &str) -> bool {
+        self.namespace.files_versions.contains_key(filename)
+            || !self.namespace.file_blocks(filename).is_empty()
+    }
+
+    // Every file with a version whose path starts with prefix and matches pattern (see
+    // glob_match, an empty pattern matches anything), in name order
+    pub fn list_files(&self, prefix: &str, pattern: &str) -> Vec<String> {
+        self.namespace
+            .files_versions
+            .range(String::from(prefix)..)
+            .take_while(|(filename, _)| filename.starts_with(prefix))
+            .filter(|(_, versions)| !versions.is_empty())
+            .filter(|(filename, _)| pattern.is_empty() || glob_match(pattern, filename))
+            .map(|(filename, _)| filename.clone())
+            .collect()
+    }
+
+    // The files and directories directly in a directory, in name order. Errors with
+    // NotFound if it is not a directory
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, SdfsError> {
+        if !self.namespace.is_dir(path) {
+            return Err(SdfsError::NotFound(String::from(path)));
+        }
+        let prefix = if path.is_empty() {
+            String::new()
         } else {
+            format!("{}/", path)
+        };
+
+        // Key is name within the directory, Value is whether it is a directory
+        let mut children: BTreeMap<String, bool> = BTreeMap::new();
+        let files = self
+            .namespace
+            .files_versions
+            .range(prefix.clone()..)
+            .map(|(filename, _)| filename);
+        let dirs = self.namespace.dirs.range(prefix.clone()..);
+        for (name, is_dir) in files
+            .take_while(|filename| filename.starts_with(&prefix))
+            .map(|filename| (filename, false))
+            .chain(
+                dirs.take_while(|dir| dir.starts_with(&prefix))
+                    .map(|dir| (dir, true)),
+            )
+        {
+            let rest = &name[prefix.len()..];
+            match rest.split_once('/') {
+                Some((child, _)) => children.insert(String::from(child), true),
+                None => children.insert(String::from(rest), is_dir),
+            };
+        }
+
+        Ok(children
+            .into_iter()
+            .map(|(name, is_dir)| {
+                if is_dir {
+                    return DirEntry {
+                        name,
+                        is_dir,
+                        size: 0,
+                        version: 0,
+                        replicas: 0,
+                        mtime: 0,
+                    };
+                }
+                let filename = prefix.clone() + &name;
+                let version = self.latest_version(&filename);
+                let stat = self
+                    .namespace
+                    .version_stats
+                    .get(&filename)
+                    .and_then(|stats| stats.get(&version))
+                    .copied()
+                    .unwrap_or_default();
+                let replicas = self
+                    .get_blocks(&filename, version)
+                    .iter()
+                    .map(|location| location.nodes.len() as u32)
+                    .min()
+                    .unwrap_or(0);
+                DirEntry {
+                    name,
+                    is_dir,
+                    size: stat.size,
+                    version,
+                    replicas,
+                    mtime: stat.mtime,
+                }
+            })
+            .collect())
+    }
+
+    // Checks that a file or directory can be made at a path: it is not the root, and none of
+    // the directories above it is a file
+    pub fn check_new_path(&self, path: &str) -> Result<(), SdfsError> {
+        if path.is_empty() {
+            return Err(SdfsError::BadRequest(String::from(
+                "the root is a directory",
+            )));
+        }
+        let mut parent = path;
+        while let Some((above, _)) = parent.rsplit_once('/') {
+            if self.namespace.is_file(above) {
+                return Err(SdfsError::BadRequest(format!("{} is a file", above)));
+            }
+            parent = above;
+        }
+        Ok(())
+    }
+
+    // Makes a directory, and those above it. Does nothing if it already exists
+    pub fn mkdir(&mut self, path: &str) -> Result<(), SdfsError> {
+        self.check_new_path(path)?;
+        if self.namespace.is_file(path) {
+            return Err(SdfsError::BadRequest(format!("{} is a file", path)));
+        }
+        if !self.namespace.dirs.contains(path) {
+            self.record(LogRecord::MakeDir {
+                path: String::from(path),
+            });
+        }
+        Ok(())
+    }
+
+    // Deletes an empty directory. Errors with BadRequest if anything is under it
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), SdfsError> {
+        if path.is_empty() {
+            return Err(SdfsError::BadRequest(String::from(
+                "cannot delete the root",
+            )));
+        }
+        if !self.namespace.is_dir(path) {
+            return Err(SdfsError::NotFound(String::from(path)));
+        }
+        if !self.list_dir(path)?.is_empty() {
+            return Err(SdfsError::BadRequest(format!("{} is not empty", path)));
+        }
+        if self.namespace.dirs.contains(path) {
+            self.record(LogRecord::RemoveFile {
+                filename: String::from(path),
+            });
+        }
+        Ok(())
+    }
+
+    // Checks that a file or directory can be moved to a path that is free. Returns every file
+    // it moves, with where each goes
+    pub fn check_rename(&self, from: &str, to: &str) -> Result<Vec<(String, String)>, SdfsError> {
+        if from.is_empty() {
+            return Err(SdfsError::BadRequest(String::from("cannot move the root")));
+        }
+        if renamed_path(to, from, to).is_some() {
+            return Err(SdfsError::BadRequest(format!(
+                "cannot move {} into itself",
+                from
+            )));
+        }
+        if !self.namespace.is_file(from) && !self.namespace.is_dir(from) {
+            return Err(SdfsError::NotFound(String::from(from)));
+        }
+        self.check_new_path(to)?;
+        if self.namespace.is_file(to) || self.namespace.is_dir(to) {
+            return Err(SdfsError::BadRequest(format!("{} already exists", to)));
+        }
+        Ok(self
+            .namespace
+            .files_under(from)
+            .into_iter()
+            .map(|filename| {
+                let moved = renamed_path(&filename, from, to).unwrap();
+                (filename, moved)
+            })
+            .collect())
+    }
+
+    // Moves a file, or a directory with everything under it, to a path that is free
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), SdfsError> {
+        self.check_rename(from, to)?;
+        self.record(LogRecord::Rename {
+            from: String::from(from),
+            to: String::from(to),
+        });
+        Ok(())
+    }
+
+    // Returns all datanodes storing a block of specified file, or just [0] if file not in SDFS
+    pub fn get_nodes_for_file(&self, filename: String) -> Vec<u8> {
+        if !self.has_file(&filename) {
             return vec![0];
         }
+        let mut nodes: Vec<u8> = Vec::new();
+        for block in self.namespace.file_blocks(&filename) {
+            for node in self.namespace.blocks[&block].nodes.iter() {
+                if !nodes.contains(node) {
+                    nodes.push(*node);
+                }
+            }
+        }
+        nodes.sort_unstable();
+        nodes
     }
 
-    // Add data for a new file to self
-    pub fn add_file(&mut self, filename: String, node: u8) {
-        // Add to file_storage
-        self.files_storage
-            .entry(filename.clone())
-            .and_modify(|datanodes| {
-                for node_used in datanodes.iter() {
-                    if *node_used == node {
-                        return;
-                    }
+    // Blocks of a version of a file in order, each with its checksum and the datanodes
+    // storing it
+    pub fn get_blocks(&self, filename: &str, version: u32) -> Vec<BlockLocation> {
+        self.namespace
+            .version_blocks(filename, version)
+            .into_iter()
+            .map(|block| {
+                let stored = &self.namespace.blocks[&block];
+                BlockLocation {
+                    checksum: stored.checksum,
+                    nodes: stored.nodes.clone(),
+                    block,
                 }
-                datanodes.push(node);
             })
-            .or_insert(vec![node]);
-
-        // Add to datanode_usage
-        self.datanode_usage
-            .entry(node)
-            .and_modify(|files| {
-                for curr_file in files.iter() {
-                    if *curr_file == filename {
-                        return;
-                    }
+            .collect()
+    }
+
+    // Add a replica of a block to self
+    pub fn add_replica(&mut self, block: String, node: u8) {
+        if let Some(stored) = self.namespace.blocks.get(&block) {
+            if stored.nodes.contains(&node) {
+                return;
+            }
+        }
+        self.record(LogRecord::AddReplica { block, node });
+    }
+
+    // Latest version of a file, 0 if it has none
+    pub fn latest_version(&self, filename: &str) -> u32 {
+        match self.namespace.files_versions.get(filename) {
+            Some(versions) => *versions.last().unwrap_or(&0),
+            None => 0,
+        }
+    }
+
+    // Version for a new put of a file. Skips versions whose put never completed, whose
+    // blocks may still be on some datanodes
+    pub fn next_version(&self, filename: &str) -> u32 {
+        let mut version = self.latest_version(filename);
+        for key in self.writes.keys() {
+            if let Some((name, write_version)) = parse_versioned_filename(key) {
+                if name == filename {
+                    version = version.max(write_version);
                 }
-                files.push(filename.clone())
-            })
-            .or_insert(vec![filename.clone()]);
+            }
+        }
+        for block in self.namespace.file_blocks(filename) {
+            if let Some((_, block_version, _)) = parse_block_name(&block) {
+                version = version.max(block_version);
+            }
+        }
+        version + 1
+    }
+
+    // Newest n versions kept of a file, newest first
+    pub fn get_versions(&self, filename: &str, n: usize) -> Vec<u32> {
+        match self.namespace.files_versions.get(filename) {
+            Some(versions) => versions.iter().rev().take(n).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Add a version of a file of the given size, once every block of it is stored. Its mtime
+    // is now
+    pub fn add_version(&mut self, filename: String, version: u32, checksums: Vec<u32>, size: u64) {
+        if let Some(versions) = self.namespace.files_versions.get(&filename) {
+            if versions.contains(&version) {
+                return;
+            }
+        }
+        let mtime = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs(),
+            Err(_) => 0,
+        };
+        self.record(LogRecord::AddVersion {
+            filename,
+            version,
+            checksums,
+            size,
+            mtime,
+        });
+    }
 
-        self.write_to_file();
+    // Starts a put of a file of the given size with the given block checksums. Returns its
+    // version
+    pub fn start_write(&mut self, filename: &str, size: u64, checksums: Vec<u32>) -> u32 {
+        // Each put writes a new version, stored beside the older ones
+        let version = self.next_version(filename);
+        self.writes.insert(
+            versioned_filename(filename, version),
+            PendingWrite {
+                version,
+                size,
+                checksums,
+            },
+        );
+        version
     }
 
-    // Writes current metadata to a file
-    fn write_to_file(&self) {
-        let mut file = File::create(METADATA_PATH).unwrap();
-        for (filename, nodes) in self.files_storage.iter() {
+    // Adds a block of the given size and checksum to the end of a put in progress, for
+    // versions written without knowing their size up front (see SdfsWriter). Renews the put's
+    // lease. Errors with NotFound if the put is not in progress, or Locked if its lease is no
+    // longer held, or BadRequest unless every block but the last is BLOCK_SIZE long. Returns
+    // the index of the block
+    pub fn add_block(
+        &mut self,
+        filename: &str,
+        version: u32,
+        lease: u64,
+        size: u64,
+        checksum: u32,
+    ) -> Result<u32, SdfsError> {
+        let pending = versioned_filename(filename, version);
+        if !self.writes.contains_key(&pending) {
+            return Err(SdfsError::NotFound(pending));
+        }
+        if !self.renew_lease(filename, lease) {
+            return Err(SdfsError::Locked(String::from(filename)));
+        }
+        let write = self.writes.get_mut(&pending).unwrap();
+        if size > BLOCK_SIZE || write.size != write.checksums.len() as u64 * BLOCK_SIZE {
+            return Err(SdfsError::BadRequest(format!(
+                "block of {} bytes after {} bytes in {} blocks",
+                size,
+                write.size,
+                write.checksums.len()
+            )));
+        }
+        write.size += size;
+        write.checksums.push(checksum);
+        Ok(write.checksums.len() as u32 - 1)
+    }
+
+    // Called when the client that put a version reports the datanodes that confirmed each
+    // of its blocks. The version is added if every block is stored on at least WRITE_QUORUM
+    // datanodes, otherwise the put has failed. Either way it is no longer in progress
+    pub fn commit_write(
+        &mut self,
+        filename: &str,
+        version: u32,
+        stored: Vec<Vec<u8>>,
+    ) -> Result<(), SdfsError> {
+        let write = match self.writes.remove(&versioned_filename(filename, version)) {
+            Some(write) => write,
+            None => {
+                return Err(SdfsError::NotFound(versioned_filename(filename, version)));
+            }
+        };
+        if stored.len() != write.checksums.len() {
+            return Err(SdfsError::BadRequest(format!(
+                "put has {} blocks, {} reported",
+                write.checksums.len(),
+                stored.len()
+            )));
+        }
+
+        // The datanodes also report the blocks they receive, which may not have arrived yet
+        for (index, nodes) in stored.into_iter().enumerate() {
+            let block = block_name(filename, version, index as u32);
             for node in nodes {
-                let line = format!("{}:{}\n", filename, node);
-                file.write(line.as_bytes()).unwrap();
+                self.add_replica(block.clone(), node);
+            }
+            let replicas = match self.namespace.blocks.get(&block) {
+                Some(stored) => stored.nodes.len(),
+                None => 0,
+            };
+            if replicas < WRITE_QUORUM as usize {
+                return Err(SdfsError::NoQuorum(format!(
+                    "{} stored on {} datanodes, {} needed",
+                    block, replicas, WRITE_QUORUM
+                )));
+            }
+        }
+        self.add_version(String::from(filename), version, write.checksums, write.size);
+        Ok(())
+    }
+
+    // Checks the checksums a datanode's scrubber computed for the blocks it stores against
+    // those recorded when they were put. Replicas that do not match are dropped, to be
+    // copied again from the others, and returned so that the datanode deletes them. Blocks
+    // of versions not yet added, and copies not yet finished, have nothing to compare to
+    pub fn check_replicas(&mut self, node: u8, checksums: Vec<(String, u32)>) -> Vec<String> {
+        let mut corrupt: Vec<String> = Vec::new();
+        for (block, checksum) in checksums {
+            let stored = match self.namespace.blocks.get(&block) {
+                Some(stored) => stored,
+                None => continue,
+            };
+            if !stored.nodes.contains(&node) || stored.checksum == checksum {
+                continue;
+            }
+            let added = match parse_block_name(&block) {
+                Some((filename, version, _)) => self
+                    .get_versions(&filename, MAX_VERSIONS)
+                    .contains(&version),
+                None => false,
+            };
+            if added {
+                self.record(LogRecord::RemoveReplica {
+                    block: block.clone(),
+                    node,
+                });
+                corrupt.push(block);
+            }
+        }
+        corrupt
+    }
+
+    // Reconciles the metadata with the blocks a datanode reports holding. Blocks of files
+    // being read, written, renamed or copied are left for a later report, as the datanode may
+    // have listed them mid-transfer. Blocks of versions not yet added have nothing to compare
+    // to, and are neither added nor deleted. Replicas added within scan_age, since the datanode
+    // started listing its blocks, may have been stored after it listed them and are not missing
+    pub fn reconcile_block_report(
+        &mut self,
+        node: u8,
+        report: Vec<StoredBlock>,
+        scan_age: Duration,
+    ) -> Reconciliation {
+        let mut result = Reconciliation::default();
+        let mut checksums: Vec<(String, u32)> = Vec::new();
+        let mut reported: HashSet<String> = HashSet::new();
+        for stored in report {
+            let (filename, version, index) = match parse_block_name(&stored.block) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            reported.insert(stored.block.clone());
+            if self.is_busy(&filename) {
+                continue;
+            }
+            let recorded = match self.namespace.blocks.get(&stored.block) {
+                Some(recorded) => recorded,
+                None => {
+                    let pending = versioned_filename(&filename, version);
+                    if !self.writes.contains_key(&pending) {
+                        result.orphaned.push(stored.block);
+                    }
+                    continue;
+                }
+            };
+            if !self
+                .get_versions(&filename, MAX_VERSIONS)
+                .contains(&version)
+            {
+                continue;
+            }
+            let size_matches = self
+                .expected_block_size(&filename, version, index)
+                .is_none_or(|size| size == stored.size);
+            if recorded.nodes.contains(&node) {
+                if size_matches {
+                    checksums.push((stored.block, stored.checksum));
+                } else {
+                    self.remove_replica(stored.block.clone(), node);
+                    result.corrupt.push(stored.block);
+                }
+            } else if size_matches && recorded.checksum == stored.checksum {
+                self.add_replica(stored.block.clone(), node);
+                result.found.push(stored.block);
+            } else {
+                result.corrupt.push(stored.block);
             }
         }
-        file.flush().unwrap();
+        result.corrupt.extend(self.check_replicas(node, checksums));
+
+        // Replicas added before the scan are settled by this report
+        let mut added_since: HashSet<String> = HashSet::new();
+        if let Some(added) = self.replicas_added.get_mut(&node) {
+            added.retain(|_, added_at| added_at.elapsed() < scan_age);
+            added_since.extend(added.keys().cloned());
+        }
+        let recorded = self.datanode_usage.get(&node).cloned().unwrap_or_default();
+        for block in recorded {
+            let busy = match parse_block_name(&block) {
+                Some((filename, _, _)) => self.is_busy(&filename),
+                None => true,
+            };
+            if !busy && !reported.contains(&block) && !added_since.contains(&block) {
+                self.remove_replica(block.clone(), node);
+                result.missing.push(block);
+            }
+        }
+        result
+    }
+
+    // Whether a file is being read, written, renamed or copied, or has requests waiting
+    fn is_busy(&self, filename: &str) -> bool {
+        match self.files_sync.get(filename) {
+            Some(file_sync) => file_sync.state() != FileState::Free || file_sync.waiting() > 0,
+            None => false,
+        }
+    }
+
+    // Size a block of an added version should have, None if the version was logged before
+    // sizes were recorded
+    fn expected_block_size(&self, filename: &str, version: u32, index: u32) -> Option<u64> {
+        let stat = self.namespace.version_stats.get(filename)?.get(&version)?;
+        if stat.mtime == 0 {
+            return None;
+        }
+        let offset = index as u64 * BLOCK_SIZE;
+        Some(stat.size.saturating_sub(offset).min(BLOCK_SIZE))
+    }
+
+    // Forget a replica, e.g. one a datanode failed to rename
+    pub fn remove_replica(&mut self, block: String, node: u8) {
+        if let Some(stored) = self.namespace.blocks.get(&block) {
+            if stored.nodes.contains(&node) {
+                self.record(LogRecord::RemoveReplica { block, node });
+            }
+        }
+    }
+
+    // Forget a deleted file
+    pub fn remove_file(&mut self, filename: &str) {
+        self.writes
+            .retain(|key, _| match parse_versioned_filename(key) {
+                Some((name, _)) => name != filename,
+                None => true,
+            });
+        self.record(LogRecord::RemoveFile {
+            filename: String::from(filename),
+        });
+    }
+
+    // Forget every replica held by a failed datanode, and any copies to or from it
+    pub fn remove_datanode(&mut self, node: u8) {
+        self.record(LogRecord::RemoveDatanode { node });
+
+        let failed: Vec<Replication> = self
+            .replications
+            .iter()
+            .filter(|copy| copy.source == node || copy.target == node)
+            .cloned()
+            .collect();
+        for copy in failed {
+            self.cancel_replication(&copy.block, copy.target);
+        }
+    }
+
+    // Blocks of kept versions with fewer than REPLICATION_FACTOR replicas, with their
+    // number of replicas
+    pub fn get_under_replicated(&self) -> Vec<(String, usize)> {
+        let mut output: Vec<(String, usize)> = Vec::new();
+        for (name, block) in self.namespace.blocks.iter() {
+            if block.nodes.len() >= REPLICATION_FACTOR as usize {
+                continue;
+            }
+            let kept = match parse_block_name(name) {
+                Some((filename, version, _)) => self
+                    .get_versions(&filename, MAX_VERSIONS)
+                    .contains(&version),
+                None => false,
+            };
+            if kept {
+                output.push((name.clone(), block.nodes.len()));
+            }
+        }
+        output
+    }
+
+    // Least loaded datanodes that are alive and do not already hold the block, at most n
+    pub fn get_replication_targets(&self, block: &str, n: usize, membership: Vec<u8>) -> Vec<u8> {
+        let holders = match self.namespace.blocks.get(block) {
+            Some(stored) => stored.nodes.clone(),
+            None => Vec::new(),
+        };
+        let mut output = self.get_n_free_nodes(VM_LIST.len(), membership);
+        output.retain(|node| !holders.contains(node));
+        output.truncate(n);
+        output
+    }
+
+    // Copies in progress for a block
+    pub fn get_replications_for_block(&self, block: &str) -> usize {
+        self.replications
+            .iter()
+            .filter(|copy| copy.block == block)
+            .count()
+    }
+
+    // Called when a datanode reports receiving a block. Returns false if it was not a copy
+    // started by the master (i.e. it was a client write)
+    pub fn finish_replication(&mut self, block: &str, target: u8) -> bool {
+        let index = match self
+            .replications
+            .iter()
+            .position(|copy| copy.block == block && copy.target == target)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let copy = self.replications.remove(index);
+        if let Some(file_sync) = self.files_sync.get_mut(&copy.filename) {
+            file_sync.release(copy.lease);
+        }
+        self.add_replica(String::from(block), target);
+        true
+    }
+
+    // Abandons a copy, releasing its read on the file so it can be retried
+    pub fn cancel_replication(&mut self, block: &str, target: u8) {
+        if let Some(index) = self
+            .replications
+            .iter()
+            .position(|copy| copy.block == block && copy.target == target)
+        {
+            let copy = self.replications.remove(index);
+            if let Some(file_sync) = self.files_sync.get_mut(&copy.filename) {
+                file_sync.release(copy.lease);
+            }
+        }
+    }
+
+    // Extends a lease on a file. Returns false if it is no longer held
+    pub fn renew_lease(&mut self, filename: &str, lease: u64) -> bool {
+        match self.files_sync.get_mut(filename) {
+            Some(file_sync) => file_sync.renew(lease),
+            None => false,
+        }
+    }
+
+    // Gives up a lease on a file. Returns false if it was not held
+    pub fn release_lease(&mut self, filename: &str, lease: u64) -> bool {
+        match self.files_sync.get_mut(filename) {
+            Some(file_sync) => file_sync.release(lease),
+            None => false,
+        }
+    }
+
+    // Releases every lease that expired by now, letting waiters on their files in. Returns
+    // the files and leases released
+    pub fn expire_leases(&mut self, now: Instant) -> Vec<(String, Lease)> {
+        let mut expired: Vec<(String, Lease)> = Vec::new();
+        for (filename, file_sync) in self.files_sync.iter_mut() {
+            for lease in file_sync.expire_leases(now) {
+                expired.push((filename.clone(), lease));
+            }
+        }
+        expired
+    }
+
+    // Abandons copies that have not completed within timeout
+    pub fn expire_replications(&mut self, timeout: Duration) {
+        let expired: Vec<Replication> = self
+            .replications
+            .iter()
+            .filter(|copy| copy.started.elapsed() >= timeout)
+            .cloned()
+            .collect();
+        for copy in expired {
+            self.cancel_replication(&copy.block, copy.target);
+        }
     }
 }
 
+// Checkpoint and write-ahead log of the metadata
 pub const METADATA_PATH: &str = "metadata";
+pub const METADATA_LOG_PATH: &str = "metadata.log";
 
 pub const VM_ID_PATH: &str = "client_id.txt";
 
+// Number of datanodes each block is stored on (N)
+pub const REPLICATION_FACTOR: u8 = 4;
+
+// Datanodes that must confirm every block of a put before it is committed (W), and that
+// must hold each block of a version before it is read (R). As W + R > N, any R datanodes a
+// block is placed on include one that confirmed it
+pub const WRITE_QUORUM: u8 = 3;
+pub const READ_QUORUM: u8 = 2;
+const _: () = assert!(WRITE_QUORUM + READ_QUORUM > REPLICATION_FACTOR);
+const _: () = assert!(WRITE_QUORUM <= REPLICATION_FACTOR && READ_QUORUM <= REPLICATION_FACTOR);
+
+// Versions kept of each file, older ones are deleted from the datanodes
+pub const MAX_VERSIONS: usize = 5;
+
+// Each version of a file is split into blocks of BLOCK_SIZE bytes (the last may be shorter),
+// each stored on the datanodes as <filename><VERSION_SEPARATOR><version><BLOCK_SEPARATOR><index>
+pub const BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+pub const VERSION_SEPARATOR: &str = ".v";
+pub const BLOCK_SEPARATOR: &str = ".b";
+
+// Blocks a client reads or writes at once
+pub const TRANSFER_THREADS: usize = 8;
+
+// A copy that has not completed within this long is abandoned and retried
+pub const REPLICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A read or write grant not renewed within this long is released, so that a client that
+// crashed mid-transfer does not hold the file forever. Clients renew theirs every
+// LEASE_RENEW_INTERVAL while transferring
+pub const LEASE_DURATION: Duration = Duration::from_secs(20);
+pub const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(5);
+
+// Reads of a file let in at once, and readers let in ahead of a waiting writer before it
+// gets the file (see FileSync)
+pub const MAX_CONCURRENT_READERS: usize = 4;
+pub const MAX_READERS_BEFORE_WRITER: usize = 8;
+
+// Time between two block reports of a datanode (see Metadata::reconcile_block_report), and
+// before it retries one that failed, e.g. as no master was elected yet
+pub const BLOCK_REPORT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+pub const BLOCK_REPORT_RETRY: Duration = Duration::from_secs(5);
+// Allowance for the time a block report takes to reach the master, added to the time since
+// its scan started
+pub const BLOCK_REPORT_SLACK: Duration = Duration::from_secs(5);
+
 /********************* Membership section *********************/
 
 // Addresses of all servers
@@ -287,17 +1329,189 @@ pub const VM_LIST: [&str; 10] = [
 ];
 
 // Membership list related
-pub const MEMBERSHIP_PATH: &str = "sdfs/membership";
-// pub const HEARTBEAT_PORT: &str = "43278";
+// Each SDFS process is a member of the gossip group, on a port for its kind so that
+// processes on the same VM do not clash. Datanodes advertise ROLE_DATANODE.
+pub const MASTER_GOSSIP_PORT: u16 = 50011;
+pub const DATANODE_GOSSIP_PORT: u16 = 50012;
+pub const ROLE_MASTER: &str = "master";
+pub const ROLE_DATANODE: &str = "datanode";
+
+// Datanode statuses returned by get_membership
+pub const NODE_DOWN: u8 = 0;
+pub const NODE_ALIVE: u8 = 1;
+pub const NODE_SUSPECTED: u8 = 2;
+
+// Reads this VM's number (1-indexed) from VM_ID_PATH
+pub fn get_vm_id() -> u8 {
+    let id_str = fs::read_to_string(VM_ID_PATH).unwrap();
+    id_str.trim().parse().unwrap()
+}
+
+// Joins the gossip group as this VM, advertising the given role
+// The datanode of the first VM acts as introducer
+pub fn start_membership(port: u16, role: &str) -> Membership {
+    let id = get_vm_id();
+
+    let mut metadata: BTreeMap<String, String> = BTreeMap::new();
+    metadata.insert(String::from(gossip::META_ROLES), String::from(role));
+
+    Membership::start(Config {
+        hostname: String::from(VM_LIST[(id - 1) as usize]),
+        port,
+        intro_hostname: String::from(VM_LIST[0]),
+        intro_port: DATANODE_GOSSIP_PORT,
+        metadata,
+    })
+}
 
-pub fn get_membership() -> Vec<u8> {
-    if !fs::metadata(MEMBERSHIP_PATH).is_ok() {
-        // If the file doesn't exist, create an empty file
-        let _ = fs::write(MEMBERSHIP_PATH, "");
+// Returns the VM number (1-indexed) of a hostname, or 0 if it is not in VM_LIST
+pub fn get_vm_number(hostname: &str) -> u8 {
+    match VM_LIST.iter().position(|vm| *vm == hostname) {
+        Some(i) => (i + 1) as u8,
+        None => 0,
     }
-    let data = fs::read(MEMBERSHIP_PATH).unwrap();
+}
 
-    data
+// Master election: every server that can be master advertises ROLE_MASTER, and the one on
+// the lowest numbered VM that gossip has not failed leads (the bully rule, with the failure
+// detector in place of election messages). Returns 0 if no server is known.
+pub fn get_leader(membership: &Membership) -> u8 {
+    role_status(&membership.members(), ROLE_MASTER)
+        .iter()
+        .position(|status| *status != NODE_DOWN)
+        .map_or(0, |i| (i + 1) as u8)
+}
+
+// Servers other than this one that could take over as master, and so are sent the log
+pub fn get_standbys(membership: &Membership, self_vm: u8) -> Vec<u8> {
+    let mut standbys: Vec<u8> = Vec::new();
+    for (i, status) in role_status(&membership.members(), ROLE_MASTER)
+        .iter()
+        .enumerate()
+    {
+        let vm = (i + 1) as u8;
+        if *status != NODE_DOWN && vm != self_vm {
+            standbys.push(vm);
+        }
+    }
+
+    standbys
+}
+
+// Time for gossip to spread before the first election, so that a server joining does not
+// briefly believe it leads before it has heard of the others
+pub const ELECTION_DELAY: Duration = Duration::from_secs(3);
+
+// Query the first available datanode for the current master
+// Note that the servers are numbered [1 - 10]! Returns 0 if no datanode knows the master
+pub fn discover_master() -> u8 {
+    for i in 0..VM_LIST.len() {
+        let master = Connection::open((i + 1) as u8, CD_PORT)
+            .and_then(|mut conn| conn.call(CD_GET_MASTER, Vec::new()))
+            .and_then(|mut reply| reply.node());
+        if let Ok(master) = master {
+            if master != 0 {
+                return master;
+            }
+        }
+    }
+
+    0
+}
+
+// Status of the datanode on each VM, indexed by VM number - 1
+// Datanodes that gossip has failed or never seen are NODE_DOWN
+pub fn get_membership(membership: &Membership) -> Vec<u8> {
+    role_status(&membership.members(), ROLE_DATANODE)
+}
+
+// Status of the members with a role on each VM, indexed by VM number - 1
+// A VM can have several entries, e.g. a failed one from before a restart next to the new
+// one, so the best status wins whatever order they come in: alive, suspected, then down
+pub fn role_status(members: &[Member], role: &str) -> Vec<u8> {
+    let mut status: Vec<u8> = vec![NODE_DOWN; VM_LIST.len()];
+    for member in members {
+        if !member.has_role(role) {
+            continue;
+        }
+        let vm = get_vm_number(&member.hostname);
+        if vm == 0 {
+            continue;
+        }
+        let member_status = match member.status {
+            0 => NODE_ALIVE,
+            2 => NODE_SUSPECTED,
+            _ => NODE_DOWN,
+        };
+        let best = &mut status[(vm - 1) as usize];
+        if status_rank(member_status) > status_rank(*best) {
+            *best = member_status;
+        }
+    }
+
+    status
+}
+
+fn status_rank(status: u8) -> u8 {
+    match status {
+        NODE_ALIVE => 2,
+        NODE_SUSPECTED => 1,
+        _ => 0,
+    }
+}
+
+// Datanode failures seen by one server through gossip, turned into RemoveDatanode records
+// only while it is the master. A standby learns of failures from the master's log, since
+// records of its own would take the sequence numbers of the master's. On taking over, it
+// catches up on datanodes that failed while it stood by, in case the old master died first.
+pub struct DatanodeWatch {
+    last_status: Vec<u8>,
+    // Datanodes seen up at some point; ones never seen are left alone, as before
+    seen: Vec<bool>,
+    leading: bool,
+}
+
+impl Default for DatanodeWatch {
+    fn default() -> Self {
+        DatanodeWatch {
+            last_status: vec![NODE_DOWN; VM_LIST.len()],
+            seen: vec![false; VM_LIST.len()],
+            leading: false,
+        }
+    }
+}
+
+impl DatanodeWatch {
+    // Statuses as of the previous update
+    pub fn last_status(&self) -> &[u8] {
+        &self.last_status
+    }
+
+    // Takes the latest statuses, returning the datanodes whose replicas should be removed
+    pub fn update(&mut self, meta: &Metadata, status: &[u8], leading: bool) -> Vec<u8> {
+        let took_over = leading && !self.leading;
+        let mut failed: Vec<u8> = Vec::new();
+        for (i, curr) in status.iter().enumerate() {
+            if *curr != NODE_DOWN {
+                self.seen[i] = true;
+                continue;
+            }
+            let node = (i + 1) as u8;
+            let newly_failed = self.last_status[i] != NODE_DOWN;
+            let unrecorded = took_over
+                && self.seen[i]
+                && meta
+                    .datanode_usage
+                    .get(&node)
+                    .is_some_and(|blocks| !blocks.is_empty());
+            if leading && (newly_failed || unrecorded) {
+                failed.push(node);
+            }
+        }
+        self.last_status = status.to_vec();
+        self.leading = leading;
+        failed
+    }
 }
 
 // Client ID related
@@ -311,6 +1525,7 @@ pub const CLIENT_ID_PATH: &str = "client_id.txt";
 pub const DM_PORT: &str = "26777";
 pub const CM_PORT: &str = "32339";
 pub const CD_PORT: &str = "38333";
+pub const MM_PORT: &str = "41017";
 
 // Message types - CM
 pub const CM_PUT_REQ: u8 = 1;
@@ -318,123 +1533,714 @@ pub const CM_GET_REQ: u8 = 2;
 pub const CM_DELETE_REQ: u8 = 3;
 pub const CM_LS_REQ: u8 = 4;
 pub const CM_MULTIREAD_REQ: u8 = 5;
+pub const CM_REPLICATION_REQ: u8 = 6;
+pub const CM_GET_VERSIONS_REQ: u8 = 7;
+pub const CM_COMMIT_REQ: u8 = 8;
+pub const CM_LEASE_REQ: u8 = 9;
+pub const CM_LIST_REQ: u8 = 10;
+pub const CM_STAT_REQ: u8 = 11;
+pub const CM_MKDIR_REQ: u8 = 12;
+pub const CM_LIST_DIR_REQ: u8 = 13;
+pub const CM_RENAME_REQ: u8 = 14;
+pub const CM_ADD_BLOCK_REQ: u8 = 15;
 
 // Message types - CD
 pub const CD_GET_MASTER: u8 = 1;
 pub const CD_WRITE_FILE: u8 = 2;
 pub const CD_READ_FILE: u8 = 3;
+pub const CD_REPLICATE: u8 = 4;
+pub const CD_BLOCK_REPORT: u8 = 5;
+pub const CD_DELETE_FILE: u8 = 6;
+pub const CD_FETCH_FILE: u8 = 7;
+pub const CD_LIST_BLOCKS: u8 = 8;
+pub const CD_RENAME_FILE: u8 = 9;
+
+// Message types - MM (master to standby masters)
+pub const MM_LOG_RECORD: u8 = 1;
+pub const MM_SNAPSHOT: u8 = 2;
 
 // Message types - DM
 pub const DM_FILE_RECEIVED: u8 = 1;
 pub const DM_FILE_SENT: u8 = 2;
-
-// Files path
+pub const DM_BLOCK_REPORT: u8 = 3;
+
+// Reply statuses, the kind of every reply frame (see rpc). Any but STATUS_OK is an error (see
+// SdfsError)
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_NOT_FOUND: u8 = 1;
+pub const STATUS_LOCKED: u8 = 2;
+pub const STATUS_NO_CAPACITY: u8 = 3;
+pub const STATUS_CHECKSUM_MISMATCH: u8 = 4;
+pub const STATUS_NOT_MASTER: u8 = 5;
+pub const STATUS_NO_QUORUM: u8 = 6;
+pub const STATUS_BAD_REQUEST: u8 = 7;
+pub const STATUS_IO: u8 = 8;
+
+// Files path. Blocks are stored directly in it, see block_path
 pub const FILES_PATH: &str = "sdfs/files/";
 
-// Buffer size for file send/receives
+// Where a datanode receives blocks before moving them into FILES_PATH. Must be on the same
+// disk, so the move is an atomic rename. Emptied when the datanode starts
+pub const TEMP_PATH: &str = "sdfs/tmp/";
+
+// Buffer size for file send/receives, the most sent in one data frame
 pub const BUF_SIZE: usize = 2048;
 
-// Code used at end of file send/received
-pub const CONFIRMATION: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
+/********************* Misc utility functions section *********************/
+// A path in the SDFS namespace in normal form: its '/'-separated components, without empty
+// ones. The root is "". Errors with BadRequest on a "." or ".." component
+pub fn normalize_path(path: &str) -> Result<String, SdfsError> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" => {}
+            "." | ".." => {
+                return Err(SdfsError::BadRequest(format!(
+                    "{} has a {} component",
+                    path, component
+                )));
+            }
+            _ => components.push(component),
+        }
+    }
+    Ok(components.join("/"))
+}
 
-// Reads 4 bytes off the stream and return the i32 formed
-// Note: Consumes bytes in stream!!
-pub fn read_payload_size(stream: &mut TcpStream) -> io::Result<i32> {
-    let mut size_bytes: [u8; 4] = [0; 4];
-    stream
-        .read_exact(&mut size_bytes)
-        .map(|_| i32::from_le_bytes(size_bytes))
+// Where a path goes when from is moved to to: to itself, or the same place under to. None if
+// the path is not from or under it
+pub fn renamed_path(path: &str, from: &str, to: &str) -> Option<String> {
+    if path == from {
+        return Some(String::from(to));
+    }
+    let rest = path.strip_prefix(from)?.strip_prefix('/')?;
+    Some(format!("{}/{}", to, rest))
 }
 
-// Writes 4 bytes onto the stream
-// Note: Uses the stream!
-pub fn write_payload_size(stream: &mut TcpStream, size: i32) -> io::Result<usize> {
-    let size_bytes = size.to_le_bytes();
-    stream.write(&size_bytes)
+// Whether a path matches a glob pattern, where '*' matches any characters and '?' any one
+// character, but neither matches a '/'
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let path: Vec<char> = path.chars().collect();
+    // matched[i] is whether the pattern so far matches the first i characters of the path
+    let mut matched: Vec<bool> = vec![false; path.len() + 1];
+    matched[0] = true;
+    for token in pattern.chars() {
+        let mut next: Vec<bool> = vec![false; path.len() + 1];
+        for i in 0..=path.len() {
+            next[i] = match token {
+                '*' => matched[i] || (i > 0 && next[i - 1] && path[i - 1] != '/'),
+                '?' => i > 0 && matched[i - 1] && path[i - 1] != '/',
+                _ => i > 0 && matched[i - 1] && path[i - 1] == token,
+            };
+        }
+        matched = next;
+    }
+    matched[path.len()]
 }
 
-/********************* Misc utility functions section *********************/
-pub fn generate_filename_bytes(filename: &str) -> Vec<u8> {
-    let mut filename_bytes = String::from(filename).into_bytes();
-    filename_bytes.insert(0, filename_bytes.len() as u8);
-    filename_bytes
+// The part of a glob pattern before its first wildcard, which every path it matches starts
+// with
+pub fn glob_prefix(pattern: &str) -> &str {
+    match pattern.find(['*', '?']) {
+        Some(wildcard) => &pattern[..wildcard],
+        None => pattern,
+    }
+}
+
+// Local path a datanode stores a block at. The '/'s of its file's path are escaped, so every
+// block is directly in FILES_PATH
+pub fn block_path(block: &str) -> String {
+    String::from(FILES_PATH) + &block.replace('%', "%25").replace('/', "%2F")
+}
+
+// Name of the block stored under a file name in FILES_PATH (see block_path)
+pub fn stored_block_name(file_name: &str) -> String {
+    file_name.replace("%2F", "/").replace("%25", "%")
+}
+
+// Name a version of a file is stored under on the datanodes
+pub fn versioned_filename(filename: &str, version: u32) -> String {
+    format!("{}{}{}", filename, VERSION_SEPARATOR, version)
+}
+
+// Splits a stored name into the file and version, or None if it is not a version
+pub fn parse_versioned_filename(stored_name: &str) -> Option<(String, u32)> {
+    let (filename, version) = stored_name.rsplit_once(VERSION_SEPARATOR)?;
+    Some((String::from(filename), version.parse().ok()?))
+}
+
+// Name a block of a version of a file is stored under on the datanodes
+pub fn block_name(filename: &str, version: u32, index: u32) -> String {
+    format!(
+        "{}{}{}",
+        versioned_filename(filename, version),
+        BLOCK_SEPARATOR,
+        index
+    )
+}
+
+// Splits a stored name into the file, version and block index, or None if it is not a block
+pub fn parse_block_name(stored_name: &str) -> Option<(String, u32, u32)> {
+    let (versioned, index) = stored_name.rsplit_once(BLOCK_SEPARATOR)?;
+    let (filename, version) = parse_versioned_filename(versioned)?;
+    Some((filename, version, index.parse().ok()?))
+}
+
+// Number of blocks a file of the given size is split into. Even an empty file has one
+pub fn block_count(size: u64) -> u32 {
+    size.div_ceil(BLOCK_SIZE).max(1) as u32
 }
 
-pub fn receive_filename(stream: &mut TcpStream) -> Result<String, String> {
-    let mut filename_size: [u8; 1] = [0];
-    if let Err(err) = stream.read_exact(&mut filename_size) {
-        return Err(err.to_string());
+// Running FNV-1a checksum of a block's contents
+#[derive(Debug, Clone, Copy)]
+pub struct Checksum(u32);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Checksum(0x811c9dc5)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u32;
+            self.0 = self.0.wrapping_mul(0x01000193);
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
     }
-    let mut filename_bytes: Vec<u8> = vec![0; filename_size[0] as usize];
-    if let Err(err) = stream.read_exact(&mut filename_bytes) {
-        return Err(err.to_string());
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Checksum::new()
     }
-    let filename: String = String::from_utf8(filename_bytes).unwrap();
-    Ok(filename)
 }
 
-// Buffered read of the stream into a file. Listens for confirmation after receiving
-// 0-length buffer read.
-pub fn read_file_from_stream(stream: &mut TcpStream, mut file: File) -> Result<(), String> {
+// Writer that keeps a checksum and count of everything written through it
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    checksum: Checksum,
+    written: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            checksum: Checksum::new(),
+            written: 0,
+        }
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.checksum.value()
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// FNV-1a over everything read
+pub fn block_checksum<R: Read>(mut data: R) -> io::Result<u32> {
+    let mut checksum = Checksum::new();
+    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
     loop {
-        let res = read_payload_size(stream);
-        if let Err(err) = res {
-            return Err(err.to_string());
+        let bytes_read = data.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(checksum.value());
         }
-        let read_size = res.unwrap();
+        checksum.update(&buf[..bytes_read]);
+    }
+}
 
-        // Check for end
-        if read_size == 0 {
-            break;
+// Checksum of each block of a local file
+pub fn block_checksums(local_filename: &str) -> Result<Vec<u32>, SdfsError> {
+    let size = match fs::metadata(local_filename) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            return Err(err.into());
         }
+    };
+    let mut file = match File::open(local_filename) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(err.into());
+        }
+    };
+    let mut checksums: Vec<u32> = Vec::new();
+    for _ in 0..block_count(size) {
+        match block_checksum(Read::by_ref(&mut file).take(BLOCK_SIZE)) {
+            Ok(checksum) => checksums.push(checksum),
+            Err(err) => {
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(checksums)
+}
 
-        let mut buf: Vec<u8> = vec![0; read_size as usize];
-        if let Err(err) = stream.read_exact(&mut buf) {
-            return Err(err.to_string());
+// Runs f on every item, on up to TRANSFER_THREADS threads at once. Returns the results in
+// the order of the items
+fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(usize, &T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..TRANSFER_THREADS.min(items.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= items.len() {
+                    break;
+                }
+                let result = f(i, &items[i]);
+                results.lock().unwrap()[i] = Some(result);
+            });
         }
-        file.write(&buf).unwrap();
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+// The read lease granted for a get, then the versions to read, newest first, with their
+// blocks
+pub type ReadPlan = (u64, Vec<(u32, Vec<BlockLocation>)>);
+
+// A block of a version of a file, with its checksum and the datanodes to write it to or read
+// it from, in the order to try them
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockLocation {
+    pub block: String,
+    pub checksum: u32,
+    pub nodes: Vec<u8>,
+}
+
+impl BlockLocation {
+    // The block with its datanodes rotated to start from the given one (modulo their
+    // number), so that reads of different blocks start from different replicas
+    pub fn starting_at(mut self, first: usize) -> Self {
+        if !self.nodes.is_empty() {
+            let len = self.nodes.len();
+            self.nodes.rotate_left(first % len);
+        }
+        self
     }
-    let _ = file.flush();
+}
 
-    let mut confirmation: [u8; 4] = [0; 4];
-    match stream.read(&mut confirmation) {
-        Ok(_) => {
-            if confirmation == CONFIRMATION {
-                return Ok(());
-            } else {
-                return Err("Failed to get file".to_string());
+// Writes each block of a local file, given in order, to its datanodes, several blocks at a
+// time. Returns the datanodes that confirmed each block
+pub fn write_blocks(local_filename: &str, blocks: &[BlockLocation]) -> Vec<Vec<u8>> {
+    parallel_map(blocks, |index, location| {
+        let offset = index as u64 * BLOCK_SIZE;
+        write_chain(location, || {
+            let mut file = File::open(local_filename)?;
+            let size = file
+                .seek(SeekFrom::End(0))?
+                .saturating_sub(offset)
+                .min(BLOCK_SIZE);
+            file.seek(SeekFrom::Start(offset))?;
+            Ok((file.take(BLOCK_SIZE), size))
+        })
+    })
+}
+
+// Writes a block held in memory to its datanodes. Returns the datanodes that confirmed it
+pub fn write_block_data(data: &[u8], location: &BlockLocation) -> Vec<u8> {
+    write_chain(location, || Ok((data, data.len() as u64)))
+}
+
+// Writes a block to the datanodes of its chain, restarting the chain after a datanode that
+// failed before the block was done. open gives the block's contents and size for each try.
+// Returns the datanodes that confirmed the block
+fn write_chain<R: Read>(
+    location: &BlockLocation,
+    mut open: impl FnMut() -> io::Result<(R, u64)>,
+) -> Vec<u8> {
+    let mut chain: &[u8] = &location.nodes;
+    while !chain.is_empty() {
+        let (data, size) = match open() {
+            Ok(opened) => opened,
+            Err(err) => {
+                println!("Failed to read {}: {}", location.block, err);
+                break;
+            }
+        };
+        match write_block(data, size, location, chain) {
+            Ok(stored) => {
+                return stored;
+            }
+            Err((first, err)) => {
+                println!(
+                    "Failed to write {} to datanode {}: {}",
+                    location.block, chain[first], err
+                );
+                chain = &chain[first + 1..];
             }
         }
+    }
+    Vec::new()
+}
+
+// Streams a block, read from data, to the first reachable datanode of the chain, which stores
+// it while forwarding it down the rest. Returns the datanodes that stored it, or the index in
+// the chain of the datanode that failed
+fn write_block<R: Read>(
+    data: R,
+    size: u64,
+    location: &BlockLocation,
+    chain: &[u8],
+) -> Result<Vec<u8>, (usize, SdfsError)> {
+    // None reachable means the last one failed
+    let opened = open_block_write(&location.block, size, location.checksum, chain);
+    let (mut conn, id, first) = match opened {
+        Ok(opened) => opened,
         Err(err) => {
-            return Err(err.to_string());
+            return Err((chain.len() - 1, err));
         }
+    };
+    if let Err(err) = conn.send_data(id, data) {
+        return Err((first, err));
     }
+    read_block_acks(&mut conn, id).map_err(|err| (first, err))
 }
 
-// Buffered write of the contents of file over stream. Each buffered write begins
-// with the payload size. Once finished reading file, sends a CONFIRMATION message.
-pub fn send_file_over_stream(mut stream: &mut TcpStream, mut file: File) -> Result<(), String> {
-    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
-    while let Ok(bytes_read) = file.read(&mut buf) {
-        // Write payload size
-        if let Err(e) = write_payload_size(&mut stream, bytes_read as i32) {
-            return Err(e.to_string());
+// Opens a write of a block, with its size and checksum, to the first reachable datanode of a
+// chain, and asks it to forward the block down the rest of the chain. Returns the connection to
+// send the block over, the id of the write, and the index in the chain of the datanode it goes to
+pub fn open_block_write(
+    block: &str,
+    size: u64,
+    checksum: u32,
+    chain: &[u8],
+) -> Result<(Connection, u64, usize), SdfsError> {
+    for (index, node) in chain.iter().enumerate() {
+        let mut conn = match Connection::open(*node, CD_PORT) {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Failed to reach datanode {}: {}", node, err);
+                continue;
+            }
+        };
+        let mut request = Encoder::new();
+        request
+            .str(block)
+            .u64(size)
+            .u32(checksum)
+            .nodes(&chain[index + 1..]);
+        if let Ok(id) = conn.request(CD_WRITE_FILE, request.into_bytes()) {
+            return Ok((conn, id, index));
         }
+    }
+    Err(SdfsError::Io(format!(
+        "no datanode of {:?} reachable",
+        chain
+    )))
+}
 
-        // Stop if payload size is zero (done reading file)
-        if bytes_read == 0 {
-            break;
+// Reads the acknowledgement of block write id, sent back up the chain once every datanode
+// of it is done: the datanodes that stored the block
+pub fn read_block_acks(conn: &mut Connection, id: u64) -> Result<Vec<u8>, SdfsError> {
+    conn.reply_to(id)?.nodes()
+}
+
+// Reads each block, given in order, into a local file starting at offset, several blocks at
+// a time. Each block is checked against its checksum, and read from the next of its
+// datanodes if it does not match or cannot be read. The file must exist, and is cut at the
+// end of the last block once every block is read. Returns whether each block was read
+pub fn read_blocks(
+    local_filename: &str,
+    offset: u64,
+    blocks: &[BlockLocation],
+) -> Vec<Result<(), SdfsError>> {
+    let results = parallel_map(blocks, |index, location| {
+        read_block(local_filename, offset + index as u64 * BLOCK_SIZE, location)
+    });
+
+    // A corrupt copy of the last block may have been longer than the good one
+    if results.iter().all(|result| result.is_ok()) {
+        let end: u64 = offset
+            + results
+                .iter()
+                .map(|result| result.as_ref().unwrap())
+                .sum::<u64>();
+        let cut = OpenOptions::new()
+            .write(true)
+            .open(local_filename)
+            .and_then(|file| file.set_len(end));
+        if let Err(err) = cut {
+            return results
+                .iter()
+                .map(|_| Err(SdfsError::Io(err.to_string())))
+                .collect();
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.map(|_| ()))
+        .collect()
+}
+
+// Calls renew every LEASE_RENEW_INTERVAL on a background thread until dropped, so a lease
+// is held for as long as the transfer under it takes
+pub struct LeaseRenewer {
+    stop: Option<Sender<()>>,
+    renewer: Option<thread::JoinHandle<()>>,
+}
+
+impl LeaseRenewer {
+    pub fn start(renew: impl Fn() + Send + 'static) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let renewer = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(LEASE_RENEW_INTERVAL) {
+                renew();
+            }
+        });
+        LeaseRenewer {
+            stop: Some(stop),
+            renewer: Some(renewer),
+        }
+    }
+}
+
+impl Drop for LeaseRenewer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(renewer) = self.renewer.take() {
+            let _ = renewer.join();
+        }
+    }
+}
+
+// Runs a client's reads under a lease from the master (1-indexed), renewing it throughout
+// and releasing it once done
+pub fn holding_lease<T>(master: u8, filename: &str, lease: u64, f: impl FnOnce() -> T) -> T {
+    let _held = HeldLease::hold(master, filename, lease);
+    f()
+}
+
+// A client's lease from the master (1-indexed), renewed until dropped, which releases it
+pub struct HeldLease {
+    master: u8,
+    filename: String,
+    lease: u64,
+    renewer: Option<LeaseRenewer>,
+}
+
+impl HeldLease {
+    pub fn hold(master: u8, filename: &str, lease: u64) -> Self {
+        let renewed = String::from(filename);
+        let renewer = LeaseRenewer::start(move || {
+            let _ = send_lease_request(master, &renewed, lease, true);
+        });
+        HeldLease {
+            master,
+            filename: String::from(filename),
+            lease,
+            renewer: Some(renewer),
+        }
+    }
+}
+
+impl Drop for HeldLease {
+    fn drop(&mut self) {
+        // Stop renewing before releasing, so a late renewal cannot follow the release
+        drop(self.renewer.take());
+        let _ = send_lease_request(self.master, &self.filename, self.lease, false);
+    }
+}
+
+// CM Message 9 - Renews a lease on a file at the master (1-indexed), or releases it once
+// the client is done. Errors with Locked if the master no longer held it
+pub fn send_lease_request(
+    master: u8,
+    filename: &str,
+    lease: u64,
+    renew: bool,
+) -> Result<(), SdfsError> {
+    let mut request = Encoder::new();
+    request.str(filename).u64(lease).u8(renew as u8);
+    Connection::open(master, CM_PORT)?.call(CM_LEASE_REQ, request.into_bytes())?;
+    Ok(())
+}
+
+// Reads every block of a version of a file from SDFS into a local file starting at offset,
+// once READ_QUORUM of the datanodes of each block confirm holding it
+pub fn read_version(
+    blocks: &[BlockLocation],
+    local_filename: &str,
+    offset: u64,
+) -> Result<(), SdfsError> {
+    check_read_quorum(blocks)?;
+    for result in read_blocks(local_filename, offset, blocks) {
+        result?;
+    }
+    Ok(())
+}
+
+// Asks the datanodes storing blocks of a version which blocks of the file they hold, until
+// READ_QUORUM of the datanodes of every block hold it. Errors if fewer do
+fn check_read_quorum(blocks: &[BlockLocation]) -> Result<(), SdfsError> {
+    let filename = match blocks
+        .first()
+        .and_then(|location| parse_block_name(&location.block))
+    {
+        Some((filename, _, _)) => filename,
+        None => {
+            return Err(SdfsError::BadRequest("no blocks to read".to_string()));
         }
-        // Write payload itself
-        if let Err(e) = stream.write(&buf[0..bytes_read]) {
-            return Err(e.to_string());
+    };
+    check_block_quorums(blocks, |node| list_blocks(node, &filename))
+}
+
+// Checks that every block is held by at least READ_QUORUM of its own datanodes, given the
+// blocks each datanode lists (asked once per datanode, and only as needed). A datanode that
+// cannot list them holds none. Errors with NoQuorum for the first block short of it
+pub fn check_block_quorums(
+    blocks: &[BlockLocation],
+    mut list: impl FnMut(u8) -> Result<Vec<String>, SdfsError>,
+) -> Result<(), SdfsError> {
+    let mut listed: HashMap<u8, HashSet<String>> = HashMap::new();
+    for location in blocks {
+        let mut holding: u8 = 0;
+        for node in location.nodes.iter().filter(|node| **node != 0) {
+            let held = listed.entry(*node).or_insert_with(|| match list(*node) {
+                Ok(held) => held.into_iter().collect(),
+                Err(err) => {
+                    println!("Failed to ask datanode {} for blocks: {}", node, err);
+                    HashSet::new()
+                }
+            });
+            if held.contains(&location.block) {
+                holding += 1;
+            }
+            if holding >= READ_QUORUM {
+                break;
+            }
+        }
+        if holding < READ_QUORUM {
+            return Err(SdfsError::NoQuorum(format!(
+                "{} of {} datanodes needed hold {}",
+                holding, READ_QUORUM, location.block
+            )));
+        }
+    }
+    Ok(())
+}
+
+// CD Message 8 - Blocks of a file a datanode stores, of every version
+fn list_blocks(node: u8, filename: &str) -> Result<Vec<String>, SdfsError> {
+    let mut request = Encoder::new();
+    request.str(filename);
+    let mut reply = Connection::open(node, CD_PORT)?.call(CD_LIST_BLOCKS, request.into_bytes())?;
+    let count = reply.count(8)?;
+    let mut blocks: Vec<String> = Vec::with_capacity(count);
+    for _ in 0..count {
+        blocks.push(reply.str()?);
+    }
+    Ok(blocks)
+}
+
+// Reads a block from the first of its datanodes that sends it intact. Returns its length
+fn read_block(
+    local_filename: &str,
+    offset: u64,
+    location: &BlockLocation,
+) -> Result<u64, SdfsError> {
+    let mut errors: Vec<(u8, SdfsError)> = Vec::new();
+    for node in location.nodes.iter().filter(|node| **node != 0) {
+        match read_block_from(local_filename, offset, location, *node) {
+            Ok(length) => {
+                return Ok(length);
+            }
+            Err(err) => {
+                println!(
+                    "Failed to read {} from datanode {}: {}",
+                    location.block, node, err
+                );
+                errors.push((*node, err));
+            }
         }
     }
+    Err(block_unread(location, errors))
+}
 
-    // Send confirmation code to client
-    if let Err(e) = stream.write(&CONFIRMATION) {
-        return Err(e.to_string());
+// Why a block could not be read from any of its datanodes, given what each failed with
+fn block_unread(location: &BlockLocation, errors: Vec<(u8, SdfsError)>) -> SdfsError {
+    if errors.is_empty() {
+        return SdfsError::NotFound(location.block.clone());
+    }
+    if errors
+        .iter()
+        .all(|(_, err)| matches!(err, SdfsError::ChecksumMismatch(_)))
+    {
+        return SdfsError::ChecksumMismatch(location.block.clone());
     }
+    let errors: Vec<String> = errors
+        .iter()
+        .map(|(node, err)| format!("datanode {}: {}", node, err))
+        .collect();
+    SdfsError::Io(format!(
+        "could not read {} ({})",
+        location.block,
+        errors.join(", ")
+    ))
+}
 
-    return Ok(());
+fn read_block_from(
+    local_filename: &str,
+    offset: u64,
+    location: &BlockLocation,
+    node: u8,
+) -> Result<u64, SdfsError> {
+    let mut file = match OpenOptions::new().write(true).open(local_filename) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(err.into());
+        }
+    };
+    if let Err(err) = file.seek(SeekFrom::Start(offset)) {
+        return Err(err.into());
+    }
+    read_block_into(location, node, file)
+}
+
+// Reads a block from a datanode into out, checking it against its checksum. Returns its
+// length
+fn read_block_into<W: Write>(location: &BlockLocation, node: u8, out: W) -> Result<u64, SdfsError> {
+    let mut conn = Connection::open(node, CD_PORT)?;
+    let mut request = Encoder::new();
+    request.str(&location.block);
+    let id = conn.request(CD_READ_FILE, request.into_bytes())?;
+    let mut writer = ChecksumWriter::new(out);
+    conn.receive_data(id, &mut writer)?;
+    if writer.checksum() != location.checksum {
+        return Err(SdfsError::ChecksumMismatch(location.block.clone()));
+    }
+    Ok(writer.written())
 }
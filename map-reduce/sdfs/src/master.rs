@@ -1,60 +1,641 @@
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use sdfs::*;
 
 fn main() {
-    let metadata = Metadata::from(METADATA_PATH);
+    let mut metadata = Metadata::recover(METADATA_PATH, METADATA_LOG_PATH);
+    println!(
+        "Recovered metadata up to change {}, {} files.",
+        metadata.log_seq(),
+        metadata.namespace.files_versions.len()
+    );
+    let (log_tx, log_rx) = mpsc::channel::<(u64, LogRecord)>();
+    if let Some(log) = &mut metadata.log {
+        log.set_follower(log_tx);
+    }
     let shared_meta = Arc::new(Mutex::new(metadata));
 
-    // Listen for incoming connections
-    let shared_meta_client = shared_meta.clone();
-    let client_listener = thread::spawn(move || client_listen(shared_meta_client));
+    // Join the gossip group to learn which datanodes are alive, and which server leads
+    let membership = Arc::new(start_membership(MASTER_GOSSIP_PORT, ROLE_MASTER));
+
+    // Whether this server is the elected master; standbys turn requests away
+    let leading = Arc::new(AtomicBool::new(false));
+
+    // React to datanode failures by restoring the lost replicas
+    let shared_meta_watcher = shared_meta.clone();
+    let membership_watcher = membership.clone();
+    let leading_watcher = leading.clone();
+    let watcher = thread::spawn(move || {
+        watch_membership(shared_meta_watcher, membership_watcher, leading_watcher)
+    });
+
+    // Keep the standbys' metadata up to date while leading, and follow the master's otherwise
+    let shared_meta_shipper = shared_meta.clone();
+    let membership_shipper = membership.clone();
+    let leading_shipper = leading.clone();
+    thread::spawn(move || {
+        ship_log(
+            log_rx,
+            shared_meta_shipper,
+            membership_shipper,
+            leading_shipper,
+        )
+    });
+    let shared_meta_standby = shared_meta.clone();
+    let leading_standby = leading.clone();
+    thread::spawn(move || standby_listen(shared_meta_standby, leading_standby));
+
+    // Take over as master whenever elected
+    let shared_meta_election = shared_meta.clone();
+    let membership_election = membership.clone();
+    let leading_election = leading.clone();
+    let election =
+        thread::spawn(move || elect(shared_meta_election, membership_election, leading_election));
+
+    election.join().unwrap();
+    watcher.join().unwrap();
+}
+
+// Follows master elections. On becoming master, adds what the datanodes' block reports
+// show to the metadata replicated so far and starts serving; on losing to a lower
+// numbered server, stands by
+fn elect(shared_meta: Arc<Mutex<Metadata>>, membership: Arc<Membership>, leading: Arc<AtomicBool>) {
+    let self_vm = get_vm_id();
+    let mut listening = false;
+
+    thread::sleep(ELECTION_DELAY);
+    loop {
+        let leader = get_leader(&membership);
+        if leader == self_vm && !leading.load(Ordering::SeqCst) {
+            println!("Elected master, merging block reports into metadata...");
+            let reports = collect_block_reports(&get_membership(&membership));
+            {
+                let mut meta = shared_meta.lock().unwrap();
+                meta.merge_block_reports(reports);
+                println!(
+                    "Metadata rebuilt, {} files.",
+                    meta.namespace.files_versions.len()
+                );
+            }
+            leading.store(true, Ordering::SeqCst);
+
+            // Listen for incoming connections
+            if !listening {
+                let shared_meta_client = shared_meta.clone();
+                let membership_client = membership.clone();
+                let leading_client = leading.clone();
+                thread::spawn(move || {
+                    client_listen(shared_meta_client, membership_client, leading_client)
+                });
+
+                let shared_meta_datanode = shared_meta.clone();
+                let leading_datanode = leading.clone();
+                thread::spawn(move || datanode_listen(shared_meta_datanode, leading_datanode));
+                listening = true;
+            }
+        } else if leader != self_vm && leading.load(Ordering::SeqCst) {
+            println!("Server {} is now master, standing by.", leader);
+            leading.store(false, Ordering::SeqCst);
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// Sends each logged change to every standby, with a snapshot to standbys that missed some
+// Unreachable standbys are skipped, and catch up through a snapshot on the next change
+fn ship_log(
+    log_rx: Receiver<(u64, LogRecord)>,
+    shared_meta: Arc<Mutex<Metadata>>,
+    membership: Arc<Membership>,
+    leading: Arc<AtomicBool>,
+) {
+    let self_vm = get_vm_id();
+    for (seq, record) in log_rx {
+        if !leading.load(Ordering::SeqCst) {
+            continue;
+        }
+        for standby in get_standbys(&membership, self_vm) {
+            match send_log_record(standby, seq, &record) {
+                Ok(true) => {}
+                Ok(false) => {
+                    let (snapshot_seq, namespace) = {
+                        let meta = shared_meta.lock().unwrap();
+                        (meta.log_seq(), meta.namespace.clone())
+                    };
+                    if let Err(err) = send_snapshot(standby, snapshot_seq, &namespace) {
+                        println!("Failed to send snapshot to server {}: {}", standby, err);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
 
-    let shared_meta_datanode = shared_meta.clone();
-    let datanode_listener = thread::spawn(move || datanode_listen(shared_meta_datanode));
+// MM Message 1 - Ship one change, as its sequence number then the encoded record. Returns
+// false if the standby needs a snapshot
+fn send_log_record(standby: u8, seq: u64, record: &LogRecord) -> Result<bool, SdfsError> {
+    let mut request = Encoder::new();
+    request.u64(seq).bytes(&record.encode());
+    let mut reply =
+        Connection::open(standby, MM_PORT)?.call(MM_LOG_RECORD, request.into_bytes())?;
+    Ok(reply.u8()? == 1)
+}
 
-    client_listener.join().unwrap();
-    datanode_listener.join().unwrap();
+// MM Message 2 - Ship the whole namespace, as of change seq
+// Sent as the blocks (name, checksum, then the datanodes), the versions (filename, version,
+// size, mtime), then the directories made, each as a count followed by the entries
+fn send_snapshot(standby: u8, seq: u64, namespace: &Namespace) -> Result<(), SdfsError> {
+    let mut request = Encoder::new();
+    request.u64(seq).count(namespace.blocks.len());
+    for (name, block) in namespace.blocks.iter() {
+        request.str(name).u32(block.checksum).nodes(&block.nodes);
+    }
+    let versions: usize = namespace.files_versions.values().map(|v| v.len()).sum();
+    request.count(versions);
+    for (filename, file_versions) in namespace.files_versions.iter() {
+        let stats = namespace.version_stats.get(filename);
+        for version in file_versions {
+            let stat = stats.and_then(|stats| stats.get(version)).copied();
+            let stat = stat.unwrap_or_default();
+            request
+                .str(filename)
+                .u32(*version)
+                .u64(stat.size)
+                .u64(stat.mtime);
+        }
+    }
+    request.count(namespace.dirs.len());
+    for dir in namespace.dirs.iter() {
+        request.str(dir);
+    }
+    Connection::open(standby, MM_PORT)?.call(MM_SNAPSHOT, request.into_bytes())?;
+    Ok(())
+}
+
+// Listener for changes shipped by the master, while standing by
+fn standby_listen(shared_meta: Arc<Mutex<Metadata>>, leading: Arc<AtomicBool>) {
+    let listen_addr = String::from("0:") + MM_PORT;
+    let listener = TcpListener::bind(listen_addr).unwrap();
+
+    for conn_res in listener.incoming() {
+        if let Ok(stream) = conn_res {
+            // Only the master's own log counts while leading
+            if leading.load(Ordering::SeqCst) {
+                continue;
+            }
+            let (conn, request) = match accept_request(stream) {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("Rejected connection: {}", err);
+                    continue;
+                }
+            };
+
+            match request.kind {
+                MM_LOG_RECORD => serve(conn, request, |request| {
+                    let (seq, record) = receive_log_record(request)?;
+                    let mut meta = shared_meta.lock().unwrap();
+                    Ok(vec![meta.apply_replicated(seq, record) as u8])
+                }),
+                MM_SNAPSHOT => serve(conn, request, |request| {
+                    let (seq, namespace) = receive_snapshot(request)?;
+                    let mut meta = shared_meta.lock().unwrap();
+                    meta.install_snapshot(seq, namespace);
+                    println!("Installed metadata snapshot at change {}.", seq);
+                    Ok(Vec::new())
+                }),
+                kind => reject(conn, request.id, kind),
+            }
+        }
+    }
+}
+
+fn receive_log_record(request: &mut Decoder) -> Result<(u64, LogRecord), SdfsError> {
+    let seq = request.u64()?;
+    match LogRecord::decode(&request.bytes()?) {
+        Some(record) => Ok((seq, record)),
+        None => Err(SdfsError::BadRequest(format!("bad log record {}", seq))),
+    }
+}
+
+fn receive_snapshot(request: &mut Decoder) -> Result<(u64, Namespace), SdfsError> {
+    let seq = request.u64()?;
+
+    let mut namespace = Namespace::default();
+    let blocks = request.count(20)?;
+    for _ in 0..blocks {
+        let name = request.str()?;
+        let block = Block {
+            checksum: request.u32()?,
+            nodes: request.nodes()?,
+        };
+        namespace.blocks.insert(name, block);
+    }
+    let versions = request.count(28)?;
+    for _ in 0..versions {
+        let filename = request.str()?;
+        let version = request.u32()?;
+        let stat = VersionStat {
+            size: request.u64()?,
+            mtime: request.u64()?,
+        };
+        let file_versions = namespace
+            .files_versions
+            .entry(filename.clone())
+            .or_default();
+        file_versions.push(version);
+        file_versions.sort_unstable();
+        namespace
+            .version_stats
+            .entry(filename)
+            .or_default()
+            .insert(version, stat);
+    }
+    let dirs = request.count(8)?;
+    for _ in 0..dirs {
+        namespace.dirs.insert(request.str()?);
+    }
+    Ok((seq, namespace))
+}
+
+// Asks every datanode that is not failed for the files it stores
+fn collect_block_reports(status: &[u8]) -> Vec<(u8, Vec<String>)> {
+    let mut reports: Vec<(u8, Vec<String>)> = Vec::new();
+    for (i, node_status) in status.iter().enumerate() {
+        if *node_status == NODE_DOWN {
+            continue;
+        }
+        let node = (i + 1) as u8;
+        match request_block_report(node) {
+            Ok(filenames) => reports.push((node, filenames)),
+            Err(err) => println!("No block report from datanode {}: {}", node, err),
+        }
+    }
+    reports
+}
+
+// MD - Request for the files a datanode stores
+fn request_block_report(node: u8) -> Result<Vec<String>, SdfsError> {
+    let mut reply = Connection::open(node, CD_PORT)?.call(CD_BLOCK_REPORT, Vec::new())?;
+    let count = reply.count(8)?;
+    let mut filenames: Vec<String> = Vec::with_capacity(count);
+    for _ in 0..count {
+        filenames.push(reply.str()?);
+    }
+    Ok(filenames)
+}
+
+// Reports datanodes joining, becoming suspected and failing, as seen by gossip
+// The master drops replicas on failed datanodes and copies them elsewhere
+fn watch_membership(
+    shared_meta: Arc<Mutex<Metadata>>,
+    membership: Arc<Membership>,
+    leading: Arc<AtomicBool>,
+) {
+    let mut watch = DatanodeWatch::default();
+    loop {
+        let status = get_membership(&membership);
+        for (i, (curr, last)) in status.iter().zip(watch.last_status()).enumerate() {
+            if curr == last {
+                continue;
+            }
+            match *curr {
+                NODE_ALIVE => println!("Datanode {} is alive.", i + 1),
+                NODE_SUSPECTED => println!("Datanode {} is suspected.", i + 1),
+                _ => println!("Datanode {} has failed.", i + 1),
+            }
+        }
+
+        let is_leading = leading.load(Ordering::SeqCst);
+        {
+            let mut meta = shared_meta.lock().unwrap();
+            for node in watch.update(&meta, &status, is_leading) {
+                meta.remove_datanode(node);
+            }
+        }
+
+        // Only the master grants leases and orchestrates copies
+        if is_leading {
+            expire_leases(&shared_meta);
+            start_replications(&shared_meta, &status);
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// Renews leases the master holds itself while it works under them, until dropped
+fn renew_leases(shared_meta: &Arc<Mutex<Metadata>>, leases: Vec<(String, u64)>) -> LeaseRenewer {
+    let shared_meta = shared_meta.clone();
+    LeaseRenewer::start(move || {
+        let mut meta = shared_meta.lock().unwrap();
+        for (filename, lease) in leases.iter() {
+            meta.renew_lease(filename, *lease);
+        }
+    })
+}
+
+// Releases leases held by clients that stopped renewing them (e.g. crashed mid-transfer),
+// so requests waiting on their files can go ahead
+fn expire_leases(shared_meta: &Arc<Mutex<Metadata>>) {
+    let expired = shared_meta.lock().unwrap().expire_leases(Instant::now());
+    for (filename, lease) in expired {
+        println!(
+            "{:?} lease {} on {} expired, datanodes {:?} did not report.",
+            lease.kind, lease.id, filename, lease.nodes
+        );
+    }
+}
+
+// Starts copies for under-replicated blocks of files that are not being written
+// Blocks of busy files, or already being copied, are retried on a later round
+fn start_replications(shared_meta: &Arc<Mutex<Metadata>>, status: &[u8]) {
+    let mut copies: Vec<Replication> = Vec::new();
+    {
+        let mut meta = shared_meta.lock().unwrap();
+        meta.expire_replications(REPLICATION_TIMEOUT);
+
+        for (block, replicas) in meta.get_under_replicated() {
+            if meta.get_replications_for_block(&block) > 0 {
+                continue;
+            }
+            let filename = match parse_block_name(&block) {
+                Some((filename, _, _)) => filename,
+                None => continue,
+            };
+            if !meta.files_sync.contains_key(&filename) {
+                continue;
+            }
+
+            // Copy from a replica that is alive, if any are left
+            let source = match meta.namespace.blocks[&block]
+                .nodes
+                .iter()
+                .find(|node| status[(**node - 1) as usize] == NODE_ALIVE)
+            {
+                Some(source) => *source,
+                None => continue,
+            };
+            let checksum = meta.namespace.blocks[&block].checksum;
+            let targets = meta.get_replication_targets(
+                &block,
+                REPLICATION_FACTOR as usize - replicas,
+                status.to_vec(),
+            );
+
+            // Copies are readers, so they can run beside other readers, but never keep a
+            // client waiting
+            for target in targets {
+                let lease = match meta
+                    .files_sync
+                    .get_mut(&filename)
+                    .unwrap()
+                    .try_read(vec![source])
+                {
+                    Some(lease) => lease,
+                    None => break,
+                };
+                let copy = Replication {
+                    filename: filename.clone(),
+                    block: block.clone(),
+                    checksum,
+                    source,
+                    target,
+                    started: Instant::now(),
+                    lease,
+                };
+                meta.replications.push(copy.clone());
+                copies.push(copy);
+            }
+        }
+    }
+
+    for copy in copies {
+        println!(
+            "Copying {} from datanode {} to datanode {}.",
+            copy.block, copy.source, copy.target
+        );
+        let shared_meta_copy = shared_meta.clone();
+        thread::spawn(move || replicate(copy, shared_meta_copy));
+    }
+}
+
+// Asks the source datanode to copy the block to the target
+// On success the target reports DM_FILE_RECEIVED, which completes the copy
+fn replicate(copy: Replication, shared_meta: Arc<Mutex<Metadata>>) {
+    if let Err(err) = send_replicate_request(&copy) {
+        println!(
+            "Failed to copy {} to datanode {}: {}",
+            copy.block, copy.target, err
+        );
+        shared_meta
+            .lock()
+            .unwrap()
+            .cancel_replication(&copy.block, copy.target);
+    }
+}
+
+// MD - Request to copy a block between datanodes
+fn send_replicate_request(copy: &Replication) -> Result<(), SdfsError> {
+    let mut request = Encoder::new();
+    request
+        .str(&copy.block)
+        .node(copy.target)
+        .u32(copy.checksum);
+
+    // Datanode replies once the target confirms the block
+    Connection::open(copy.source, CD_PORT)?.call(CD_REPLICATE, request.into_bytes())?;
+    Ok(())
 }
 
 // <----------- Stream Listener Functions ----------->
 // Listener for client connections
-fn client_listen(shared_meta: Arc<Mutex<Metadata>>) {
+fn client_listen(
+    shared_meta: Arc<Mutex<Metadata>>,
+    membership: Arc<Membership>,
+    leading: Arc<AtomicBool>,
+) {
     let listen_addr = String::from("0:") + CM_PORT;
     let listener = TcpListener::bind(listen_addr).unwrap();
 
     for conn_res in listener.incoming() {
-        if let Ok(mut stream) = conn_res {
-            let mut opcode: [u8; 1] = [0];
-            if stream.read_exact(&mut opcode).is_err() {
-                continue;
+        if let Ok(stream) = conn_res {
+            let (mut conn, request) = match accept_request(stream) {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("Rejected connection: {}", err);
+                    continue;
+                }
             };
 
-            match opcode[0] {
+            // Turn requests away while standing by, so clients look for the new master
+            if !leading.load(Ordering::SeqCst) {
+                let _ = conn.reply(request.id, Err(SdfsError::NotMaster));
+                continue;
+            }
+
+            match request.kind {
                 // Request to put file
                 CM_PUT_REQ => {
                     let shared_meta_handle = shared_meta.clone();
+                    let membership_handle = membership.clone();
 
-                    thread::spawn(move || handle_put_file(stream, shared_meta_handle));
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_put_file(request, &shared_meta_handle, &membership_handle)
+                        })
+                    });
+                }
+                // Request to commit a put, once its blocks are written
+                CM_COMMIT_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_commit_file(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request to place the next block of a put whose size was not given
+                CM_ADD_BLOCK_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+                    let membership_handle = membership.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_add_block(request, &shared_meta_handle, &membership_handle)
+                        })
+                    });
+                }
+                // Request to renew or release a lease
+                CM_LEASE_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_lease(request, &shared_meta_handle)
+                        })
+                    });
                 }
                 // Request to get file
                 CM_GET_REQ => {
                     let shared_meta_handle = shared_meta.clone();
 
-                    thread::spawn(move || handle_get_file(stream, shared_meta_handle));
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_get_file(request, &shared_meta_handle, false)
+                        })
+                    });
+                }
+                // Request to get the latest versions of a file
+                CM_GET_VERSIONS_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_get_file(request, &shared_meta_handle, true)
+                        })
+                    });
+                }
+                // Request to delete file
+                CM_DELETE_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_delete_file(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request to list every file
+                CM_LIST_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_list_files(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request to make a directory
+                CM_MKDIR_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_mkdir(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request to list a directory
+                CM_LIST_DIR_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_list_dir(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request to move a file or directory
+                CM_RENAME_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_rename(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request for what is known of a file
+                CM_STAT_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_stat_file(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request to have several nodes fetch a file
+                CM_MULTIREAD_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_multiread(request, &shared_meta_handle)
+                        })
+                    });
                 }
                 // Request to list where file is stored
                 CM_LS_REQ => {
                     let shared_meta_handle = shared_meta.clone();
 
-                    thread::spawn(move || handle_ls_file(stream, shared_meta_handle));
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_ls_file(request, &shared_meta_handle)
+                        })
+                    });
+                }
+                // Request for progress of re-replication
+                CM_REPLICATION_REQ => {
+                    let shared_meta_handle = shared_meta.clone();
+
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            handle_replication_status(request, &shared_meta_handle)
+                        })
+                    });
                 }
 
-                _ => {}
+                kind => reject(conn, request.id, kind),
             }
         } else {
             continue;
@@ -63,28 +644,51 @@ fn client_listen(shared_meta: Arc<Mutex<Metadata>>) {
 }
 
 // Listener for datanode connections
-fn datanode_listen(shared_meta: Arc<Mutex<Metadata>>) {
+fn datanode_listen(shared_meta: Arc<Mutex<Metadata>>, leading: Arc<AtomicBool>) {
     let listen_addr = String::from("0:") + DM_PORT;
     let listener = TcpListener::bind(listen_addr).unwrap();
 
     for conn_res in listener.incoming() {
-        if let Ok(mut stream) = conn_res {
-            let mut opcode: [u8; 1] = [0];
-            if stream.read_exact(&mut opcode).is_err() {
-                continue;
+        if let Ok(stream) = conn_res {
+            let (mut conn, request) = match accept_request(stream) {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("Rejected connection: {}", err);
+                    continue;
+                }
             };
+            if !leading.load(Ordering::SeqCst) {
+                let _ = conn.reply(request.id, Err(SdfsError::NotMaster));
+                continue;
+            }
 
-            // Idiom is to pass the stream off to another thread that will handle it
-            match opcode[0] {
+            // Idiom is to pass the connection off to another thread that will handle it
+            match request.kind {
                 DM_FILE_RECEIVED => {
                     let shared_meta_clone = shared_meta.clone();
-                    thread::spawn(move || datanode_file_received(stream, shared_meta_clone));
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            datanode_file_received(request, &shared_meta_clone)
+                        })
+                    });
                 }
                 DM_FILE_SENT => {
                     let shared_meta_clone = shared_meta.clone();
-                    thread::spawn(move || datanode_file_sent(stream, shared_meta_clone));
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            datanode_file_sent(request, &shared_meta_clone)
+                        })
+                    });
                 }
-                _ => {}
+                DM_BLOCK_REPORT => {
+                    let shared_meta_clone = shared_meta.clone();
+                    thread::spawn(move || {
+                        serve(conn, request, |request| {
+                            datanode_block_report(request, &shared_meta_clone)
+                        })
+                    });
+                }
+                kind => reject(conn, request.id, kind),
             }
         } else {
             continue;
@@ -92,206 +696,804 @@ fn datanode_listen(shared_meta: Arc<Mutex<Metadata>>) {
     }
 }
 
-fn datanode_file_received(mut stream: TcpStream, shared_meta: Arc<Mutex<Metadata>>) {
-    let filename = receive_filename(&mut stream).unwrap();
+// Replies to a request of a type this listener does not take
+fn reject(mut conn: Connection, id: u64, kind: u8) {
+    let unknown = SdfsError::BadRequest(format!("unknown message {}", kind));
+    let _ = conn.reply(id, Err(unknown));
+}
 
-    // Receive the node number
-    let mut node_num: [u8; 1] = [0];
-    stream.read_exact(&mut node_num).unwrap();
+// DM Message 1 - A datanode stored a block, as the block name then the node number
+fn datanode_file_received(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let block = request.str()?;
+    let node = request.node()?;
 
-    println!("Datanode {} received {}.", node_num[0], filename);
+    println!("Datanode {} received {}.", node, block);
+    let filename = match parse_block_name(&block) {
+        Some((filename, _, _)) => filename,
+        None => {
+            return Err(SdfsError::BadRequest(format!("{} is not a block", block)));
+        }
+    };
 
     // Update Metadata
     {
         let mut meta = shared_meta.lock().unwrap();
-        meta.files_sync
-            .get_mut(&filename)
-            .unwrap()
-            .write_complete(node_num[0]);
-        meta.add_file(filename.clone(), node_num[0]);
-        println!(
-            "FileSync: {:?}",
-            meta.files_sync.get_mut(&filename).unwrap()
-        );
+        if meta.finish_replication(&block, node) {
+            println!("Copy of {} to datanode {} complete.", block, node);
+        } else {
+            if let Some(file_sync) = meta.files_sync.get_mut(&filename) {
+                file_sync.write_complete(node);
+            }
+            meta.add_replica(block.clone(), node);
+        }
+        println!("FileSync: {:?}", meta.files_sync.get(&filename));
     }
+    Ok(Vec::new())
 }
 
-fn datanode_file_sent(mut stream: TcpStream, shared_meta: Arc<Mutex<Metadata>>) {
-    let filename = receive_filename(&mut stream).unwrap();
-
-    // Receive the node number
-    let mut node_num: [u8; 1] = [0];
-    stream.read_exact(&mut node_num).unwrap();
+// DM Message 2 - A datanode sent a block to a reader, as the block name then the node number
+fn datanode_file_sent(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let block = request.str()?;
+    let node = request.node()?;
 
-    println!("Datanode {} received {}.", node_num[0], filename);
+    println!("Datanode {} sent {}.", node, block);
+    let filename = match parse_block_name(&block) {
+        Some((filename, _, _)) => filename,
+        None => {
+            return Err(SdfsError::BadRequest(format!("{} is not a block", block)));
+        }
+    };
 
     // Update Metadata
     {
         let mut meta = shared_meta.lock().unwrap();
-        meta.files_sync
-            .get_mut(&filename)
-            .unwrap()
-            .read_complete(node_num[0]);
-        meta.add_file(filename.clone(), node_num[0]);
+        if let Some(file_sync) = meta.files_sync.get_mut(&filename) {
+            file_sync.read_complete(node);
+        }
+        meta.add_replica(block.clone(), node);
+        println!("FileSync: {:?}", meta.files_sync.get(&filename));
+    }
+    Ok(Vec::new())
+}
+
+// DM Message 3 - Every block a datanode stores, as the node number, the milliseconds since it
+// started listing them, the number of blocks, then (block name, size, checksum) for each. Sent
+// when the datanode starts and every BLOCK_REPORT_INTERVAL
+// Replicas it lost are dropped and copied again by start_replications. Replies with the
+// number of blocks to delete, then their names: corrupt copies and orphaned blocks
+fn datanode_block_report(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let node = request.node()?;
+    let scan_age = Duration::from_millis(request.u64()?);
+    let no_blocks = request.count(20)?;
+    let mut report: Vec<StoredBlock> = Vec::with_capacity(no_blocks);
+    for _ in 0..no_blocks {
+        report.push(StoredBlock {
+            block: request.str()?,
+            size: request.u64()?,
+            checksum: request.u32()?,
+        });
+    }
+
+    let reconciled = shared_meta.lock().unwrap().reconcile_block_report(
+        node,
+        report,
+        scan_age + BLOCK_REPORT_SLACK,
+    );
+    for block in reconciled.missing.iter() {
+        println!(
+            "Datanode {} lost its copy of {}, copying it again.",
+            node, block
+        );
+    }
+    for block in reconciled.found.iter() {
+        println!("Datanode {} holds an unrecorded copy of {}.", node, block);
+    }
+    for block in reconciled.corrupt.iter() {
         println!(
-            "FileSync: {:?}",
-            meta.files_sync.get_mut(&filename).unwrap()
+            "Datanode {} holds a corrupt copy of {}, deleting it.",
+            node, block
         );
     }
+    for block in reconciled.orphaned.iter() {
+        println!("Datanode {} holds orphaned {}, deleting it.", node, block);
+    }
+
+    let mut reply = Encoder::new();
+    reply.count(reconciled.corrupt.len() + reconciled.orphaned.len());
+    for block in reconciled.corrupt.iter().chain(reconciled.orphaned.iter()) {
+        reply.str(block);
+    }
+    Ok(reply.into_bytes())
 }
 
-// <----------- Stream Listener Helper Functions ----------->
-// CM Message 1 - Request to put file
-fn handle_put_file(mut stream: TcpStream, shared_meta: Arc<Mutex<Metadata>>) {
-    let mut client_id: [u8; 1] = [0];
-    stream.read_exact(&mut client_id).unwrap();
-    let client_id = client_id[0];
+// Queues a read or write of a file and waits until it is let in. Returns its lease. Errors
+// with NotFound if the file is not in SDFS (or was dropped while waiting)
+fn wait_for_lease(
+    shared_meta: &Arc<Mutex<Metadata>>,
+    filename: &str,
+    kind: RequestType,
+) -> Result<u64, SdfsError> {
+    let rx = {
+        let mut meta = shared_meta.lock().unwrap();
+        let file_sync = match meta.files_sync.get_mut(filename) {
+            Some(file_sync) => file_sync,
+            None => {
+                return Err(SdfsError::NotFound(String::from(filename)));
+            }
+        };
+        let rx = file_sync.request(kind);
+        println!("FileSync: {:?}", file_sync);
+        rx
+    };
+    rx.recv()
+        .map_err(|_| SdfsError::NotFound(String::from(filename)))
+}
 
-    let mut no_datanodes: [u8; 1] = [0];
-    stream.read_exact(&mut no_datanodes).unwrap();
-    let no_datanodes = no_datanodes[0];
+// CM Message 1 - Request to put file, with its size, the number of blocks and the checksum
+// of each. The directories above it are made as needed
+// Replies with the new version, the write lease, the number of blocks, then the datanodes to
+// write each block to. Fails with NoCapacity if fewer than WRITE_QUORUM datanodes are alive
+// to take a block
+fn handle_put_file(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+    membership: &Arc<Membership>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let no_datanodes = request.u32()?;
+    let filename = request.path()?;
+    let size = request.u64()?;
 
-    let filename = receive_filename(&mut stream).unwrap();
+    let no_blocks = request.count(4)?;
+    let mut checksums: Vec<u32> = Vec::with_capacity(no_blocks);
+    for _ in 0..no_blocks {
+        checksums.push(request.u32()?);
+    }
 
     println!(
-        "received request from {} to put file {} into {} datanodes",
-        client_id, filename, no_datanodes
+        "received request from {} to put file {} of {} blocks into {} datanodes",
+        client_id, filename, no_blocks, no_datanodes
     );
 
-    // Do checks
-    let mut rx_opt: Option<Receiver<u8>> = None;
-    let mut file_exist: bool = false;
     {
         let mut meta = shared_meta.lock().unwrap();
+        meta.check_new_path(&filename)?;
+        if meta.namespace.is_dir(&filename) {
+            return Err(SdfsError::BadRequest(format!(
+                "{} is a directory",
+                filename
+            )));
+        }
+        meta.files_sync.entry(filename.clone()).or_default();
+    }
+    let lease = wait_for_lease(shared_meta, &filename, RequestType::Write)?;
 
-        match meta.files_sync.get_mut(&filename) {
-            // File does exist
-            Some(file_sync) => {
-                file_exist = true;
-                if file_sync.state != FileState::Free {
-                    let (tx, rx) = mpsc::channel::<u8>();
-                    file_sync.queue.push_back((RequestType::Write, tx));
-                    rx_opt = Some(rx);
-                }
-            }
-            // File does not exist
-            None => {
-                meta.files_storage.insert(filename.clone(), Vec::new());
-                meta.files_sync.insert(filename.clone(), FileSync::new());
-            }
+    // Each block is placed on its own n least loaded datanodes
+    let placement: Vec<Vec<u8>>;
+    let version: u32;
+    {
+        let mut meta = shared_meta.lock().unwrap();
+
+        placement = meta.place_blocks(
+            checksums.len(),
+            no_datanodes as usize,
+            get_membership(membership),
+        );
+        // The put could never be committed, let the next waiter in
+        if placement
+            .iter()
+            .any(|nodes| nodes.len() < WRITE_QUORUM as usize)
+        {
+            meta.release_lease(&filename, lease);
+            return Err(SdfsError::NoCapacity);
         }
+        version = meta.start_write(&filename, size, checksums);
+        let file_sync = meta.files_sync.get_mut(&filename).unwrap();
+        file_sync.assign(lease, placement.concat());
+        println!("FileSync: {:?}", file_sync);
+    }
+
+    let mut reply = Encoder::new();
+    reply.u32(version).u64(lease).count(placement.len());
+    for nodes in placement.iter() {
+        reply.nodes(nodes);
+    }
+    Ok(reply.into_bytes())
+}
+
+// CM Message 8 - Request to commit a put, with its version, its write lease, the number of
+// blocks, then the datanodes that confirmed each block
+// The lease is released either way, as the client is done writing
+// Replies once the version is recorded, else fails with why it was not (e.g. NoQuorum)
+fn handle_commit_file(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let filename = request.str()?;
+    let version = request.u32()?;
+    let lease = request.u64()?;
+    let no_blocks = request.count(8)?;
+    let mut stored: Vec<Vec<u8>> = Vec::with_capacity(no_blocks);
+    for _ in 0..no_blocks {
+        stored.push(request.nodes()?);
+    }
+
+    println!(
+        "received request from {} to commit file {} version {}",
+        client_id, filename, version
+    );
+
+    {
+        let mut meta = shared_meta.lock().unwrap();
+        meta.release_lease(&filename, lease);
+        meta.commit_write(&filename, version, stored)?;
+    }
+    println!("Committed {} version {}.", filename, version);
+    Ok(Vec::new())
+}
+
+// CM Message 15 - Request to add a block to the end of a put in progress, as written through
+// an SdfsWriter, with the version, the write lease, the number of datanodes wanted, then the
+// size and checksum of the block. The lease is renewed
+// Replies with the datanodes to write the block to. Fails with NoCapacity if fewer than
+// WRITE_QUORUM datanodes are alive to take it
+fn handle_add_block(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+    membership: &Arc<Membership>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let filename = request.path()?;
+    let version = request.u32()?;
+    let lease = request.u64()?;
+    let no_datanodes = request.u32()?;
+    let size = request.u64()?;
+    let checksum = request.u32()?;
+
+    let nodes = {
+        let mut meta = shared_meta.lock().unwrap();
+        let nodes = meta
+            .place_blocks(1, no_datanodes as usize, get_membership(membership))
+            .remove(0);
+        if nodes.len() < WRITE_QUORUM as usize {
+            return Err(SdfsError::NoCapacity);
+        }
+        let index = meta.add_block(&filename, version, lease, size, checksum)?;
         println!(
-            "FileSync: {:?}",
-            meta.files_sync.get_mut(&filename).unwrap()
+            "received request from {} to add block {} of file {} version {}",
+            client_id, index, filename, version
         );
+        nodes
+    };
+
+    let mut reply = Encoder::new();
+    reply.nodes(&nodes);
+    Ok(reply.into_bytes())
+}
+
+// CM Message 9 - Request to renew a lease on a file, or release it early, as the file name,
+// the lease and 1 to renew or 0 to release
+// Fails with Locked if the lease is no longer held (e.g. it expired)
+fn handle_lease(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let filename = request.str()?;
+    let lease = request.u64()?;
+    let renew = request.u8()? == 1;
+
+    let held = {
+        let mut meta = shared_meta.lock().unwrap();
+        if renew {
+            meta.renew_lease(&filename, lease)
+        } else {
+            meta.release_lease(&filename, lease)
+        }
+    };
+    if !held {
+        return Err(SdfsError::Locked(filename));
     }
+    Ok(Vec::new())
+}
 
-    // Wait if necessary
-    if let Some(rx) = rx_opt {
-        rx.recv().unwrap();
+// CM Message 2 - Request to get file, and CM Message 7 - Request to get the latest versions
+// of a file, which also gives the number of versions wanted
+// Replies with the read lease, the number of versions, then for each version, newest first,
+// the version, the number of blocks, then for each block its checksum and the datanodes to
+// read it from. The first datanode of each block is a reader under the lease
+fn handle_get_file(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+    many: bool,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let filename = request.str()?;
+    let no_versions = if many { request.u32()? } else { 1 };
+
+    println!(
+        "received request from {} to get {} versions of file {}",
+        client_id, no_versions, filename
+    );
+
+    let lease = wait_for_lease(shared_meta, &filename, RequestType::Read)?;
+
+    // Spread the reads of the blocks over their replicas. The client falls back to the other
+    // replicas of a block if the first does not send it intact
+    let mut to_read: Vec<(u32, Vec<BlockLocation>)> = Vec::new();
+    {
+        let mut meta = shared_meta.lock().unwrap();
+        let versions = meta.get_versions(&filename, no_versions as usize);
+
+        // Deleted while waiting, let the next waiter in
+        if versions.is_empty() {
+            meta.release_lease(&filename, lease);
+            return Err(SdfsError::NotFound(filename));
+        }
+        for version in versions {
+            let blocks: Vec<BlockLocation> = meta
+                .get_blocks(&filename, version)
+                .into_iter()
+                .enumerate()
+                .map(|(index, location)| location.starting_at(index))
+                .collect();
+            to_read.push((version, blocks));
+        }
+        let file_sync = meta.files_sync.get_mut(&filename).unwrap();
+        let readers: Vec<u8> = to_read
+            .iter()
+            .flat_map(|(_, blocks)| blocks.iter())
+            .filter_map(|location| location.nodes.first().copied())
+            .collect();
+        file_sync.assign(lease, readers);
+        println!("FileSync: {:?}", file_sync);
     }
 
-    let mut list: Vec<u8>;
+    let mut reply = Encoder::new();
+    reply.u64(lease).count(to_read.len());
+    for (version, blocks) in to_read {
+        reply.u32(version).count(blocks.len());
+        for location in blocks {
+            reply.u32(location.checksum).nodes(&location.nodes);
+        }
+    }
+    Ok(reply.into_bytes())
+}
+
+// CM Message 3 - Request to delete file, or an empty directory
+// Takes the write lock on the file, removes its blocks from every datanode storing any,
+// then forgets it. Replies once deleted
+fn handle_delete_file(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let filename = request.str()?;
+
+    println!(
+        "received request from {} to delete file {}",
+        client_id, filename
+    );
+
     {
         let mut meta = shared_meta.lock().unwrap();
+        if !meta.namespace.is_file(&filename) && meta.namespace.is_dir(&filename) {
+            meta.remove_dir(&filename)?;
+            println!("Deleted directory {}.", filename);
+            return Ok(Vec::new());
+        }
+    }
+
+    let lease = wait_for_lease(shared_meta, &filename, RequestType::Write)?;
 
-        // Get n free nodes if file does not exist
-        if !file_exist {
-            list = meta.get_n_free_nodes(no_datanodes.into(), get_membership());
+    let datanodes: Vec<u8>;
+    {
+        let mut meta = shared_meta.lock().unwrap();
+
+        // Deleted while waiting, let the next waiter in
+        if !meta.has_file(&filename) {
+            meta.release_lease(&filename, lease);
+            return Err(SdfsError::NotFound(filename));
         }
-        // Get n existing nodes if file already exists
-        else {
-            list = meta.get_nodes_for_file(filename.clone())[0..(no_datanodes as usize)].into();
+        datanodes = meta.get_nodes_for_file(filename.clone());
+    }
+
+    // A datanode that cannot be reached has failed, and its replicas are dropped anyway
+    let renewer = renew_leases(shared_meta, vec![(filename.clone(), lease)]);
+    for node in datanodes.iter() {
+        if let Err(err) = send_delete_request(*node, &filename) {
+            println!(
+                "Failed to delete {} from datanode {}: {}",
+                filename, node, err
+            );
         }
-        meta.files_sync
-            .get_mut(&filename)
-            .unwrap()
-            .add_writer(list.clone());
-        println!(
-            "FileSync: {:?}",
-            meta.files_sync.get_mut(&filename).unwrap()
-        );
     }
+    drop(renewer);
 
-    list.insert(0, list.len() as u8);
-    stream.write(list.as_slice()).unwrap();
+    {
+        let mut meta = shared_meta.lock().unwrap();
+        meta.release_lease(&filename, lease);
+        meta.remove_file(&filename);
+    }
+
+    println!("Deleted {} from {:?}.", filename, datanodes);
+    Ok(Vec::new())
+}
+
+fn send_delete_request(node: u8, filename: &str) -> Result<(), SdfsError> {
+    let mut request = Encoder::new();
+    request.str(filename);
+    Connection::open(node, CD_PORT)?.call(CD_DELETE_FILE, request.into_bytes())?;
+    Ok(())
+}
+
+// CM Message 4 - Request to list where file is stored
+// Replies with the datanodes storing a block of it
+fn handle_ls_file(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let filename = request.str()?;
+
+    println!("received request from to list file {}", filename);
+
+    let meta = shared_meta.lock().unwrap();
+    if !meta.has_file(&filename) {
+        return Err(SdfsError::NotFound(filename));
+    }
+    let mut reply = Encoder::new();
+    reply.nodes(&meta.get_nodes_for_file(filename));
+    Ok(reply.into_bytes())
 }
 
-// CM Message 2 - Request to get file
-fn handle_get_file(mut stream: TcpStream, shared_meta: Arc<Mutex<Metadata>>) {
-    let mut client_id: [u8; 1] = [0];
-    stream.read_exact(&mut client_id).unwrap();
-    let client_id = client_id[0];
+// CM Message 10 - Request to list the files in SDFS whose paths start with a prefix and
+// match a glob pattern, empty to match any
+// Replies with the number of files, then their paths in order
+fn handle_list_files(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let prefix = request.str()?;
+    let pattern = request.str()?;
+    let filenames = shared_meta.lock().unwrap().list_files(&prefix, &pattern);
 
-    let filename = receive_filename(&mut stream).unwrap();
+    let mut reply = Encoder::new();
+    reply.count(filenames.len());
+    for filename in filenames.iter() {
+        reply.str(filename);
+    }
+    Ok(reply.into_bytes())
+}
+
+// CM Message 12 - Request to make a directory, and those above it
+fn handle_mkdir(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let path = request.path()?;
 
     println!(
-        "received request from {} to get file {}",
-        client_id, filename
+        "received request from {} to make directory {}",
+        client_id, path
     );
 
-    // Do checks
-    let mut rx_opt: Option<Receiver<u8>> = None;
+    shared_meta.lock().unwrap().mkdir(&path)?;
+    Ok(Vec::new())
+}
+
+// CM Message 13 - Request to list a directory
+// Replies with the number of entries, then for each in name order its name, 1 if it is a
+// directory or 0 if a file, and the size, version, fewest replicas of a block and mtime of
+// the file's latest version
+fn handle_list_dir(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let path = request.path()?;
+
+    let entries = shared_meta.lock().unwrap().list_dir(&path)?;
+    let mut reply = Encoder::new();
+    reply.count(entries.len());
+    for entry in entries.iter() {
+        reply
+            .str(&entry.name)
+            .u8(entry.is_dir as u8)
+            .u64(entry.size)
+            .u32(entry.version)
+            .u32(entry.replicas)
+            .u64(entry.mtime);
+    }
+    Ok(reply.into_bytes())
+}
+
+// CM Message 14 - Request to move a file, or a directory with everything under it, to a
+// path that is free
+// Takes the write lock on both names of every file moved, in name order so that it cannot
+// deadlock with another rename, has every datanode storing a block of each rename its
+// blocks, then records the move. A datanode that fails to rename its blocks loses its
+// replicas of them, which are copied again from the others. Replies once moved
+fn handle_rename(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let from = request.path()?;
+    let to = request.path()?;
+
+    println!(
+        "received request from {} to move {} to {}",
+        client_id, from, to
+    );
+
+    let moves = shared_meta.lock().unwrap().check_rename(&from, &to)?;
+    let mut names: Vec<String> = Vec::new();
+    for (filename, moved) in moves.iter() {
+        names.push(filename.clone());
+        names.push(moved.clone());
+    }
+    names.sort();
     {
         let mut meta = shared_meta.lock().unwrap();
+        for name in names.iter() {
+            meta.files_sync.entry(name.clone()).or_default();
+        }
+    }
+    let mut leases: Vec<(String, u64)> = Vec::new();
+    let release = |leases: &[(String, u64)]| {
+        let mut meta = shared_meta.lock().unwrap();
+        for (name, lease) in leases {
+            meta.release_lease(name, *lease);
+        }
+    };
+    for name in names {
+        match wait_for_lease(shared_meta, &name, RequestType::Write) {
+            Ok(lease) => leases.push((name, lease)),
+            Err(err) => {
+                release(&leases);
+                return Err(err);
+            }
+        }
+    }
 
-        match meta.files_sync.get_mut(&filename) {
-            // File does exist
-            Some(file_sync) => {
-                if file_sync.state != FileState::Free {
-                    if file_sync.state == FileState::Write {
-                        let (tx, rx) = mpsc::channel::<u8>();
-                        file_sync.queue.push_back((RequestType::Read, tx));
-                        rx_opt = Some(rx);
-                    } else if file_sync.state == FileState::Read {
-                        if file_sync.queue.len() > 0 || file_sync.ops.len() >= 2 {
-                            let (tx, rx) = mpsc::channel::<u8>();
-                            file_sync.queue.push_back((RequestType::Read, tx));
-                            rx_opt = Some(rx);
-                        }
-                    }
-                }
+    // Files may have been put or deleted while waiting
+    let datanodes: Vec<(String, String, Vec<u8>)>;
+    {
+        let meta = shared_meta.lock().unwrap();
+        match meta.check_rename(&from, &to) {
+            Ok(now) if now == moves => {}
+            Ok(_) => {
+                drop(meta);
+                release(&leases);
+                return Err(SdfsError::Locked(from));
+            }
+            Err(err) => {
+                drop(meta);
+                release(&leases);
+                return Err(err);
             }
-            // File does not exist
-            None => {}
         }
-        println!(
-            "FileSync: {:?}",
-            meta.files_sync.get_mut(&filename).unwrap()
-        );
+        datanodes = moves
+            .into_iter()
+            .map(|(filename, moved)| {
+                let nodes = meta.get_nodes_for_file(filename.clone());
+                (filename, moved, nodes)
+            })
+            .collect();
     }
 
-    // Wait if necessary
-    if let Some(rx) = rx_opt {
-        rx.recv().unwrap();
+    let mut failed: Vec<(String, u8)> = Vec::new();
+    let renewer = renew_leases(shared_meta, leases.clone());
+    for (filename, moved, nodes) in datanodes.iter() {
+        for node in nodes.iter() {
+            if let Err(err) = send_rename_request(*node, filename, moved) {
+                println!("Failed to move {} on datanode {}: {}", filename, node, err);
+                failed.push((moved.clone(), *node));
+            }
+        }
     }
+    drop(renewer);
 
-    let to_write_datanode: [u8; 1];
     {
         let mut meta = shared_meta.lock().unwrap();
-        to_write_datanode = [meta.get_nodes_for_file(filename.clone())[0]];
-        meta.files_sync
-            .get_mut(&filename)
-            .unwrap()
-            .add_reader(to_write_datanode[0].clone());
-        println!(
-            "FileSync: {:?}",
-            meta.files_sync.get_mut(&filename).unwrap()
-        );
+        let renamed = meta.rename(&from, &to);
+        if renamed.is_ok() {
+            for (moved, node) in failed {
+                for block in meta.namespace.file_blocks(&moved) {
+                    meta.remove_replica(block, node);
+                }
+            }
+        }
+        drop(meta);
+        release(&leases);
+        renamed?;
     }
 
-    stream.write(&to_write_datanode).unwrap();
+    println!("Moved {} to {}.", from, to);
+    Ok(Vec::new())
 }
 
-// CM Message 2 - Request to get file
-fn handle_ls_file(mut stream: TcpStream, shared_meta: Arc<Mutex<Metadata>>) {
-    let filename = receive_filename(&mut stream).unwrap();
+fn send_rename_request(node: u8, filename: &str, moved: &str) -> Result<(), SdfsError> {
+    let mut request = Encoder::new();
+    request.str(filename).str(moved);
+    Connection::open(node, CD_PORT)?.call(CD_RENAME_FILE, request.into_bytes())?;
+    Ok(())
+}
 
-    println!("received request from to list file {}", filename);
+// CM Message 11 - Request for what is known of a file, without reading it
+// Replies with the number of versions kept, those versions newest first, the number of
+// blocks of the newest, then the datanodes storing a block of it
+fn handle_stat_file(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let filename = request.str()?;
 
     let meta = shared_meta.lock().unwrap();
-    let mut to_list_datanodes = meta.get_nodes_for_file(filename);
+    let versions = meta.get_versions(&filename, MAX_VERSIONS);
+    if versions.is_empty() {
+        return Err(SdfsError::NotFound(filename));
+    }
+    let mut reply = Encoder::new();
+    reply.count(versions.len());
+    for version in versions.iter() {
+        reply.u32(*version);
+    }
+    let blocks = meta.get_blocks(&filename, versions[0]).len() as u32;
+    reply.u32(blocks).nodes(&meta.get_nodes_for_file(filename));
+    Ok(reply.into_bytes())
+}
+
+// CM Message 5 - Request to have several nodes fetch a file, in parallel
+// Each fetch reads every block of the file, each from one of its replicas in turn, as a
+// reader. Replies with the number of nodes, then (node, 1 or 0) for each node
+fn handle_multiread(
+    request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let client_id = request.node()?;
+    let filename = request.str()?;
+    let local_filename = request.str()?;
+    let targets = request.nodes()?;
+
+    println!(
+        "received request from {} to have {:?} fetch file {}",
+        client_id, targets, filename
+    );
+
+    let lease = wait_for_lease(shared_meta, &filename, RequestType::Read)?;
+
+    // Spread the fetches of the latest version's blocks over their replicas. Each node
+    // falls back to the other replicas of a block if the first does not send it intact
+    let mut fetches: Vec<(u8, Vec<BlockLocation>)> = Vec::new();
+    {
+        let mut meta = shared_meta.lock().unwrap();
+        let version = meta.latest_version(&filename);
+
+        // Deleted while waiting, let the next waiter in
+        if version == 0 {
+            meta.release_lease(&filename, lease);
+            return Err(SdfsError::NotFound(filename));
+        }
+        let blocks = meta.get_blocks(&filename, version);
+        let file_sync = meta.files_sync.get_mut(&filename).unwrap();
+        let mut readers: Vec<u8> = Vec::new();
+        for (i, target) in targets.iter().enumerate() {
+            let mut sources: Vec<BlockLocation> = Vec::new();
+            for (index, location) in blocks.iter().enumerate() {
+                let location = location.clone().starting_at(i + index);
+                if let Some(source) = location.nodes.first() {
+                    readers.push(*source);
+                }
+                sources.push(location);
+            }
+            fetches.push((*target, sources));
+        }
+        file_sync.assign(lease, readers);
+        println!("FileSync: {:?}", file_sync);
+    }
+
+    // The source of each block tells the master once it has sent it, which completes the
+    // read. A block that never left its first source completes the read here instead. The
+    // lease is held until every node is done
+    let mut handles = Vec::new();
+    for (target, sources) in fetches {
+        let filename = filename.clone();
+        let local_filename = local_filename.clone();
+        let shared_meta = shared_meta.clone();
+        handles.push(thread::spawn(move || {
+            let fetched = match send_fetch_request(target, &sources, &local_filename) {
+                Ok(fetched) => fetched,
+                Err(err) => {
+                    println!("Failed to reach node {}: {}", target, err);
+                    vec![false; sources.len()]
+                }
+            };
+            let mut meta = shared_meta.lock().unwrap();
+            if let Some(file_sync) = meta.files_sync.get_mut(&filename) {
+                for (location, block_fetched) in sources.iter().zip(fetched.iter()) {
+                    match location.nodes.first() {
+                        Some(source) if !block_fetched => file_sync.read_complete(*source),
+                        _ => {}
+                    }
+                }
+            }
+            (target, fetched.iter().all(|block_fetched| *block_fetched))
+        }));
+    }
+
+    let mut reply = Encoder::new();
+    reply.count(handles.len());
+    let renewer = renew_leases(shared_meta, vec![(filename.clone(), lease)]);
+    for handle in handles {
+        let (target, fetched) = handle.join().unwrap();
+        reply.node(target).u8(fetched as u8);
+    }
+    drop(renewer);
+    shared_meta.lock().unwrap().release_lease(&filename, lease);
+    Ok(reply.into_bytes())
+}
+
+// Asks a node to fetch every block of a file, each from the first of the given datanodes
+// that sends it intact, into a local file. Returns whether it got each block
+fn send_fetch_request(
+    target: u8,
+    sources: &[BlockLocation],
+    local_filename: &str,
+) -> Result<Vec<bool>, SdfsError> {
+    let mut request = Encoder::new();
+    request.str(local_filename).count(sources.len());
+    for location in sources {
+        request
+            .str(&location.block)
+            .u32(location.checksum)
+            .nodes(&location.nodes);
+    }
+
+    let mut reply = Connection::open(target, CD_PORT)?.call(CD_FETCH_FILE, request.into_bytes())?;
+    let mut fetched: Vec<bool> = Vec::with_capacity(sources.len());
+    for _ in sources {
+        fetched.push(reply.u8()? == 1);
+    }
+    Ok(fetched)
+}
+
+// CM Message 6 - Request for progress of re-replication
+// Replies with a report of every under-replicated block
+fn handle_replication_status(
+    _request: &mut Decoder,
+    shared_meta: &Arc<Mutex<Metadata>>,
+) -> Result<Vec<u8>, SdfsError> {
+    let mut report = String::new();
+    {
+        let meta = shared_meta.lock().unwrap();
+        let under_replicated = meta.get_under_replicated();
+        report += &format!(
+            "{} under-replicated blocks, {} copies in progress\n",
+            under_replicated.len(),
+            meta.replications.len()
+        );
+        for (block, replicas) in under_replicated {
+            report += &format!(
+                "    >{}: {}/{} replicas, {} being copied\n",
+                block,
+                replicas,
+                REPLICATION_FACTOR,
+                meta.get_replications_for_block(&block)
+            );
+        }
+    }
 
-    to_list_datanodes.insert(0, to_list_datanodes.len() as u8);
-    stream.write(&to_list_datanodes).unwrap();
+    let mut reply = Encoder::new();
+    reply.str(&report);
+    Ok(reply.into_bytes())
 }
@@ -0,0 +1,473 @@
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/********************* RPC section *********************/
+
+// Every SDFS connection (client, datanode, master and standby alike) starts with a handshake:
+// the side connecting sends PROTOCOL_MAGIC and the PROTOCOL_VERSION it speaks, and the side
+// accepting answers with its own, then 1 if it accepts the peer or 0 if it does not. Peers
+// speaking another version are turned away.
+//
+// Then each side sends frames: [body length: u64][message id: u64][kind: u8][body], little
+// endian. A request's kind is its message type (e.g. CM_PUT_REQ) and its id is picked by the
+// sender. The reply carries the same id, and its kind is the status (see SdfsError): STATUS_OK
+// with the reply as body, or an error with its detail as body. Block contents are sent as
+// FRAME_DATA frames carrying the id of the request they belong to, ended by a FRAME_END frame.
+//
+// In bodies, names are UTF-8 strings of any length, sent as a u64 length then the bytes.
+// Counts and lengths are u64, and node ids u32.
+
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"SDFS";
+pub const PROTOCOL_VERSION: u16 = 5;
+
+// Frame kinds for block contents, apart from message types and statuses
+pub const FRAME_DATA: u8 = 0xFE;
+pub const FRAME_END: u8 = 0xFF;
+
+// Largest frame body accepted, so that a corrupt length does not exhaust memory
+pub const MAX_FRAME: u64 = 256 * 1024 * 1024;
+
+// Time a peer that connected has to shake hands and send its request. Listeners accept
+// connections one at a time, so a slow or stalled peer must not hold up the rest
+pub const ACCEPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub id: u64,
+    pub kind: u8,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    // The reply to request id: STATUS_OK and the payload, or the error's status and detail
+    pub fn reply(id: u64, reply: Result<Vec<u8>, SdfsError>) -> Self {
+        match reply {
+            Ok(body) => Frame {
+                id,
+                kind: STATUS_OK,
+                body,
+            },
+            Err(err) => {
+                let mut detail = Encoder::new();
+                detail.str(err.detail());
+                Frame {
+                    id,
+                    kind: err.status(),
+                    body: detail.into_bytes(),
+                }
+            }
+        }
+    }
+
+    // Part of the contents of a block sent for request id
+    pub fn data(id: u64, body: &[u8]) -> Self {
+        Frame {
+            id,
+            kind: FRAME_DATA,
+            body: body.to_vec(),
+        }
+    }
+
+    // The end of the contents of a block sent for request id
+    pub fn end(id: u64) -> Self {
+        Frame {
+            id,
+            kind: FRAME_END,
+            body: Vec::new(),
+        }
+    }
+
+    // The payload of a successful reply, or the error it reports
+    pub fn into_reply(self) -> Result<Decoder, SdfsError> {
+        match self.kind {
+            STATUS_OK => Ok(Decoder::new(self.body)),
+            FRAME_DATA | FRAME_END => Err(SdfsError::BadRequest(
+                "block contents where a reply was expected".to_string(),
+            )),
+            status => {
+                let detail = Decoder::new(self.body).str().unwrap_or_default();
+                Err(SdfsError::from_status(status, detail))
+            }
+        }
+    }
+}
+
+pub fn write_frame<W: Write>(mut stream: W, frame: &Frame) -> io::Result<()> {
+    let mut buf: Vec<u8> = Vec::with_capacity(17 + frame.body.len());
+    buf.extend_from_slice(&(frame.body.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&frame.id.to_le_bytes());
+    buf.push(frame.kind);
+    buf.extend_from_slice(&frame.body);
+    stream.write_all(&buf)
+}
+
+pub fn read_frame<R: Read>(mut stream: R) -> Result<Frame, SdfsError> {
+    let mut header: [u8; 17] = [0; 17];
+    stream.read_exact(&mut header)?;
+    let length = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    if length > MAX_FRAME {
+        return Err(SdfsError::BadRequest(format!("frame of {} bytes", length)));
+    }
+    let mut body: Vec<u8> = vec![0; length as usize];
+    stream.read_exact(&mut body)?;
+    Ok(Frame {
+        id: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+        kind: header[16],
+        body,
+    })
+}
+
+// Sends this side's half of the handshake: the magic and the version spoken
+pub fn write_hello<W: Write>(mut stream: W, version: u16) -> io::Result<()> {
+    let mut hello: Vec<u8> = PROTOCOL_MAGIC.to_vec();
+    hello.extend_from_slice(&version.to_le_bytes());
+    stream.write_all(&hello)
+}
+
+// Reads the peer's half of the handshake. Returns the version it speaks
+pub fn read_hello<R: Read>(mut stream: R) -> Result<u16, SdfsError> {
+    let mut hello: [u8; 6] = [0; 6];
+    stream.read_exact(&mut hello)?;
+    if hello[0..4] != PROTOCOL_MAGIC {
+        return Err(SdfsError::BadRequest(
+            "peer does not speak SDFS".to_string(),
+        ));
+    }
+    Ok(u16::from_le_bytes([hello[4], hello[5]]))
+}
+
+// A connection to another SDFS process, past the handshake
+pub struct Connection {
+    stream: TcpStream,
+    next_id: u64,
+}
+
+impl Connection {
+    // Connects to the given port of a node (1-indexed) and shakes hands
+    pub fn open(node: u8, port: &str) -> Result<Self, SdfsError> {
+        if node == 0 || node as usize > VM_LIST.len() {
+            return Err(SdfsError::BadRequest(format!("no node {}", node)));
+        }
+        let sock_addr = String::from(VM_LIST[(node - 1) as usize]) + ":" + port;
+        let mut stream = TcpStream::connect(sock_addr)?;
+        write_hello(&mut stream, PROTOCOL_VERSION)?;
+        let version = read_hello(&mut stream)?;
+        let mut accepted: [u8; 1] = [0];
+        stream.read_exact(&mut accepted)?;
+        if accepted[0] != 1 {
+            return Err(SdfsError::BadRequest(format!(
+                "node {} speaks protocol version {}, not {}",
+                node, version, PROTOCOL_VERSION
+            )));
+        }
+        Ok(Connection { stream, next_id: 1 })
+    }
+
+    // Shakes hands with a peer that connected, turning it away if it speaks another version
+    pub fn accept(stream: TcpStream) -> Result<Self, SdfsError> {
+        accept_hello(&stream)?;
+        Ok(Connection { stream, next_id: 1 })
+    }
+
+    pub fn send(&mut self, frame: &Frame) -> Result<(), SdfsError> {
+        write_frame(&mut self.stream, frame)?;
+        Ok(())
+    }
+
+    pub fn receive(&mut self) -> Result<Frame, SdfsError> {
+        read_frame(&mut self.stream)
+    }
+
+    // Sends a request. Returns its id
+    pub fn request(&mut self, kind: u8, body: Vec<u8>) -> Result<u64, SdfsError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&Frame { id, kind, body })?;
+        Ok(id)
+    }
+
+    // Waits for the reply to a request. Returns its payload, or the error it reports
+    pub fn reply_to(&mut self, id: u64) -> Result<Decoder, SdfsError> {
+        let frame = self.receive()?;
+        if frame.id != id {
+            return Err(SdfsError::BadRequest(format!(
+                "reply to message {} instead of {}",
+                frame.id, id
+            )));
+        }
+        frame.into_reply()
+    }
+
+    // Sends a request and waits for its reply
+    pub fn call(&mut self, kind: u8, body: Vec<u8>) -> Result<Decoder, SdfsError> {
+        let id = self.request(kind, body)?;
+        self.reply_to(id)
+    }
+
+    pub fn reply(&mut self, id: u64, reply: Result<Vec<u8>, SdfsError>) -> Result<(), SdfsError> {
+        self.send(&Frame::reply(id, reply))
+    }
+
+    // Sends the contents of a block as part of request id, up to the end of data
+    pub fn send_data<R: Read>(&mut self, id: u64, mut data: R) -> Result<(), SdfsError> {
+        let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+        loop {
+            let bytes_read = data.read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            self.send(&Frame::data(id, &buf[..bytes_read]))?;
+        }
+        self.send(&Frame::end(id))
+    }
+
+    // Receives the contents of a block sent as part of request id into out. A reply in their
+    // place reports why they were not sent. Returns their length
+    pub fn receive_data<W: Write>(&mut self, id: u64, mut out: W) -> Result<u64, SdfsError> {
+        let mut received: u64 = 0;
+        while let Some(chunk) = self.receive_chunk(id)? {
+            out.write_all(&chunk)?;
+            received += chunk.len() as u64;
+        }
+        out.flush()?;
+        Ok(received)
+    }
+
+    // Receives the next part of the contents of a block sent as part of request id, None once
+    // they are all received. A reply in their place reports why they were not sent
+    pub fn receive_chunk(&mut self, id: u64) -> Result<Option<Vec<u8>>, SdfsError> {
+        let frame = self.receive()?;
+        if frame.id != id {
+            return Err(SdfsError::BadRequest(format!(
+                "data of message {} instead of {}",
+                frame.id, id
+            )));
+        }
+        match frame.kind {
+            FRAME_DATA => Ok(Some(frame.body)),
+            FRAME_END => Ok(None),
+            _ => {
+                frame.into_reply()?;
+                Err(SdfsError::BadRequest(
+                    "reply where block contents were expected".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+// The accepting side of the handshake
+fn accept_hello<S: Read + Write>(mut stream: S) -> Result<(), SdfsError> {
+    let version = read_hello(&mut stream)?;
+    write_hello(&mut stream, PROTOCOL_VERSION)?;
+    let accepted = version == PROTOCOL_VERSION;
+    stream.write_all(&[accepted as u8])?;
+    if !accepted {
+        return Err(SdfsError::BadRequest(format!(
+            "peer speaks protocol version {}, not {}",
+            version, PROTOCOL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+// A stream whose reads fail once a deadline has passed, however the data trickles in
+struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Accepts a connection and reads the request it carries, giving up after ACCEPT_TIMEOUT
+pub fn accept_request(stream: TcpStream) -> Result<(Connection, Frame), SdfsError> {
+    let mut timed = DeadlineStream {
+        stream: &stream,
+        deadline: Instant::now() + ACCEPT_TIMEOUT,
+    };
+    accept_hello(&mut timed)?;
+    let request = read_frame(&mut timed)?;
+    // Handlers take as long as the transfer they serve
+    stream.set_read_timeout(None)?;
+    Ok((Connection { stream, next_id: 1 }, request))
+}
+
+// Handles a request, then replies with the outcome. Failures are logged, as the peer may
+// not be there to read them
+pub fn serve<F>(mut conn: Connection, request: Frame, handle: F)
+where
+    F: FnOnce(&mut Decoder) -> Result<Vec<u8>, SdfsError>,
+{
+    let reply = handle(&mut Decoder::new(request.body));
+    if let Err(err) = &reply {
+        println!("Failed request: {}", err);
+    }
+    let _ = conn.reply(request.id, reply);
+}
+
+// Builds the body of a message
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { buf: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    // A count of the items that follow
+    pub fn count(&mut self, count: usize) -> &mut Self {
+        self.u64(count as u64)
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.count(bytes.len());
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    pub fn node(&mut self, node: u8) -> &mut Self {
+        self.u32(node as u32)
+    }
+
+    pub fn nodes(&mut self, nodes: &[u8]) -> &mut Self {
+        self.count(nodes.len());
+        for node in nodes {
+            self.node(*node);
+        }
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// Reads the body of a message. Running past its end, or a value out of range, is a
+// BadRequest
+#[derive(Debug)]
+pub struct Decoder {
+    body: Vec<u8>,
+    at: usize,
+}
+
+impl Decoder {
+    pub fn new(body: Vec<u8>) -> Self {
+        Decoder { body, at: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], SdfsError> {
+        if self.body.len() - self.at < n {
+            return Err(SdfsError::BadRequest("message cut short".to_string()));
+        }
+        self.at += n;
+        Ok(&self.body[self.at - n..self.at])
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SdfsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SdfsError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SdfsError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // A count of items that follow, each at least min_size bytes long, which must fit in
+    // the rest of the message
+    pub fn count(&mut self, min_size: usize) -> Result<usize, SdfsError> {
+        let count = self.u64()?;
+        if count.saturating_mul(min_size as u64) > (self.body.len() - self.at) as u64 {
+            return Err(SdfsError::BadRequest(format!("count {} too large", count)));
+        }
+        Ok(count as usize)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, SdfsError> {
+        let length = self.count(1)?;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub fn str(&mut self) -> Result<String, SdfsError> {
+        match String::from_utf8(self.bytes()?) {
+            Ok(value) => Ok(value),
+            Err(_) => Err(SdfsError::BadRequest("name is not UTF-8".to_string())),
+        }
+    }
+
+    // A path in the namespace, which must be in normal form (see normalize_path)
+    pub fn path(&mut self) -> Result<String, SdfsError> {
+        let path = self.str()?;
+        if normalize_path(&path)? != path {
+            return Err(SdfsError::BadRequest(format!(
+                "{} is not a normal path",
+                path
+            )));
+        }
+        Ok(path)
+    }
+
+    // A node id, 0 for none, else the VM's number
+    pub fn node(&mut self) -> Result<u8, SdfsError> {
+        let node = self.u32()?;
+        if node as usize > VM_LIST.len() {
+            return Err(SdfsError::BadRequest(format!("no node {}", node)));
+        }
+        Ok(node as u8)
+    }
+
+    pub fn nodes(&mut self) -> Result<Vec<u8>, SdfsError> {
+        let count = self.count(4)?;
+        let mut nodes: Vec<u8> = Vec::with_capacity(count);
+        for _ in 0..count {
+            nodes.push(self.node()?);
+        }
+        Ok(nodes)
+    }
+}
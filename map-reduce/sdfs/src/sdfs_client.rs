@@ -0,0 +1,697 @@
+use std::io::Cursor;
+use std::mem;
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
+
+use crate::*;
+
+// Client of SDFS, used by sdfs-client and MapleJuice. Finds the master through the
+// datanodes, and again whenever it cannot be reached (e.g. after a failover). VMs are
+// numbered from 1, as in VM_LIST order. Clones share the master found
+// Paths are '/'-separated, and may start or end with a '/' (see normalize_path)
+#[derive(Debug, Clone)]
+pub struct SdfsClient {
+    client_id: u8,
+    // 0 until found
+    master: Arc<AtomicU8>,
+}
+
+// What the master knows of a file, without reading it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStat {
+    // Versions kept, newest first
+    pub versions: Vec<u32>,
+    // Blocks of the newest version
+    pub blocks: u32,
+    // Datanodes storing a block of any version
+    pub datanodes: Vec<u8>,
+}
+
+impl SdfsClient {
+    pub fn new(client_id: u8) -> Self {
+        SdfsClient {
+            client_id,
+            master: Arc::new(AtomicU8::new(0)),
+        }
+    }
+
+    // The master last found, or 0 if none is
+    pub fn master(&self) -> u8 {
+        let mut master = self.master.load(Ordering::SeqCst);
+        if master == 0 {
+            master = discover_master();
+            self.master.store(master, Ordering::SeqCst);
+        }
+        master
+    }
+
+    // Inserts a local file into SDFS as a new version. Returns the version
+    pub fn put(&self, local_filename: &str, sdfs_filename: &str) -> Result<u32, SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let size = match fs::metadata(local_filename) {
+            Ok(metadata) => metadata.len(),
+            Err(err) => {
+                return Err(err.into());
+            }
+        };
+        let checksums = block_checksums(local_filename)?;
+
+        let mut request = Encoder::new();
+        request
+            .node(self.client_id)
+            .u32(REPLICATION_FACTOR as u32)
+            .str(sdfs_filename)
+            .u64(size)
+            .count(checksums.len());
+        for checksum in checksums.iter() {
+            request.u32(*checksum);
+        }
+        let (master, (version, lease, placement)) =
+            self.call(CM_PUT_REQ, request.into_bytes(), read_placement_reply)?;
+
+        let blocks: Vec<BlockLocation> = placement
+            .into_iter()
+            .zip(checksums)
+            .enumerate()
+            .map(|(index, (nodes, checksum))| BlockLocation {
+                block: block_name(sdfs_filename, version, index as u32),
+                checksum,
+                nodes,
+            })
+            .collect();
+        // The lease is renewed while writing, and released by the commit
+        let renewed = sdfs_filename.clone();
+        let renewer = LeaseRenewer::start(move || {
+            let _ = send_lease_request(master, &renewed, lease, true);
+        });
+        let written = write_blocks(local_filename, &blocks);
+        drop(renewer);
+        self.commit(master, sdfs_filename, version, lease, &written)?;
+        Ok(version)
+    }
+
+    // Copies the latest version of a file from SDFS into a local file, replacing it
+    pub fn get(&self, sdfs_filename: &str, local_filename: &str) -> Result<(), SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let (master, (lease, versions)) = self.read_plan(sdfs_filename, 1)?;
+        let (_, blocks) = &versions[0];
+        holding_lease(master, sdfs_filename, lease, || {
+            if let Err(err) = File::create(local_filename) {
+                return Err(err.into());
+            }
+            read_version(blocks, local_filename, 0)
+        })
+    }
+
+    // Writes the newest num_versions versions of a file into a local file, newest first,
+    // each after a delimiter line naming it. Returns the versions written
+    pub fn get_versions(
+        &self,
+        sdfs_filename: &str,
+        num_versions: u8,
+        local_filename: &str,
+    ) -> Result<Vec<u32>, SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let (master, (lease, versions)) = self.read_plan(sdfs_filename, num_versions)?;
+        holding_lease(master, sdfs_filename, lease, || {
+            let mut file = match File::create(local_filename) {
+                Ok(file) => file,
+                Err(err) => {
+                    return Err(err.into());
+                }
+            };
+            for (version, blocks) in versions.iter() {
+                // The blocks of the previous version were written through other handles
+                let end = match file.seek(SeekFrom::End(0)) {
+                    Ok(end) => end,
+                    Err(err) => {
+                        return Err(err.into());
+                    }
+                };
+                let delimiter = format!("===== {} version {} =====\n", sdfs_filename, version);
+                if let Err(err) = file.write_all(delimiter.as_bytes()) {
+                    return Err(err.into());
+                }
+                read_version(blocks, local_filename, end + delimiter.len() as u64)?;
+            }
+            Ok(versions.iter().map(|(version, _)| *version).collect())
+        })
+    }
+
+    // Removes every version of a file from SDFS, or an empty directory
+    pub fn delete(&self, sdfs_filename: &str) -> Result<(), SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let mut request = Encoder::new();
+        request.node(self.client_id).str(sdfs_filename);
+        // Replied once the file is deleted
+        self.call(CM_DELETE_REQ, request.into_bytes(), |_| Ok(()))?;
+        Ok(())
+    }
+
+    // Datanodes storing a block of a file
+    pub fn ls(&self, sdfs_filename: &str) -> Result<Vec<u8>, SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let mut request = Encoder::new();
+        request.str(sdfs_filename);
+        let (_, nodes) = self.call(CM_LS_REQ, request.into_bytes(), |reply| reply.nodes())?;
+        Ok(nodes)
+    }
+
+    // Every file in SDFS, in name order
+    pub fn list(&self) -> Result<Vec<String>, SdfsError> {
+        self.list_files("", "")
+    }
+
+    // Every file whose path starts with prefix, in name order. E.g. the prefix "dir/" lists
+    // everything under dir
+    pub fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, SdfsError> {
+        self.list_files(prefix, "")
+    }
+
+    // Every file whose path matches a glob pattern (see glob_match), in name order
+    pub fn glob(&self, pattern: &str) -> Result<Vec<String>, SdfsError> {
+        self.list_files(glob_prefix(pattern), pattern)
+    }
+
+    fn list_files(&self, prefix: &str, pattern: &str) -> Result<Vec<String>, SdfsError> {
+        let mut request = Encoder::new();
+        request.str(prefix).str(pattern);
+        let (_, filenames) = self.call(CM_LIST_REQ, request.into_bytes(), |reply| {
+            let count = reply.count(8)?;
+            let mut filenames: Vec<String> = Vec::with_capacity(count);
+            for _ in 0..count {
+                filenames.push(reply.str()?);
+            }
+            Ok(filenames)
+        })?;
+        Ok(filenames)
+    }
+
+    // Makes a directory, and those above it
+    pub fn mkdir(&self, path: &str) -> Result<(), SdfsError> {
+        let mut request = Encoder::new();
+        request.node(self.client_id).str(&normalize_path(path)?);
+        self.call(CM_MKDIR_REQ, request.into_bytes(), |_| Ok(()))?;
+        Ok(())
+    }
+
+    // The files and directories directly in a directory, in name order
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, SdfsError> {
+        let mut request = Encoder::new();
+        request.str(&normalize_path(path)?);
+        let (_, entries) = self.call(CM_LIST_DIR_REQ, request.into_bytes(), |reply| {
+            let count = reply.count(33)?;
+            let mut entries: Vec<DirEntry> = Vec::with_capacity(count);
+            for _ in 0..count {
+                entries.push(DirEntry {
+                    name: reply.str()?,
+                    is_dir: reply.u8()? == 1,
+                    size: reply.u64()?,
+                    version: reply.u32()?,
+                    replicas: reply.u32()?,
+                    mtime: reply.u64()?,
+                });
+            }
+            Ok(entries)
+        })?;
+        Ok(entries)
+    }
+
+    // Moves a file, or a directory with everything under it, to a path that is free
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), SdfsError> {
+        let mut request = Encoder::new();
+        request
+            .node(self.client_id)
+            .str(&normalize_path(from)?)
+            .str(&normalize_path(to)?);
+        // Replied once moved
+        self.call(CM_RENAME_REQ, request.into_bytes(), |_| Ok(()))?;
+        Ok(())
+    }
+
+    pub fn exists(&self, sdfs_filename: &str) -> Result<bool, SdfsError> {
+        match self.stat(sdfs_filename) {
+            Ok(_) => Ok(true),
+            Err(SdfsError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // What the master knows of a file
+    pub fn stat(&self, sdfs_filename: &str) -> Result<FileStat, SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let mut request = Encoder::new();
+        request.str(sdfs_filename);
+        let (_, stat) = self.call(CM_STAT_REQ, request.into_bytes(), read_stat_reply)?;
+        Ok(stat)
+    }
+
+    // Has each of the given nodes fetch the latest version of a file from SDFS into
+    // local_filename, in parallel. Returns whether each node got the file
+    pub fn multiread(
+        &self,
+        sdfs_filename: &str,
+        local_filename: &str,
+        nodes: &[u8],
+    ) -> Result<Vec<(u8, bool)>, SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let mut request = Encoder::new();
+        request
+            .node(self.client_id)
+            .str(sdfs_filename)
+            .str(local_filename)
+            .nodes(nodes);
+        // Replied once every node is done
+        let (_, results) = self.call(CM_MULTIREAD_REQ, request.into_bytes(), |reply| {
+            let count = reply.count(5)?;
+            let mut results: Vec<(u8, bool)> = Vec::with_capacity(count);
+            for _ in 0..count {
+                results.push((reply.node()?, reply.u8()? == 1));
+            }
+            Ok(results)
+        })?;
+        Ok(results)
+    }
+
+    // Report of the blocks stored on fewer than REPLICATION_FACTOR datanodes, and the copies
+    // restoring them
+    pub fn replication_status(&self) -> Result<String, SdfsError> {
+        let (_, report) = self.call(CM_REPLICATION_REQ, Vec::new(), |reply| reply.str())?;
+        Ok(report)
+    }
+
+    // Opens the latest version of a file for reading. Its blocks are streamed one at a time
+    // as they are read
+    pub fn open_read(&self, sdfs_filename: &str) -> Result<SdfsReader, SdfsError> {
+        let sdfs_filename = &normalize_path(sdfs_filename)?;
+        let (master, (lease, mut versions)) = self.read_plan(sdfs_filename, 1)?;
+        let held = HeldLease::hold(master, sdfs_filename, lease);
+        let (_, blocks) = versions.remove(0);
+        check_read_quorum(&blocks)?;
+        Ok(SdfsReader {
+            blocks,
+            next: 0,
+            stream: None,
+            chunk: Cursor::new(Vec::new()),
+            errors: Vec::new(),
+            _held: held,
+        })
+    }
+
+    // Creates a new version of a file, written through the returned handle. Each block is
+    // sent as soon as it is full, and the version is committed once the handle is finished
+    pub fn create(&self, sdfs_filename: &str) -> Result<SdfsWriter, SdfsError> {
+        let sdfs_filename = normalize_path(sdfs_filename)?;
+        // A put of no blocks, which are added as they are written
+        let mut request = Encoder::new();
+        request
+            .node(self.client_id)
+            .u32(REPLICATION_FACTOR as u32)
+            .str(&sdfs_filename)
+            .u64(0)
+            .count(0);
+        let (master, (version, lease, _)) =
+            self.call(CM_PUT_REQ, request.into_bytes(), read_placement_reply)?;
+        let renewed = sdfs_filename.clone();
+        let renewer = LeaseRenewer::start(move || {
+            let _ = send_lease_request(master, &renewed, lease, true);
+        });
+        Ok(SdfsWriter {
+            client: self.clone(),
+            sdfs_filename,
+            master,
+            version,
+            lease,
+            renewer: Some(renewer),
+            block: Vec::new(),
+            written: Vec::new(),
+        })
+    }
+
+    // Commits a put once its blocks are written, given the datanodes that confirmed each.
+    // Only acknowledged once every block is on WRITE_QUORUM datanodes and the master that
+    // placed the put has recorded the version
+    fn commit(
+        &self,
+        master: u8,
+        sdfs_filename: &str,
+        version: u32,
+        lease: u64,
+        written: &[Vec<u8>],
+    ) -> Result<(), SdfsError> {
+        let mut request = Encoder::new();
+        request
+            .node(self.client_id)
+            .str(sdfs_filename)
+            .u32(version)
+            .u64(lease)
+            .count(written.len());
+        for nodes in written.iter() {
+            request.nodes(nodes);
+        }
+        self.call_at(master, CM_COMMIT_REQ, &request.into_bytes(), |_| Ok(()))
+    }
+
+    // Asks the master for the newest versions of a file to read, under a read lease.
+    // Returns the master that granted it
+    fn read_plan(
+        &self,
+        sdfs_filename: &str,
+        num_versions: u8,
+    ) -> Result<(u8, ReadPlan), SdfsError> {
+        let mut request = Encoder::new();
+        request
+            .node(self.client_id)
+            .str(sdfs_filename)
+            .u32(num_versions as u32);
+        let (master, plan) = self.call(CM_GET_VERSIONS_REQ, request.into_bytes(), |reply| {
+            read_blocks_reply(reply, sdfs_filename)
+        })?;
+        if plan.1.is_empty() {
+            return Err(SdfsError::NotFound(String::from(sdfs_filename)));
+        }
+        Ok((master, plan))
+    }
+
+    // Sends a request to the master and reads its reply, trying again on the new master if
+    // it has moved (it cannot be reached, or is standing by). Returns the master that replied
+    fn call<T>(
+        &self,
+        kind: u8,
+        request: Vec<u8>,
+        reply: impl Fn(&mut Decoder) -> Result<T, SdfsError>,
+    ) -> Result<(u8, T), SdfsError> {
+        let master = self.master();
+        let first = self.call_at(master, kind, &request, &reply);
+        match first {
+            Err(SdfsError::NotMaster) | Err(SdfsError::Io(_)) => {}
+            _ => {
+                return first.map(|result| (master, result));
+            }
+        }
+        let found = discover_master();
+        if found == 0 || found == master {
+            return first.map(|result| (master, result));
+        }
+        self.master.store(found, Ordering::SeqCst);
+        self.call_at(found, kind, &request, &reply)
+            .map(|result| (found, result))
+    }
+
+    fn call_at<T>(
+        &self,
+        master: u8,
+        kind: u8,
+        request: &[u8],
+        reply: impl Fn(&mut Decoder) -> Result<T, SdfsError>,
+    ) -> Result<T, SdfsError> {
+        if master == 0 {
+            return Err(SdfsError::Io("no master available".to_string()));
+        }
+        let mut conn = Connection::open(master, CM_PORT)?;
+        reply(&mut conn.call(kind, request.to_vec())?)
+    }
+}
+
+// A version of a file in SDFS being read, streamed a block at a time. Each part of a block is
+// returned as it arrives from the datanode, so a block is only checked against its checksum
+// once it has been read to the end: a read then fails with ChecksumMismatch, after the
+// corrupt data was returned. Holds a read lease on the file until dropped
+pub struct SdfsReader {
+    blocks: Vec<BlockLocation>,
+    // Block being read, or to open once the current one is done
+    next: usize,
+    stream: Option<BlockStream>,
+    // Part of the block received and not yet read
+    chunk: Cursor<Vec<u8>>,
+    // Datanodes that failed to send the block being read, and why
+    errors: Vec<(u8, SdfsError)>,
+    _held: HeldLease,
+}
+
+// A block being received from one of its datanodes
+struct BlockStream {
+    conn: Connection,
+    id: u64,
+    // Index of the datanode among the block's
+    node: usize,
+    checksum: Checksum,
+    received: u64,
+}
+
+impl SdfsReader {
+    // Receives the next part of the version into chunk, moving on to the next block once one
+    // is received whole and intact. A datanode that fails before sending any of the block is
+    // replaced by the next of its datanodes. Returns false at the end of the version
+    fn next_chunk(&mut self) -> Result<bool, SdfsError> {
+        loop {
+            if self.next == self.blocks.len() {
+                return Ok(false);
+            }
+            let mut stream = match self.stream.take() {
+                Some(stream) => stream,
+                None => self.open_block(0)?,
+            };
+            match stream.conn.receive_chunk(stream.id) {
+                Ok(Some(chunk)) => {
+                    stream.checksum.update(&chunk);
+                    stream.received += chunk.len() as u64;
+                    self.chunk = Cursor::new(chunk);
+                    self.stream = Some(stream);
+                    return Ok(true);
+                }
+                Ok(None) => {
+                    let location = &self.blocks[self.next];
+                    if stream.checksum.value() != location.checksum {
+                        return Err(SdfsError::ChecksumMismatch(location.block.clone()));
+                    }
+                    self.errors.clear();
+                    self.next += 1;
+                }
+                Err(err) if stream.received == 0 => {
+                    let node = self.blocks[self.next].nodes[stream.node];
+                    self.errors.push((node, err));
+                    self.stream = Some(self.open_block(stream.node + 1)?);
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    // Asks the datanodes of the block being read for it, starting from the given one, until
+    // one takes the request
+    fn open_block(&mut self, from: usize) -> Result<BlockStream, SdfsError> {
+        let location = &self.blocks[self.next];
+        for (index, node) in location.nodes.iter().enumerate().skip(from) {
+            if *node == 0 {
+                continue;
+            }
+            let mut request = Encoder::new();
+            request.str(&location.block);
+            let opened = Connection::open(*node, CD_PORT).and_then(|mut conn| {
+                let id = conn.request(CD_READ_FILE, request.into_bytes())?;
+                Ok((conn, id))
+            });
+            match opened {
+                Ok((conn, id)) => {
+                    return Ok(BlockStream {
+                        conn,
+                        id,
+                        node: index,
+                        checksum: Checksum::new(),
+                        received: 0,
+                    });
+                }
+                Err(err) => self.errors.push((*node, err)),
+            }
+        }
+        Err(block_unread(location, mem::take(&mut self.errors)))
+    }
+}
+
+impl Read for SdfsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.next_chunk() {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Ok(0);
+                }
+                Err(err) => {
+                    return Err(io::Error::other(err));
+                }
+            }
+        }
+    }
+}
+
+// A new version of a file in SDFS being written. What is written is buffered until it fills a
+// block (up to BLOCK_SIZE in memory), which is then placed by the master and streamed down its
+// chain of datanodes before the write returns. Holds the put's write lease until finished.
+// Dropping the handle without finishing it abandons the put, whose blocks are orphaned
+pub struct SdfsWriter {
+    client: SdfsClient,
+    sdfs_filename: String,
+    // Master that placed the put, which alone can commit it
+    master: u8,
+    version: u32,
+    lease: u64,
+    // None once finished
+    renewer: Option<LeaseRenewer>,
+    // Contents of the block being filled
+    block: Vec<u8>,
+    // Datanodes that confirmed each block sent
+    written: Vec<Vec<u8>>,
+}
+
+impl SdfsWriter {
+    // Sends what is left, then commits everything written as a new version. Returns the
+    // version
+    pub fn finish(mut self) -> Result<u32, SdfsError> {
+        // Even an empty file has a block
+        if !self.block.is_empty() || self.written.is_empty() {
+            self.send_block()?;
+        }
+        // The commit releases the lease
+        drop(self.renewer.take());
+        self.client.commit(
+            self.master,
+            &self.sdfs_filename,
+            self.version,
+            self.lease,
+            &self.written,
+        )?;
+        Ok(self.version)
+    }
+
+    // CM Message 15 - Has the master place the block filled so far, then writes it to its
+    // datanodes. Errors with NoQuorum if fewer than WRITE_QUORUM of them stored it, as the
+    // version could not be committed
+    fn send_block(&mut self) -> Result<(), SdfsError> {
+        let mut checksum = Checksum::new();
+        checksum.update(&self.block);
+        let mut request = Encoder::new();
+        request
+            .node(self.client.client_id)
+            .str(&self.sdfs_filename)
+            .u32(self.version)
+            .u64(self.lease)
+            .u32(REPLICATION_FACTOR as u32)
+            .u64(self.block.len() as u64)
+            .u32(checksum.value());
+        let nodes = self.client.call_at(
+            self.master,
+            CM_ADD_BLOCK_REQ,
+            &request.into_bytes(),
+            |reply| reply.nodes(),
+        )?;
+
+        let location = BlockLocation {
+            block: block_name(&self.sdfs_filename, self.version, self.written.len() as u32),
+            checksum: checksum.value(),
+            nodes,
+        };
+        let stored = write_block_data(&self.block, &location);
+        if stored.len() < WRITE_QUORUM as usize {
+            return Err(SdfsError::NoQuorum(format!(
+                "{} stored on {} datanodes, {} needed",
+                location.block,
+                stored.len(),
+                WRITE_QUORUM
+            )));
+        }
+        self.written.push(stored);
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl Write for SdfsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.renewer.is_none() {
+            return Err(io::Error::other("write already finished"));
+        }
+        let filled = self.block.len();
+        let taken = buf.len().min(BLOCK_SIZE as usize - filled);
+        self.block.extend_from_slice(&buf[..taken]);
+        if self.block.len() == BLOCK_SIZE as usize {
+            // Nothing of buf is written if its block could not be sent
+            if let Err(err) = self.send_block() {
+                self.block.truncate(filled);
+                return Err(io::Error::other(err));
+            }
+        }
+        Ok(taken)
+    }
+
+    // Blocks are only sent once full, or by finish
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SdfsWriter {
+    fn drop(&mut self) {
+        // Not finished, give the file up to the next waiter
+        if let Some(renewer) = self.renewer.take() {
+            drop(renewer);
+            let _ = send_lease_request(self.master, &self.sdfs_filename, self.lease, false);
+        }
+    }
+}
+
+// Reads the version to write, the write lease, then the number of blocks and the datanodes
+// for each
+fn read_placement_reply(reply: &mut Decoder) -> Result<(u32, u64, Vec<Vec<u8>>), SdfsError> {
+    let version = reply.u32()?;
+    let lease = reply.u64()?;
+    let blocks = reply.count(8)?;
+    let mut placement: Vec<Vec<u8>> = Vec::with_capacity(blocks);
+    for _ in 0..blocks {
+        placement.push(reply.nodes()?);
+    }
+    Ok((version, lease, placement))
+}
+
+// Reads the read lease, the number of versions, then the versions to read, newest first,
+// each with the checksum of each of its blocks and the datanodes to try reading it from
+fn read_blocks_reply(reply: &mut Decoder, remote_filename: &str) -> Result<ReadPlan, SdfsError> {
+    let lease = reply.u64()?;
+    let no_versions = reply.count(12)?;
+    let mut versions: Vec<(u32, Vec<BlockLocation>)> = Vec::with_capacity(no_versions);
+    for _ in 0..no_versions {
+        let version = reply.u32()?;
+        let no_blocks = reply.count(12)?;
+        let mut blocks: Vec<BlockLocation> = Vec::with_capacity(no_blocks);
+        for index in 0..no_blocks {
+            blocks.push(BlockLocation {
+                block: block_name(remote_filename, version, index as u32),
+                checksum: reply.u32()?,
+                nodes: reply.nodes()?,
+            });
+        }
+        versions.push((version, blocks));
+    }
+    Ok((lease, versions))
+}
+
+// Reads the versions kept, newest first, the number of blocks of the newest, then the
+// datanodes storing a block of it
+fn read_stat_reply(reply: &mut Decoder) -> Result<FileStat, SdfsError> {
+    let no_versions = reply.count(4)?;
+    let mut versions: Vec<u32> = Vec::with_capacity(no_versions);
+    for _ in 0..no_versions {
+        versions.push(reply.u32()?);
+    }
+    Ok(FileStat {
+        versions,
+        blocks: reply.u32()?,
+        datanodes: reply.nodes()?,
+    })
+}
@@ -0,0 +1,674 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+use crate::{block_name, parse_block_name, renamed_path, versioned_filename, MAX_VERSIONS};
+
+/********************* Metadata log section *********************/
+
+// Every metadata mutation is appended to the log (and fsynced) before it is applied.
+// Every CHECKPOINT_INTERVAL records the whole namespace is written to a checkpoint and
+// the log is emptied. On startup the checkpoint is loaded and the log replayed on top.
+//
+// Log records are [seq: u64][length: u32][checksum: u32][payload], little endian. A record
+// that is cut short or fails its checksum marks the end of the log (a crash mid-append).
+// The checkpoint has a "%block:checksum" line for every block, a "block:node" line for
+// every replica, a "#filename:v1=size@mtime,v2=size@mtime" line with the versions kept of
+// each file, then a "/path" line for every directory made, after a "@seq" line giving the
+// last record it includes, so a crash between checkpointing and emptying the log replays
+// nothing twice.
+
+pub const CHECKPOINT_INTERVAL: usize = 1000;
+
+const RECORD_HEADER_LENGTH: usize = 16;
+
+// A block of a version of a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Block {
+    // Vector of vm numbers (1-indexed) storing the block
+    pub nodes: Vec<u8>,
+    // Checksum of the block's contents, as computed by the client that put it
+    pub checksum: u32,
+}
+
+// Size and modification time of a version of a file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VersionStat {
+    pub size: u64,
+    // Seconds since the epoch at which the version was added
+    pub mtime: u64,
+}
+
+// The part of the metadata that is logged
+// Files are named by '/'-separated paths (see normalize_path). A directory exists while a file
+// or directory is under it, or once it is made, until it is deleted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespace {
+    // Key is filename, Value is the versions kept, oldest first
+    pub files_versions: BTreeMap<String, Vec<u32>>,
+    // Key is block name (see block_name), Value is where it is stored and its checksum
+    pub blocks: BTreeMap<String, Block>,
+    // Key is filename, Value is the size and modification time of each version kept
+    pub version_stats: BTreeMap<String, BTreeMap<u32, VersionStat>>,
+    // Directories made explicitly, which stay when emptied
+    pub dirs: BTreeSet<String>,
+}
+
+impl Namespace {
+    pub fn is_file(&self, path: &str) -> bool {
+        self.files_versions.contains_key(path)
+    }
+
+    // Whether a path is the root, a directory made, or has a file or directory under it
+    pub fn is_dir(&self, path: &str) -> bool {
+        if path.is_empty() || self.dirs.contains(path) {
+            return true;
+        }
+        let prefix = format!("{}/", path);
+        let file_under = self
+            .files_versions
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|(filename, _)| filename.starts_with(&prefix));
+        let dir_under = self
+            .dirs
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|dir| dir.starts_with(&prefix));
+        file_under || dir_under
+    }
+
+    // Files at a path: the file itself, or every file under the directory
+    pub fn files_under(&self, path: &str) -> Vec<String> {
+        if self.is_file(path) {
+            return vec![String::from(path)];
+        }
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+        self.files_versions
+            .range(prefix.clone()..)
+            .map(|(filename, _)| filename)
+            .take_while(|filename| filename.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+
+    // Names of the blocks of a version of a file, in order
+    pub fn version_blocks(&self, filename: &str, version: u32) -> Vec<String> {
+        let prefix = block_name(filename, version, 0);
+        let prefix = &prefix[..prefix.len() - 1];
+        let mut indices: Vec<u32> = self
+            .blocks
+            .range(String::from(prefix)..)
+            .map(|(block, _)| block)
+            .take_while(|block| block.starts_with(prefix))
+            .filter_map(|block| block[prefix.len()..].parse().ok())
+            .collect();
+        indices.sort_unstable();
+        indices
+            .into_iter()
+            .map(|index| block_name(filename, version, index))
+            .collect()
+    }
+
+    // Names of every block of a file, including those of versions not (or no longer) kept
+    pub fn file_blocks(&self, filename: &str) -> Vec<String> {
+        let prefix = versioned_filename(filename, 0);
+        let prefix = &prefix[..prefix.len() - 1];
+        self.blocks
+            .range(String::from(prefix)..)
+            .map(|(block, _)| block)
+            .take_while(|block| block.starts_with(prefix))
+            .filter(|block| match parse_block_name(block) {
+                Some((name, _, _)) => name == filename,
+                None => false,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+// Mutations of the namespace
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogRecord {
+    AddReplica {
+        block: String,
+        node: u8,
+    },
+    RemoveDatanode {
+        node: u8,
+    },
+    RemoveFile {
+        filename: String,
+    },
+    // A version is added once every one of its blocks is stored, with their checksums
+    AddVersion {
+        filename: String,
+        version: u32,
+        checksums: Vec<u32>,
+        size: u64,
+        mtime: u64,
+    },
+    // A replica found not to match its block's checksum
+    RemoveReplica {
+        block: String,
+        node: u8,
+    },
+    MakeDir {
+        path: String,
+    },
+    // Moves a file, or a directory with everything under it
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+impl LogRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            LogRecord::AddReplica { block, node } => {
+                let mut buf: Vec<u8> = vec![1, *node, block.len() as u8];
+                buf.extend_from_slice(block.as_bytes());
+                buf
+            }
+            LogRecord::RemoveDatanode { node } => vec![2, *node],
+            LogRecord::RemoveFile { filename } => {
+                let mut buf: Vec<u8> = vec![3, filename.len() as u8];
+                buf.extend_from_slice(filename.as_bytes());
+                buf
+            }
+            LogRecord::AddVersion {
+                filename,
+                version,
+                checksums,
+                size,
+                mtime,
+            } => {
+                let mut buf: Vec<u8> = vec![4, filename.len() as u8];
+                buf.extend_from_slice(filename.as_bytes());
+                buf.extend_from_slice(&version.to_le_bytes());
+                buf.extend_from_slice(&(checksums.len() as u32).to_le_bytes());
+                for checksum in checksums {
+                    buf.extend_from_slice(&checksum.to_le_bytes());
+                }
+                buf.extend_from_slice(&size.to_le_bytes());
+                buf.extend_from_slice(&mtime.to_le_bytes());
+                buf
+            }
+            LogRecord::RemoveReplica { block, node } => {
+                let mut buf: Vec<u8> = vec![5, *node, block.len() as u8];
+                buf.extend_from_slice(block.as_bytes());
+                buf
+            }
+            LogRecord::MakeDir { path } => {
+                let mut buf: Vec<u8> = vec![6, path.len() as u8];
+                buf.extend_from_slice(path.as_bytes());
+                buf
+            }
+            LogRecord::Rename { from, to } => {
+                let mut buf: Vec<u8> = vec![7, from.len() as u8];
+                buf.extend_from_slice(from.as_bytes());
+                buf.push(to.len() as u8);
+                buf.extend_from_slice(to.as_bytes());
+                buf
+            }
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match buf.first()? {
+            1 => {
+                let node = *buf.get(1)?;
+                let length = *buf.get(2)? as usize;
+                let block = String::from_utf8(buf.get(3..3 + length)?.to_vec()).ok()?;
+                Some(LogRecord::AddReplica { block, node })
+            }
+            2 => Some(LogRecord::RemoveDatanode { node: *buf.get(1)? }),
+            3 => {
+                let length = *buf.get(1)? as usize;
+                let filename = String::from_utf8(buf.get(2..2 + length)?.to_vec()).ok()?;
+                Some(LogRecord::RemoveFile { filename })
+            }
+            4 => {
+                let length = *buf.get(1)? as usize;
+                let filename = String::from_utf8(buf.get(2..2 + length)?.to_vec()).ok()?;
+                let version_bytes = buf.get(2 + length..6 + length)?;
+                let version = u32::from_le_bytes(version_bytes.try_into().unwrap());
+                let count_bytes = buf.get(6 + length..10 + length)?;
+                let count = u32::from_le_bytes(count_bytes.try_into().unwrap()) as usize;
+                let end = 10 + length + 4 * count;
+                let checksums_bytes = buf.get(10 + length..end)?;
+                let checksums = checksums_bytes
+                    .chunks(4)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect();
+                // Records logged before versions had a size and mtime have neither
+                let (size, mtime) = match buf.get(end..end + 16) {
+                    Some(stat_bytes) => (
+                        u64::from_le_bytes(stat_bytes[0..8].try_into().unwrap()),
+                        u64::from_le_bytes(stat_bytes[8..16].try_into().unwrap()),
+                    ),
+                    None => (0, 0),
+                };
+                Some(LogRecord::AddVersion {
+                    filename,
+                    version,
+                    checksums,
+                    size,
+                    mtime,
+                })
+            }
+            5 => {
+                let node = *buf.get(1)?;
+                let length = *buf.get(2)? as usize;
+                let block = String::from_utf8(buf.get(3..3 + length)?.to_vec()).ok()?;
+                Some(LogRecord::RemoveReplica { block, node })
+            }
+            6 => {
+                let length = *buf.get(1)? as usize;
+                let path = String::from_utf8(buf.get(2..2 + length)?.to_vec()).ok()?;
+                Some(LogRecord::MakeDir { path })
+            }
+            7 => {
+                let from_length = *buf.get(1)? as usize;
+                let from = String::from_utf8(buf.get(2..2 + from_length)?.to_vec()).ok()?;
+                let to_length = *buf.get(2 + from_length)? as usize;
+                let to_start = 3 + from_length;
+                let to =
+                    String::from_utf8(buf.get(to_start..to_start + to_length)?.to_vec()).ok()?;
+                Some(LogRecord::Rename { from, to })
+            }
+            _ => None,
+        }
+    }
+
+    // Applies the record to the namespace
+    pub fn apply(&self, namespace: &mut Namespace) {
+        match self {
+            LogRecord::AddReplica { block, node } => {
+                let datanodes = &mut namespace.blocks.entry(block.clone()).or_default().nodes;
+                if !datanodes.contains(node) {
+                    datanodes.push(*node);
+                }
+            }
+            LogRecord::RemoveDatanode { node } => {
+                for block in namespace.blocks.values_mut() {
+                    block.nodes.retain(|node_used| node_used != node);
+                }
+            }
+            LogRecord::RemoveFile { filename } => {
+                for block in namespace.file_blocks(filename) {
+                    namespace.blocks.remove(&block);
+                }
+                namespace.files_versions.remove(filename);
+                namespace.version_stats.remove(filename);
+                // Also how an empty directory is deleted
+                namespace.dirs.remove(filename);
+            }
+            // Only the newest MAX_VERSIONS are kept, the blocks of older ones are dropped
+            LogRecord::AddVersion {
+                filename,
+                version,
+                checksums,
+                size,
+                mtime,
+            } => {
+                for (index, checksum) in checksums.iter().enumerate() {
+                    let block = block_name(filename, *version, index as u32);
+                    namespace.blocks.entry(block).or_default().checksum = *checksum;
+                }
+
+                let versions = namespace
+                    .files_versions
+                    .entry(filename.clone())
+                    .or_default();
+                if !versions.contains(version) {
+                    versions.push(*version);
+                    versions.sort_unstable();
+                }
+                while versions.len() > MAX_VERSIONS {
+                    versions.remove(0);
+                }
+                let oldest = versions[0];
+                for block in namespace.file_blocks(filename) {
+                    if let Some((_, block_version, _)) = parse_block_name(&block) {
+                        if block_version < oldest {
+                            namespace.blocks.remove(&block);
+                        }
+                    }
+                }
+
+                let stats = namespace.version_stats.entry(filename.clone()).or_default();
+                stats.insert(
+                    *version,
+                    VersionStat {
+                        size: *size,
+                        mtime: *mtime,
+                    },
+                );
+                stats.retain(|stat_version, _| *stat_version >= oldest);
+            }
+            LogRecord::RemoveReplica { block, node } => {
+                if let Some(stored) = namespace.blocks.get_mut(block) {
+                    stored.nodes.retain(|node_used| node_used != node);
+                }
+            }
+            LogRecord::MakeDir { path } => {
+                namespace.dirs.insert(path.clone());
+            }
+            // The blocks of each file moved are renamed with it
+            LogRecord::Rename { from, to } => {
+                for filename in namespace.files_under(from) {
+                    let moved = match renamed_path(&filename, from, to) {
+                        Some(moved) => moved,
+                        None => continue,
+                    };
+                    for block in namespace.file_blocks(&filename) {
+                        let stored = namespace.blocks.remove(&block).unwrap();
+                        if let Some((_, version, index)) = parse_block_name(&block) {
+                            namespace
+                                .blocks
+                                .insert(block_name(&moved, version, index), stored);
+                        }
+                    }
+                    if let Some(versions) = namespace.files_versions.remove(&filename) {
+                        namespace.files_versions.insert(moved.clone(), versions);
+                    }
+                    if let Some(stats) = namespace.version_stats.remove(&filename) {
+                        namespace.version_stats.insert(moved, stats);
+                    }
+                }
+                let dirs: Vec<String> = namespace
+                    .dirs
+                    .iter()
+                    .filter(|dir| renamed_path(dir, from, to).is_some())
+                    .cloned()
+                    .collect();
+                for dir in dirs {
+                    namespace.dirs.remove(&dir);
+                    namespace.dirs.insert(renamed_path(&dir, from, to).unwrap());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MetadataLog {
+    checkpoint_path: String,
+    file: File,
+    // Sequence number of the last record written
+    seq: u64,
+    since_checkpoint: usize,
+    // Receives every record once it is durable, to ship to standby masters
+    follower: Option<Sender<(u64, LogRecord)>>,
+}
+
+impl MetadataLog {
+    // Recovers the namespace from the checkpoint and log, dropping a torn record at the end
+    pub fn open(checkpoint_path: &str, log_path: &str) -> io::Result<(Self, Namespace)> {
+        let (mut namespace, checkpoint_seq) = read_checkpoint(checkpoint_path)?;
+
+        let mut data: Vec<u8> = Vec::new();
+        if let Ok(mut file) = File::open(log_path) {
+            file.read_to_end(&mut data)?;
+        }
+
+        let mut seq = checkpoint_seq;
+        let mut offset: usize = 0;
+        let mut since_checkpoint: usize = 0;
+        while let Some((record_seq, record, length)) = read_record(&data[offset..]) {
+            // Records already in the checkpoint are skipped, anything else must follow on
+            if record_seq > checkpoint_seq {
+                if record_seq != seq + 1 {
+                    break;
+                }
+                record.apply(&mut namespace);
+                seq = record_seq;
+                since_checkpoint += 1;
+            }
+            offset += length;
+        }
+
+        // Cut off whatever follows the last good record, so appends continue from there
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+        file.set_len(offset as u64)?;
+        file.sync_all()?;
+        let log = MetadataLog {
+            checkpoint_path: String::from(checkpoint_path),
+            file,
+            seq,
+            since_checkpoint,
+            follower: None,
+        };
+
+        Ok((log, namespace))
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn set_follower(&mut self, follower: Sender<(u64, LogRecord)>) {
+        self.follower = Some(follower);
+    }
+
+    // Appends a record and waits for it to reach the disk. Returns its sequence number
+    pub fn append(&mut self, record: &LogRecord) -> io::Result<u64> {
+        let seq = self.seq + 1;
+        self.append_at(seq, record)?;
+        Ok(seq)
+    }
+
+    // Appends a record received from the master, which must be the next in sequence
+    pub fn append_at(&mut self, seq: u64, record: &LogRecord) -> io::Result<()> {
+        if seq != self.seq + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected record {}, got {}", self.seq + 1, seq),
+            ));
+        }
+
+        let payload = record.encode();
+        let mut buf: Vec<u8> = Vec::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&checksum(seq, &payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+
+        self.seq = seq;
+        self.since_checkpoint += 1;
+        if let Some(follower) = &self.follower {
+            let _ = follower.send((seq, record.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn needs_checkpoint(&self) -> bool {
+        self.since_checkpoint >= CHECKPOINT_INTERVAL
+    }
+
+    // Writes the namespace as of the last record to the checkpoint, then empties the log
+    pub fn checkpoint(&mut self, namespace: &Namespace) -> io::Result<()> {
+        let seq = self.seq;
+        self.checkpoint_at(seq, namespace)
+    }
+
+    // Replaces everything with a namespace as of record seq, e.g. a snapshot from the master
+    pub fn checkpoint_at(&mut self, seq: u64, namespace: &Namespace) -> io::Result<()> {
+        // Write the new checkpoint beside the old one, and only then swap it in
+        let tmp_path = self.checkpoint_path.clone() + ".tmp";
+        let mut file = File::create(&tmp_path)?;
+        let mut data = format!("@{}\n", seq);
+        for (name, block) in namespace.blocks.iter() {
+            data += &format!("%{}:{}\n", name, block.checksum);
+            for node in block.nodes.iter() {
+                data += &format!("{}:{}\n", name, node);
+            }
+        }
+        for (filename, versions) in namespace.files_versions.iter() {
+            let stats = namespace.version_stats.get(filename);
+            let versions: Vec<String> = versions
+                .iter()
+                .map(|v| {
+                    let stat = stats.and_then(|stats| stats.get(v)).copied();
+                    let stat = stat.unwrap_or_default();
+                    format!("{}={}@{}", v, stat.size, stat.mtime)
+                })
+                .collect();
+            data += &format!("#{}:{}\n", filename, versions.join(","));
+        }
+        for dir in namespace.dirs.iter() {
+            data += &format!("/{}\n", dir);
+        }
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.checkpoint_path)?;
+        sync_parent(&self.checkpoint_path)?;
+
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.seq = seq;
+        self.since_checkpoint = 0;
+        Ok(())
+    }
+}
+
+// Reads the checkpoint, or an empty namespace if there is none
+fn read_checkpoint(checkpoint_path: &str) -> io::Result<(Namespace, u64)> {
+    let mut namespace = Namespace::default();
+    let data = match fs::read_to_string(checkpoint_path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok((namespace, 0));
+        }
+        Err(err) => {
+            return Err(err);
+        }
+    };
+
+    let mut seq: u64 = 0;
+    for line in data.lines() {
+        if let Some(seq_str) = line.strip_prefix('@') {
+            seq = seq_str.parse().unwrap_or(0);
+            continue;
+        }
+        if let Some(versions_line) = line.strip_prefix('#') {
+            if let Some((name, versions)) = versions_line.rsplit_once(':') {
+                read_versions_line(&mut namespace, name, versions);
+            }
+            continue;
+        }
+        if let Some(dir) = line.strip_prefix('/') {
+            namespace.dirs.insert(String::from(dir));
+            continue;
+        }
+        if let Some(checksum_line) = line.strip_prefix('%') {
+            if let Some((name, checksum)) = checksum_line.rsplit_once(':') {
+                if let Ok(checksum) = checksum.parse() {
+                    namespace
+                        .blocks
+                        .entry(String::from(name))
+                        .or_default()
+                        .checksum = checksum;
+                }
+            }
+            continue;
+        }
+        let (name, node) = if let Some((prefix, suffix)) = line.rsplit_once(':') {
+            (prefix, suffix)
+        } else {
+            continue;
+        };
+        let node: u8 = if let Ok(num) = node.parse() {
+            num
+        } else {
+            continue;
+        };
+        LogRecord::AddReplica {
+            block: String::from(name),
+            node,
+        }
+        .apply(&mut namespace);
+    }
+
+    Ok((namespace, seq))
+}
+
+// Adds the versions of a file from its checkpoint line, each "version=size@mtime", or just
+// "version" in checkpoints from before versions had a size and mtime
+fn read_versions_line(namespace: &mut Namespace, name: &str, versions_str: &str) {
+    let mut versions: Vec<u32> = Vec::new();
+    for entry in versions_str.split(',') {
+        let (version, stat) = match entry.split_once('=') {
+            Some((version, stat)) => (version, Some(stat)),
+            None => (entry, None),
+        };
+        let version: u32 = match version.parse() {
+            Ok(version) => version,
+            Err(_) => continue,
+        };
+        versions.push(version);
+
+        let stat = stat.and_then(|stat| stat.split_once('@'));
+        if let Some((size, mtime)) = stat {
+            let stat = VersionStat {
+                size: size.parse().unwrap_or(0),
+                mtime: mtime.parse().unwrap_or(0),
+            };
+            namespace
+                .version_stats
+                .entry(String::from(name))
+                .or_default()
+                .insert(version, stat);
+        }
+    }
+    namespace
+        .files_versions
+        .insert(String::from(name), versions);
+}
+
+// Returns the record at the start of buf and its length on disk, or None if it is torn
+fn read_record(buf: &[u8]) -> Option<(u64, LogRecord, usize)> {
+    let header = buf.get(0..RECORD_HEADER_LENGTH)?;
+    let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let length = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let sum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    let payload = buf.get(RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + length)?;
+    if checksum(seq, payload) != sum {
+        return None;
+    }
+    let record = LogRecord::decode(payload)?;
+    Some((seq, record, RECORD_HEADER_LENGTH + length))
+}
+
+// FNV-1a over the sequence number and payload
+fn checksum(seq: u64, payload: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in seq.to_le_bytes().iter().chain(payload.iter()) {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+// Makes a rename durable by syncing the directory holding the file
+fn sync_parent(path: &str) -> io::Result<()> {
+    let parent = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
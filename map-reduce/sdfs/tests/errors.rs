@@ -0,0 +1,59 @@
+use sdfs::{read_frame, write_frame, Frame, SdfsError, STATUS_OK};
+
+use std::io::Cursor;
+
+fn round_trip(reply: Result<Vec<u8>, SdfsError>) -> Result<Vec<u8>, SdfsError> {
+    let mut wire: Vec<u8> = Vec::new();
+    write_frame(&mut wire, &Frame::reply(7, reply)).unwrap();
+    let frame = read_frame(Cursor::new(wire)).unwrap();
+    assert_eq!(frame.id, 7);
+    let mut payload = frame.into_reply()?;
+    let mut rest: Vec<u8> = Vec::new();
+    while let Ok(byte) = payload.u8() {
+        rest.push(byte);
+    }
+    Ok(rest)
+}
+
+#[test]
+fn every_error_survives_the_wire() {
+    let errors = vec![
+        SdfsError::NotFound(String::from("a")),
+        SdfsError::Locked(String::from("a")),
+        SdfsError::NoCapacity,
+        SdfsError::ChecksumMismatch(String::from("a.v1.b0")),
+        SdfsError::NotMaster,
+        SdfsError::NoQuorum(String::from("1 of 2 datanodes needed hold version 1")),
+        SdfsError::BadRequest(String::from("unknown message 99")),
+        SdfsError::Io(String::from("connection reset")),
+    ];
+    let mut statuses: Vec<u8> = Vec::new();
+    for err in errors {
+        assert_ne!(err.status(), STATUS_OK);
+        assert!(!statuses.contains(&err.status()));
+        statuses.push(err.status());
+
+        assert_eq!(round_trip(Err(err.clone())), Err(err));
+    }
+}
+
+#[test]
+fn successful_reply_leaves_its_payload() {
+    assert_eq!(round_trip(Ok(vec![3, 1, 2, 3])), Ok(vec![3, 1, 2, 3]));
+}
+
+#[test]
+fn unknown_status_is_a_bad_request() {
+    let frame = Frame {
+        id: 1,
+        kind: 200,
+        body: Vec::new(),
+    };
+    assert!(matches!(frame.into_reply(), Err(SdfsError::BadRequest(_))));
+
+    // A reply cut short is an I/O error, not a panic
+    assert!(matches!(
+        read_frame(Cursor::new(vec![5, 0, 0, 0, 0, 0, 0, 0, 1])),
+        Err(SdfsError::Io(_))
+    ));
+}
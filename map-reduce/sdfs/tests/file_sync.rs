@@ -0,0 +1,336 @@
+use sdfs::{block_name, FileState, FileSync, Metadata, Namespace, RequestType, LEASE_DURATION};
+
+use proptest::prelude::*;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Takes a lease on a file that lets it in right away, waiting on the given datanodes, as the
+// master does once it knows them
+fn take(file_sync: &mut FileSync, kind: RequestType, datanodes: Vec<u8>) -> u64 {
+    let lease = file_sync.request(kind).try_recv().unwrap();
+    assert!(file_sync.assign(lease, datanodes));
+    lease
+}
+
+#[test]
+fn write_lease_expires_when_client_disappears_mid_write() {
+    let mut file_sync = FileSync::new();
+    let lease = take(&mut file_sync, RequestType::Write, vec![1, 2, 3]);
+    let reader = file_sync.request(RequestType::Read);
+
+    // One block reaches its datanode, then the client is gone and the rest never arrive
+    file_sync.write_complete(1);
+    assert!(file_sync.expire_leases(Instant::now()).is_empty());
+    assert_eq!(reader.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(file_sync.state(), FileState::Write);
+
+    let expired = file_sync.expire_leases(Instant::now() + LEASE_DURATION);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].id, lease);
+    assert_eq!(expired[0].nodes, vec![2, 3]);
+    let read = reader.try_recv().unwrap();
+    assert_eq!(file_sync.state(), FileState::Read);
+    assert!(file_sync.release(read));
+
+    // Reports straggling in for the expired lease do not touch the next one
+    let next = take(&mut file_sync, RequestType::Write, vec![2]);
+    file_sync.write_complete(3);
+    assert_eq!(file_sync.ops(), 1);
+    assert!(!file_sync.renew(lease));
+    assert!(file_sync.renew(next));
+}
+
+#[test]
+fn renewed_lease_outlives_its_duration() {
+    let mut file_sync = FileSync::new();
+    let lease = take(&mut file_sync, RequestType::Write, vec![1]);
+    let granted = Instant::now();
+    let writer = file_sync.request(RequestType::Write);
+
+    thread::sleep(Duration::from_millis(10));
+    assert!(file_sync.renew(lease));
+    assert!(file_sync.expire_leases(granted + LEASE_DURATION).is_empty());
+    assert_eq!(writer.try_recv(), Err(TryRecvError::Empty));
+
+    assert_eq!(
+        file_sync
+            .expire_leases(Instant::now() + LEASE_DURATION)
+            .len(),
+        1
+    );
+    assert!(writer.try_recv().is_ok());
+}
+
+#[test]
+fn released_lease_lets_next_waiter_in() {
+    let mut file_sync = FileSync::new();
+    let lease = take(&mut file_sync, RequestType::Write, vec![1, 2]);
+    let first = file_sync.request(RequestType::Write);
+    let second = file_sync.request(RequestType::Write);
+
+    // The first waiter's client went away while queued, so it is skipped
+    drop(first);
+    file_sync.write_complete(1);
+    assert!(file_sync.release(lease));
+    assert!(!file_sync.release(lease));
+    assert!(second.try_recv().is_ok());
+    assert_eq!(file_sync.waiting(), 0);
+    assert_eq!(file_sync.leases.len(), 1);
+}
+
+#[test]
+fn last_reader_wakes_queued_writer() {
+    let mut file_sync = FileSync::new();
+    take(&mut file_sync, RequestType::Read, vec![1]);
+    take(&mut file_sync, RequestType::Read, vec![2]);
+    let writer = file_sync.request(RequestType::Write);
+
+    file_sync.read_complete(1);
+    assert_eq!(writer.try_recv(), Err(TryRecvError::Empty));
+    file_sync.read_complete(2);
+    assert!(writer.try_recv().is_ok());
+    assert_eq!(file_sync.state(), FileState::Write);
+}
+
+#[test]
+fn queued_readers_are_let_in_together() {
+    let mut file_sync = FileSync::with_limits(3, 8);
+    let lease = take(&mut file_sync, RequestType::Write, vec![1]);
+    let readers: Vec<Receiver<u64>> = (0..4)
+        .map(|_| file_sync.request(RequestType::Read))
+        .collect();
+
+    // Up to the limit go in as soon as the writer is done, the rest as room frees up
+    file_sync.write_complete(1);
+    assert!(!file_sync.release(lease));
+    let leases: Vec<u64> = readers[..3]
+        .iter()
+        .map(|reader| reader.try_recv().unwrap())
+        .collect();
+    assert_eq!(readers[3].try_recv(), Err(TryRecvError::Empty));
+    assert!(file_sync.release(leases[1]));
+    assert!(readers[3].try_recv().is_ok());
+}
+
+#[test]
+fn readers_pass_waiting_writer_a_bounded_number_of_times() {
+    let mut file_sync = FileSync::with_limits(10, 2);
+    let first = take(&mut file_sync, RequestType::Read, vec![1]);
+    let writer = file_sync.request(RequestType::Write);
+    let readers: Vec<Receiver<u64>> = (0..3)
+        .map(|_| file_sync.request(RequestType::Read))
+        .collect();
+
+    let passed: Vec<u64> = readers[..2]
+        .iter()
+        .map(|reader| reader.try_recv().unwrap())
+        .collect();
+    assert_eq!(readers[2].try_recv(), Err(TryRecvError::Empty));
+
+    // The writer goes next, then the reader held back
+    assert!(file_sync.release(first));
+    assert!(file_sync.release(passed[0]));
+    assert_eq!(writer.try_recv(), Err(TryRecvError::Empty));
+    assert!(file_sync.release(passed[1]));
+    let written = writer.try_recv().unwrap();
+    assert_eq!(readers[2].try_recv(), Err(TryRecvError::Empty));
+    assert!(file_sync.release(written));
+    assert!(readers[2].try_recv().is_ok());
+}
+
+#[test]
+fn copies_only_read_a_file_nobody_waits_on() {
+    let mut file_sync = FileSync::with_limits(2, 8);
+    let copy = file_sync.try_read(vec![1]).unwrap();
+    let read = take(&mut file_sync, RequestType::Read, vec![2]);
+    assert_eq!(file_sync.try_read(vec![3]), None);
+
+    assert!(file_sync.release(read));
+    let writer = file_sync.request(RequestType::Write);
+    assert_eq!(file_sync.try_read(vec![3]), None);
+    file_sync.read_complete(1);
+    assert!(!file_sync.release(copy));
+    assert!(writer.try_recv().is_ok());
+}
+
+#[test]
+fn master_expires_leases_of_every_file() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    let version = metadata.start_write("a", 1, vec![1]);
+    metadata
+        .files_sync
+        .insert(String::from("a"), FileSync::new());
+    metadata
+        .files_sync
+        .insert(String::from("b"), FileSync::new());
+    let written = take(
+        metadata.files_sync.get_mut("a").unwrap(),
+        RequestType::Write,
+        vec![1, 2],
+    );
+    let read = take(
+        metadata.files_sync.get_mut("b").unwrap(),
+        RequestType::Read,
+        vec![3],
+    );
+    let writer = metadata
+        .files_sync
+        .get_mut("b")
+        .unwrap()
+        .request(RequestType::Write);
+
+    // The client writing a crashed after one replica, and the one reading b never finished
+    metadata.add_replica(block_name("a", version, 0), 1);
+    metadata.files_sync.get_mut("a").unwrap().write_complete(1);
+    assert!(metadata.renew_lease("a", written));
+
+    let mut expired: Vec<(String, u64)> = metadata
+        .expire_leases(Instant::now() + LEASE_DURATION)
+        .into_iter()
+        .map(|(filename, lease)| (filename, lease.id))
+        .collect();
+    expired.sort();
+    assert_eq!(
+        expired,
+        vec![(String::from("a"), written), (String::from("b"), read)]
+    );
+    assert!(writer.try_recv().is_ok());
+    assert!(!metadata.release_lease("a", written));
+
+    // The uncommitted version never became visible
+    assert_eq!(metadata.latest_version("a"), 0);
+}
+
+// A step of a workload on one file
+#[derive(Debug, Clone)]
+enum Step {
+    Request(RequestType),
+    // Release the nth lease held
+    Release(usize),
+    // The nth waiting client goes away
+    GiveUp(usize),
+    ExpireAll,
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    prop_oneof![
+        3 => Just(Step::Request(RequestType::Read)),
+        2 => Just(Step::Request(RequestType::Write)),
+        3 => any::<usize>().prop_map(Step::Release),
+        1 => any::<usize>().prop_map(Step::GiveUp),
+        1 => Just(Step::ExpireAll),
+    ]
+}
+
+// Drives a FileSync like the master's request handlers, recording the order requests
+// arrive in and are let in
+struct Workload {
+    file_sync: FileSync,
+    arrivals: usize,
+    waiting: Vec<(usize, RequestType, Receiver<u64>)>,
+    held: Vec<(usize, RequestType, u64)>,
+    // (arrival, kind) of each request let in, in order
+    granted: Vec<(usize, RequestType)>,
+}
+
+impl Workload {
+    fn run(&mut self, step: Step) {
+        match step {
+            Step::Request(kind) => {
+                let rx = self.file_sync.request(kind);
+                self.waiting.push((self.arrivals, kind, rx));
+                self.arrivals += 1;
+            }
+            Step::Release(n) if !self.held.is_empty() => {
+                let (_, _, lease) = self.held.remove(n % self.held.len());
+                assert!(self.file_sync.release(lease));
+            }
+            Step::GiveUp(n) if !self.waiting.is_empty() => {
+                self.waiting.remove(n % self.waiting.len());
+            }
+            Step::ExpireAll => {
+                let expired = self
+                    .file_sync
+                    .expire_leases(Instant::now() + LEASE_DURATION);
+                assert_eq!(expired.len(), self.held.len());
+                self.held.clear();
+            }
+            _ => {}
+        }
+        self.collect();
+    }
+
+    // Picks up the leases of the requests let in
+    fn collect(&mut self) {
+        let mut still_waiting = Vec::new();
+        for (arrival, kind, rx) in self.waiting.drain(..) {
+            match rx.try_recv() {
+                Ok(lease) => {
+                    self.held.push((arrival, kind, lease));
+                    self.granted.push((arrival, kind));
+                }
+                Err(_) => still_waiting.push((arrival, kind, rx)),
+            }
+        }
+        self.waiting = still_waiting;
+    }
+
+    fn check_exclusion(&self, max_readers: usize) {
+        let writers = self
+            .held
+            .iter()
+            .filter(|(_, kind, _)| *kind == RequestType::Write)
+            .count();
+        let readers = self.held.len() - writers;
+        assert!(writers == 0 || (writers == 1 && readers == 0));
+        assert!(readers <= max_readers);
+        assert_eq!(self.file_sync.leases.len(), self.held.len());
+    }
+}
+
+proptest! {
+    #[test]
+    fn scheduler_is_exclusive_and_starvation_free(
+        max_readers in 1usize..5,
+        max_readers_before_writer in 0usize..4,
+        steps in proptest::collection::vec(step(), 1..80),
+    ) {
+        let mut workload = Workload {
+            file_sync: FileSync::with_limits(max_readers, max_readers_before_writer),
+            arrivals: 0,
+            waiting: Vec::new(),
+            held: Vec::new(),
+            granted: Vec::new(),
+        };
+        for step in steps {
+            workload.run(step);
+            workload.check_exclusion(max_readers);
+        }
+
+        // Once holders finish, everyone still waiting gets in, at least one each round
+        let mut rounds = 0;
+        while !workload.waiting.is_empty() {
+            prop_assert!(rounds <= workload.arrivals);
+            for (_, _, lease) in workload.held.drain(..) {
+                prop_assert!(workload.file_sync.release(lease));
+            }
+            workload.collect();
+            workload.check_exclusion(max_readers);
+            rounds += 1;
+        }
+
+        // Between a writer and the one before it, at most max_readers_before_writer readers
+        // that arrived after it went ahead of it
+        let mut since_writer: Vec<usize> = Vec::new();
+        for (arrival, kind) in workload.granted.iter() {
+            if *kind == RequestType::Write {
+                let passed = since_writer.iter().filter(|reader| *reader > arrival).count();
+                prop_assert!(passed <= max_readers_before_writer);
+                since_writer.clear();
+            } else {
+                since_writer.push(*arrival);
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use sdfs::{
+    role_status, Member, NODE_ALIVE, NODE_DOWN, NODE_SUSPECTED, ROLE_DATANODE, ROLE_MASTER, VM_LIST,
+};
+
+use std::collections::BTreeMap;
+
+fn member(vm: usize, incarnation: u64, status: u8, roles: &str) -> Member {
+    let mut metadata = BTreeMap::new();
+    metadata.insert(String::from("roles"), String::from(roles));
+    Member {
+        id: format!("{}:50001:{}:00000001", VM_LIST[vm - 1], incarnation),
+        hostname: String::from(VM_LIST[vm - 1]),
+        port: 50001,
+        status,
+        metadata,
+    }
+}
+
+#[test]
+fn restarted_datanode_counts_as_alive_in_any_order() {
+    // The failed entry from before the restart is still gossiped next to the new one
+    let mut members = vec![
+        member(2, 1700000000, 1, ROLE_DATANODE),
+        member(2, 1700000100, 0, ROLE_DATANODE),
+        member(3, 1700000000, 2, ROLE_DATANODE),
+        member(3, 1700000100, 1, ROLE_DATANODE),
+        member(4, 1700000000, 0, ROLE_MASTER),
+        member(5, 1700000000, 1, ROLE_MASTER),
+        member(5, 1700000100, 2, ROLE_MASTER),
+    ];
+    for _ in 0..2 {
+        let status = role_status(&members, ROLE_DATANODE);
+        assert_eq!(status.len(), VM_LIST.len());
+        assert_eq!(status[0], NODE_DOWN);
+        assert_eq!(status[1], NODE_ALIVE);
+        assert_eq!(status[2], NODE_SUSPECTED);
+        assert_eq!(status[3], NODE_DOWN);
+
+        // Masters are elected and sent the log on the same view
+        let masters = role_status(&members, ROLE_MASTER);
+        assert_eq!(masters[3], NODE_ALIVE);
+        assert_eq!(masters[4], NODE_SUSPECTED);
+        members.reverse();
+    }
+}
@@ -0,0 +1,668 @@
+use sdfs::{
+    block_checksum, block_name, check_block_quorums, versioned_filename, BlockLocation,
+    ChecksumWriter, DatanodeWatch, LogRecord, Metadata, MetadataLog, Namespace, Reconciliation,
+    RequestType, SdfsError, StoredBlock, BLOCK_SIZE, MAX_VERSIONS, NODE_ALIVE, NODE_DOWN,
+    READ_QUORUM, VM_LIST, WRITE_QUORUM,
+};
+
+use rand::Rng;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Set for the copy of this binary that writes the log until it is killed
+const CHILD_DIR_VAR: &str = "SDFS_WAL_CHILD_DIR";
+
+// Change number seq of a workload that keeps adding replicas and versions, and occasionally
+// loses a node or replica, deletes a file, makes a directory or moves a file into one
+fn record_for(seq: u64) -> LogRecord {
+    let node = (seq % 10 + 1) as u8;
+    match (seq % 7, seq % 11, seq % 3) {
+        (0, _, _) => LogRecord::RemoveDatanode { node },
+        (_, _, _) if seq.is_multiple_of(13) => LogRecord::RemoveReplica {
+            block: block_name(&format!("file{}", seq % 50), (seq - 1) as u32, 0),
+            node,
+        },
+        (_, _, _) if seq.is_multiple_of(17) => LogRecord::Rename {
+            from: format!("file{}", seq % 50),
+            to: format!("dir{}/file{}", seq % 5, seq),
+        },
+        (_, _, _) if seq.is_multiple_of(19) => LogRecord::MakeDir {
+            path: format!("dir{}/sub{}", seq % 5, seq % 3),
+        },
+        (_, 0, _) => LogRecord::RemoveFile {
+            filename: format!("file{}", seq % 50),
+        },
+        (_, _, 0) => LogRecord::AddVersion {
+            filename: format!("file{}", seq % 50),
+            version: seq as u32,
+            checksums: (0..seq % 4 + 1).map(|index| (seq * index) as u32).collect(),
+            size: seq * 100,
+            mtime: 1_700_000_000 + seq,
+        },
+        _ => LogRecord::AddReplica {
+            block: block_name(&format!("file{}", seq % 50), seq as u32, (seq % 4) as u32),
+            node,
+        },
+    }
+}
+
+// Namespace after the first n changes of the workload
+fn expected_after(n: u64) -> Namespace {
+    let mut namespace = Namespace::default();
+    for seq in 1..=n {
+        record_for(seq).apply(&mut namespace);
+    }
+    namespace
+}
+
+// Fresh directory holding a checkpoint and log, returned as paths
+fn temp_paths(name: &str) -> (PathBuf, String, String) {
+    let dir = env::temp_dir().join(format!("sdfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let checkpoint_path = dir.join("metadata").to_str().unwrap().to_string();
+    let log_path = dir.join("metadata.log").to_str().unwrap().to_string();
+    (dir, checkpoint_path, log_path)
+}
+
+#[test]
+fn recovers_log_and_checkpoint() {
+    let (dir, checkpoint_path, log_path) = temp_paths("recover");
+
+    let (mut log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(namespace, Namespace::default());
+    for seq in 1..=40 {
+        assert_eq!(log.append(&record_for(seq)).unwrap(), seq);
+        if seq == 25 {
+            log.checkpoint(&expected_after(25)).unwrap();
+        }
+    }
+    drop(log);
+
+    let (log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(log.seq(), 40);
+    assert_eq!(namespace, expected_after(40));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn torn_record_is_dropped_at_every_offset() {
+    let (dir, checkpoint_path, log_path) = temp_paths("torn");
+
+    // Note where each record ends
+    let (mut log, _) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    let mut record_ends: Vec<u64> = Vec::new();
+    for seq in 1..=20 {
+        log.append(&record_for(seq)).unwrap();
+        record_ends.push(fs::metadata(&log_path).unwrap().len());
+    }
+    drop(log);
+    let data = fs::read(&log_path).unwrap();
+
+    // A crash can leave any prefix of the log on disk
+    for length in 0..=data.len() {
+        fs::write(&log_path, &data[..length]).unwrap();
+        let complete = record_ends
+            .iter()
+            .filter(|end| **end as usize <= length)
+            .count() as u64;
+
+        let (mut log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+        assert_eq!(log.seq(), complete, "log cut at byte {}", length);
+        assert_eq!(namespace, expected_after(complete));
+
+        // Appending after recovery must not leave the torn bytes behind
+        log.append(&record_for(complete + 1)).unwrap();
+        drop(log);
+        let (log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+        assert_eq!(log.seq(), complete + 1);
+        assert_eq!(namespace, expected_after(complete + 1));
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupt_record_ends_the_log() {
+    let (dir, checkpoint_path, log_path) = temp_paths("corrupt");
+
+    let (mut log, _) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    for seq in 1..=10 {
+        log.append(&record_for(seq)).unwrap();
+    }
+    drop(log);
+
+    let mut data = fs::read(&log_path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&log_path, &data).unwrap();
+
+    let (log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(log.seq(), 9);
+    assert_eq!(namespace, expected_after(9));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn crash_between_checkpoint_and_log_truncation() {
+    let (dir, checkpoint_path, log_path) = temp_paths("checkpoint");
+
+    let (mut log, _) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    for seq in 1..=30 {
+        log.append(&record_for(seq)).unwrap();
+    }
+    // The checkpoint is in place but the log still holds everything it covers
+    let stale_log = fs::read(&log_path).unwrap();
+    log.checkpoint(&expected_after(30)).unwrap();
+    drop(log);
+    fs::write(&log_path, &stale_log).unwrap();
+
+    let (mut log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(log.seq(), 30);
+    assert_eq!(namespace, expected_after(30));
+
+    // A crash in the middle of writing the next checkpoint leaves the old one in use
+    fs::write(checkpoint_path.clone() + ".tmp", "@99\nfile1:").unwrap();
+    for seq in 31..=35 {
+        log.append(&record_for(seq)).unwrap();
+    }
+    drop(log);
+    let (log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(log.seq(), 35);
+    assert_eq!(namespace, expected_after(35));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn standby_follows_master_log() {
+    let (master_dir, master_checkpoint, master_log) = temp_paths("master");
+    let (standby_dir, standby_checkpoint, standby_log) = temp_paths("standby");
+
+    let mut master = Metadata::recover(&master_checkpoint, &master_log);
+    let (tx, rx) = mpsc::channel();
+    master.log.as_mut().unwrap().set_follower(tx);
+    let mut standby = Metadata::recover(&standby_checkpoint, &standby_log);
+
+    master.add_replica(block_name("a", 1, 0), 1);
+    master.add_replica(block_name("a", 1, 0), 2);
+    master.add_replica(block_name("b", 1, 0), 3);
+    master.add_version(String::from("a"), 1, vec![7], 10);
+    for (seq, record) in rx.try_iter() {
+        assert!(standby.apply_replicated(seq, record));
+    }
+    assert_eq!(standby.namespace, master.namespace);
+    assert_eq!(standby.datanode_usage[&2], vec![block_name("a", 1, 0)]);
+
+    // A standby that missed a change asks for a snapshot
+    master.remove_datanode(1);
+    master.add_replica(block_name("c", 1, 0), 4);
+    let mut shipped = rx.try_iter();
+    shipped.next();
+    let (seq, record) = shipped.next().unwrap();
+    assert!(!standby.apply_replicated(seq, record));
+    standby.install_snapshot(master.log_seq(), master.namespace.clone());
+    assert_eq!(standby.log_seq(), master.log_seq());
+    assert_eq!(standby.namespace, master.namespace);
+
+    // and what it installed survives a restart
+    drop(standby);
+    let standby = Metadata::recover(&standby_checkpoint, &standby_log);
+    assert_eq!(standby.log_seq(), 6);
+    assert_eq!(standby.latest_version("a"), 1);
+    assert_eq!(standby.namespace, master.namespace);
+
+    fs::remove_dir_all(master_dir).unwrap();
+    fs::remove_dir_all(standby_dir).unwrap();
+}
+
+#[test]
+fn standby_leaves_datanode_failures_to_the_master() {
+    let (master_dir, master_checkpoint, master_log) = temp_paths("failure-master");
+    let (standby_dir, standby_checkpoint, standby_log) = temp_paths("failure-standby");
+
+    let mut master = Metadata::recover(&master_checkpoint, &master_log);
+    let (tx, rx) = mpsc::channel();
+    master.log.as_mut().unwrap().set_follower(tx);
+    let mut standby = Metadata::recover(&standby_checkpoint, &standby_log);
+    let mut master_watch = DatanodeWatch::default();
+    let mut standby_watch = DatanodeWatch::default();
+
+    let mut status = vec![NODE_ALIVE; VM_LIST.len()];
+    for node in 1..=3 {
+        master.add_replica(block_name("a", 1, 0), node);
+    }
+    assert!(master_watch.update(&master, &status, true).is_empty());
+    assert!(standby_watch.update(&standby, &status, false).is_empty());
+
+    // Both see datanode 2 fail while the standby follows, but only the master records it
+    status[1] = NODE_DOWN;
+    assert!(standby_watch.update(&standby, &status, false).is_empty());
+    assert_eq!(master_watch.update(&master, &status, true), vec![2]);
+    master.remove_datanode(2);
+    master.add_replica(block_name("a", 1, 0), 4);
+    for (seq, record) in rx.try_iter() {
+        assert!(standby.apply_replicated(seq, record));
+    }
+    assert_eq!(standby.log_seq(), master.log_seq());
+    assert_eq!(standby.namespace, master.namespace);
+
+    // Datanode 3 fails along with the master, so the standby records it on taking over
+    status[2] = NODE_DOWN;
+    assert!(standby_watch.update(&standby, &status, false).is_empty());
+    assert_eq!(standby_watch.update(&standby, &status, true), vec![3]);
+    standby.remove_datanode(3);
+    assert_eq!(
+        standby.namespace.blocks[&block_name("a", 1, 0)].nodes,
+        vec![1, 4]
+    );
+
+    fs::remove_dir_all(master_dir).unwrap();
+    fs::remove_dir_all(standby_dir).unwrap();
+}
+
+#[test]
+fn keeps_newest_versions() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    assert_eq!(metadata.latest_version("a"), 0);
+
+    for version in 1..=(MAX_VERSIONS as u32 + 3) {
+        metadata.add_replica(block_name("a", version, 0), 1);
+        metadata.add_version(String::from("a"), version, vec![0], 0);
+    }
+    let newest: Vec<u32> = (4..=(MAX_VERSIONS as u32 + 3)).rev().collect();
+    assert_eq!(metadata.latest_version("a"), MAX_VERSIONS as u32 + 3);
+    assert_eq!(metadata.get_versions("a", 100), newest);
+    assert_eq!(metadata.get_versions("a", 2), newest[..2].to_vec());
+
+    // Blocks of versions no longer kept are dropped with them
+    assert!(metadata.get_blocks("a", 3).is_empty());
+    assert_eq!(metadata.get_blocks("a", 4).len(), 1);
+
+    metadata.remove_file("a");
+    assert_eq!(metadata.latest_version("a"), 0);
+    assert!(metadata.get_versions("a", 100).is_empty());
+}
+
+#[test]
+fn version_added_once_committed_by_write_quorum() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    let version = metadata.start_write("a", 3, vec![1, 2, 3]);
+    let quorum: Vec<u8> = (1..=WRITE_QUORUM).collect();
+
+    // Datanodes report blocks as they store them, which does not add the version
+    metadata.add_replica(block_name("a", version, 0), 1);
+    assert_eq!(metadata.latest_version("a"), 0);
+
+    // Overlapping puts each get their own version
+    let other = metadata.start_write("a", 1, vec![4]);
+    assert_eq!(other, version + 1);
+
+    let stored = vec![quorum.clone(), quorum.clone(), quorum.clone()];
+    assert_eq!(metadata.commit_write("a", version, stored.clone()), Ok(()));
+    assert_eq!(metadata.latest_version("a"), version);
+    let blocks = metadata.get_blocks("a", version);
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(
+        blocks[1],
+        BlockLocation {
+            block: block_name("a", version, 1),
+            checksum: 2,
+            nodes: quorum.clone(),
+        }
+    );
+    // A put is only committed once
+    assert_eq!(
+        metadata.commit_write("a", version, stored),
+        Err(SdfsError::NotFound(versioned_filename("a", version)))
+    );
+
+    // Too few datanodes confirmed a block, so the put fails but still takes up its version
+    let mut short = quorum.clone();
+    short.pop();
+    assert!(matches!(
+        metadata.commit_write("a", other, vec![short]),
+        Err(SdfsError::NoQuorum(_))
+    ));
+    assert_eq!(metadata.latest_version("a"), version);
+    assert_eq!(metadata.next_version("a"), other + 1);
+}
+
+#[test]
+fn streamed_put_adds_blocks_as_they_fill() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    let version = metadata.start_write("a", 0, Vec::new());
+    let lease = metadata
+        .files_sync
+        .entry(String::from("a"))
+        .or_default()
+        .request(RequestType::Write)
+        .recv()
+        .unwrap();
+    let quorum: Vec<u8> = (1..=WRITE_QUORUM).collect();
+
+    assert_eq!(
+        metadata.add_block("a", version, lease, BLOCK_SIZE, 7),
+        Ok(0)
+    );
+    assert_eq!(metadata.add_block("a", version, lease, 10, 8), Ok(1));
+    // Only the last block may be short, and only the put's lease adds to it
+    assert!(matches!(
+        metadata.add_block("a", version, lease, 10, 9),
+        Err(SdfsError::BadRequest(_))
+    ));
+    assert_eq!(
+        metadata.add_block("a", version, lease + 1, BLOCK_SIZE, 9),
+        Err(SdfsError::Locked(String::from("a")))
+    );
+    assert_eq!(
+        metadata.add_block("a", version + 1, lease, BLOCK_SIZE, 9),
+        Err(SdfsError::NotFound(versioned_filename("a", version + 1)))
+    );
+
+    let stored = vec![quorum.clone(), quorum.clone()];
+    assert_eq!(metadata.commit_write("a", version, stored), Ok(()));
+    let blocks = metadata.get_blocks("a", version);
+    assert_eq!(
+        blocks
+            .iter()
+            .map(|block| block.checksum)
+            .collect::<Vec<u32>>(),
+        vec![7, 8]
+    );
+    assert_eq!(
+        metadata.namespace.version_stats["a"][&version].size,
+        BLOCK_SIZE + 10
+    );
+}
+
+#[test]
+fn corrupt_replica_is_dropped() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    let block = block_name("a", 1, 0);
+    let pending = block_name("a", 2, 0);
+    for node in 1..=3 {
+        metadata.add_replica(block.clone(), node);
+    }
+    metadata.add_version(String::from("a"), 1, vec![7], 10);
+    metadata.add_replica(pending.clone(), 1);
+
+    // Only replicas of added versions that do not match are dropped
+    let corrupt = metadata.check_replicas(1, vec![(block.clone(), 8), (pending, 9)]);
+    assert_eq!(corrupt, vec![block.clone()]);
+    assert!(metadata
+        .check_replicas(2, vec![(block.clone(), 7)])
+        .is_empty());
+    assert!(metadata
+        .check_replicas(4, vec![(block.clone(), 8)])
+        .is_empty());
+    assert_eq!(metadata.namespace.blocks[&block].nodes, vec![2, 3]);
+    assert!(!metadata.datanode_usage[&1].contains(&block));
+    assert_eq!(metadata.get_under_replicated(), vec![(block, 2)]);
+}
+
+fn stored(block: &str, size: u64, checksum: u32) -> StoredBlock {
+    StoredBlock {
+        block: String::from(block),
+        size,
+        checksum,
+    }
+}
+
+#[test]
+fn block_report_reconciles_replicas() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    let first = block_name("a", 1, 0);
+    let last = block_name("a", 1, 1);
+    metadata.add_replica(first.clone(), 1);
+    metadata.add_replica(last.clone(), 1);
+    metadata.add_replica(first.clone(), 2);
+    metadata.add_version(String::from("a"), 1, vec![7, 8], BLOCK_SIZE + 10);
+    let pending = block_name("p", 1, 0);
+    metadata.start_write("p", 1, vec![9]);
+    let deleted = block_name("gone", 1, 0);
+
+    // Datanode 1 lost the last block, and holds blocks of a put in progress and a deleted file
+    let reconciled = metadata.reconcile_block_report(
+        1,
+        vec![
+            stored(&first, BLOCK_SIZE, 7),
+            stored(&pending, 1, 9),
+            stored(&deleted, 1, 1),
+        ],
+        Duration::ZERO,
+    );
+    assert_eq!(
+        reconciled,
+        Reconciliation {
+            missing: vec![last.clone()],
+            orphaned: vec![deleted],
+            ..Reconciliation::default()
+        }
+    );
+    assert!(metadata.namespace.blocks[&last].nodes.is_empty());
+
+    // Datanode 2's copy is cut short, datanode 3 holds an unrecorded copy of each block
+    let reconciled = metadata.reconcile_block_report(2, vec![stored(&first, 5, 7)], Duration::ZERO);
+    assert_eq!(reconciled.corrupt, vec![first.clone()]);
+    let reconciled = metadata.reconcile_block_report(
+        3,
+        vec![stored(&first, BLOCK_SIZE, 6), stored(&last, 10, 8)],
+        Duration::ZERO,
+    );
+    assert_eq!(reconciled.found, vec![last.clone()]);
+    assert_eq!(reconciled.corrupt, vec![first.clone()]);
+    assert_eq!(metadata.namespace.blocks[&first].nodes, vec![1]);
+    assert_eq!(metadata.namespace.blocks[&last].nodes, vec![3]);
+
+    // Nothing is decided about a file in use
+    let _lease = metadata
+        .files_sync
+        .get_mut("a")
+        .unwrap()
+        .request(RequestType::Read);
+    assert_eq!(
+        metadata.reconcile_block_report(1, Vec::new(), Duration::ZERO),
+        Reconciliation::default()
+    );
+    assert_eq!(metadata.namespace.blocks[&first].nodes, vec![1]);
+}
+
+#[test]
+fn block_report_leaves_replicas_added_during_the_scan() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    let old = block_name("a", 1, 0);
+    metadata.add_replica(old.clone(), 1);
+    metadata.add_version(String::from("a"), 1, vec![7], 10);
+    thread::sleep(Duration::from_millis(200));
+
+    // The datanode starts listing its blocks, then a put commits a block to it
+    let scan_started = Instant::now();
+    let new = block_name("b", 1, 0);
+    metadata.add_replica(new.clone(), 1);
+    metadata.add_version(String::from("b"), 1, vec![8], 10);
+
+    // Neither block made the listing, only the one added before the scan is missing
+    let reconciled = metadata.reconcile_block_report(1, Vec::new(), scan_started.elapsed());
+    assert_eq!(reconciled.missing, vec![old.clone()]);
+    assert_eq!(metadata.namespace.blocks[&new].nodes, vec![1]);
+
+    // The next report, scanned after the commit, settles it
+    let reconciled = metadata.reconcile_block_report(1, Vec::new(), Duration::ZERO);
+    assert_eq!(reconciled.missing, vec![new.clone()]);
+    assert!(metadata.namespace.blocks[&new].nodes.is_empty());
+}
+
+#[test]
+fn read_quorum_is_checked_per_block() {
+    let first = BlockLocation {
+        block: block_name("a", 1, 0),
+        checksum: 1,
+        nodes: vec![1, 2, 3, 4],
+    };
+    let last = BlockLocation {
+        block: block_name("a", 1, 1),
+        checksum: 2,
+        nodes: vec![5, 6, 7, 8],
+    };
+    // Datanodes 1 and 5 each hold one block of the version, and 6 fails to answer
+    let held = |node: u8| match node {
+        1 => Ok(vec![first.block.clone()]),
+        5 => Ok(vec![last.block.clone()]),
+        6 => Err(SdfsError::Io(String::from("unreachable"))),
+        _ => Ok(vec![block_name("a", 2, 0)]),
+    };
+    let blocks = vec![first.clone(), last.clone()];
+    assert_eq!(
+        check_block_quorums(&blocks, held),
+        Err(SdfsError::NoQuorum(format!(
+            "1 of {} datanodes needed hold {}",
+            READ_QUORUM, first.block
+        )))
+    );
+
+    // Two holders of each block make a quorum, and each datanode is asked once
+    let mut asked: Vec<u8> = Vec::new();
+    let held = |node: u8| {
+        asked.push(node);
+        match node {
+            1 | 2 => Ok(vec![first.block.clone(), last.block.clone()]),
+            5 | 7 => Ok(vec![last.block.clone()]),
+            _ => Ok(Vec::new()),
+        }
+    };
+    let blocks = vec![first.clone(), last.clone(), first.clone()];
+    assert_eq!(check_block_quorums(&blocks, held), Ok(()));
+    assert_eq!(asked, vec![1, 2, 5, 6, 7]);
+}
+
+#[test]
+fn checksum_writer_matches_block_checksum() {
+    let data: Vec<u8> = (0..10000).map(|i| (i * 7 % 251) as u8).collect();
+    let mut writer = ChecksumWriter::new(Vec::new());
+    for chunk in data.chunks(999) {
+        writer.write_all(chunk).unwrap();
+    }
+    assert_eq!(writer.written(), data.len() as u64);
+    assert_eq!(writer.checksum(), block_checksum(&data[..]).unwrap());
+
+    let mut flipped = data.clone();
+    flipped[5000] ^= 1;
+    assert_ne!(block_checksum(&flipped[..]).unwrap(), writer.checksum());
+}
+
+#[test]
+fn blocks_are_spread_over_datanodes() {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    metadata.add_replica(block_name("a", 1, 0), 1);
+    let membership = vec![NODE_ALIVE; 4];
+
+    let placement = metadata.place_blocks(6, 2, membership);
+    assert_eq!(placement.len(), 6);
+    let mut load = [1, 0, 0, 0];
+    for nodes in placement.iter() {
+        assert_eq!(nodes.len(), 2);
+        assert_ne!(nodes[0], nodes[1]);
+        for node in nodes {
+            load[*node as usize - 1] += 1;
+        }
+    }
+    // Counting the block node 1 already stores, no node ends up with more than one extra
+    assert!(load.iter().max().unwrap() - load.iter().min().unwrap() <= 1);
+}
+
+// Run by the parent test below in a separate process: continues the workload from
+// whatever it recovers, reporting every change once it is durable
+#[test]
+fn log_writer_child() {
+    let dir = match env::var(CHILD_DIR_VAR) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return,
+    };
+    let checkpoint_path = dir.join("metadata").to_str().unwrap().to_string();
+    let log_path = dir.join("metadata.log").to_str().unwrap().to_string();
+
+    let (mut log, mut namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    let stdout = std::io::stdout();
+    let mut seq = log.seq();
+    loop {
+        seq += 1;
+        let record = record_for(seq);
+        log.append(&record).unwrap();
+        record.apply(&mut namespace);
+        if seq % 25 == 0 {
+            log.checkpoint(&namespace).unwrap();
+        }
+        let mut out = stdout.lock();
+        writeln!(out, "ack {}", seq).unwrap();
+        out.flush().unwrap();
+    }
+}
+
+#[test]
+fn survives_being_killed_at_arbitrary_points() {
+    let (dir, checkpoint_path, log_path) = temp_paths("kill");
+    let mut rng = rand::thread_rng();
+
+    let mut recovered: u64 = 0;
+    for _ in 0..15 {
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "log_writer_child", "--nocapture"])
+            .env(CHILD_DIR_VAR, &dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        if let Some(seq) = line.strip_prefix("ack ") {
+                            let _ = tx.send(seq.parse::<u64>().unwrap());
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        // Let it get a little past where it started, then kill it without warning
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut acked = recovered;
+        while acked == recovered && Instant::now() < deadline {
+            if let Ok(seq) = rx.recv_timeout(Duration::from_millis(100)) {
+                acked = seq;
+            }
+        }
+        thread::sleep(Duration::from_millis(rng.gen_range(0..30)));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        reader.join().unwrap();
+        acked = rx.try_iter().last().unwrap_or(acked);
+        assert!(acked > recovered, "child made no progress");
+
+        // Everything acknowledged survives, and nothing but a prefix of the workload does
+        let (log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+        assert!(
+            log.seq() >= acked,
+            "lost change {} of {}",
+            log.seq() + 1,
+            acked
+        );
+        assert_eq!(namespace, expected_after(log.seq()));
+        recovered = log.seq();
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,237 @@
+use sdfs::{
+    block_name, block_path, glob_match, glob_prefix, normalize_path, stored_block_name, DirEntry,
+    LogRecord, Metadata, MetadataLog, Namespace, SdfsError, FILES_PATH,
+};
+
+use std::env;
+use std::fs;
+use std::process;
+
+// Metadata holding a committed version of each file, stored on datanodes 1 and 2
+fn with_files(files: &[(&str, u64)]) -> Metadata {
+    let mut metadata = Metadata::from_namespace(Namespace::default());
+    for (filename, size) in files {
+        let version = metadata.start_write(filename, *size, vec![7, 8]);
+        let stored = vec![vec![1, 2, 3], vec![1, 2, 3]];
+        metadata.commit_write(filename, version, stored).unwrap();
+    }
+    metadata
+}
+
+fn names(entries: &[DirEntry]) -> Vec<(&str, bool)> {
+    entries
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.is_dir))
+        .collect()
+}
+
+#[test]
+fn paths_are_normalized() {
+    assert_eq!(normalize_path("/a//b/c/"), Ok(String::from("a/b/c")));
+    assert_eq!(normalize_path("plain"), Ok(String::from("plain")));
+    assert_eq!(normalize_path("/"), Ok(String::new()));
+    assert!(matches!(
+        normalize_path("a/../b"),
+        Err(SdfsError::BadRequest(_))
+    ));
+    assert!(matches!(
+        normalize_path("./a"),
+        Err(SdfsError::BadRequest(_))
+    ));
+}
+
+#[test]
+fn globs_stay_within_a_directory() {
+    assert!(glob_match("job/*", "job/key1"));
+    assert!(glob_match("job_?_*", "job_3_apple"));
+    assert!(glob_match("*.txt", "notes.txt"));
+    assert!(!glob_match("job/*", "job/sub/key1"));
+    assert!(!glob_match("*", "a/b"));
+    assert!(!glob_match("job_?", "job_12"));
+    assert!(glob_match("", ""));
+
+    assert_eq!(glob_prefix("job/k*y?"), "job/k");
+    assert_eq!(glob_prefix("plain"), "plain");
+}
+
+#[test]
+fn block_paths_round_trip() {
+    for block in [
+        block_name("a/b/c", 2, 0),
+        block_name("100%/x", 1, 3),
+        block_name("%2F", 1, 0),
+    ] {
+        let path = block_path(&block);
+        let file_name = path.strip_prefix(FILES_PATH).unwrap();
+        assert!(!file_name.contains('/'));
+        assert_eq!(stored_block_name(file_name), block);
+    }
+}
+
+#[test]
+fn directories_list_their_children() {
+    let mut metadata = with_files(&[("top", 5), ("job/a", 10), ("job/b", 20), ("job/w/1/a", 1)]);
+    metadata.mkdir("job/empty").unwrap();
+
+    let root = metadata.list_dir("").unwrap();
+    assert_eq!(names(&root), vec![("job", true), ("top", false)]);
+
+    let job = metadata.list_dir("job").unwrap();
+    assert_eq!(
+        names(&job),
+        vec![("a", false), ("b", false), ("empty", true), ("w", true)]
+    );
+    assert_eq!(job[1].size, 20);
+    assert_eq!(job[1].version, 1);
+    assert_eq!(job[1].replicas, 3);
+    assert!(job[1].mtime > 0);
+    assert!(metadata.list_dir("job/empty").unwrap().is_empty());
+
+    assert!(matches!(
+        metadata.list_dir("top"),
+        Err(SdfsError::NotFound(_))
+    ));
+    assert!(matches!(
+        metadata.list_dir("nowhere"),
+        Err(SdfsError::NotFound(_))
+    ));
+}
+
+#[test]
+fn files_and_directories_do_not_overlap() {
+    let mut metadata = with_files(&[("a", 1), ("d/f", 1)]);
+
+    // A file cannot be under a file, nor a directory where a file is
+    assert!(matches!(
+        metadata.check_new_path("a/b"),
+        Err(SdfsError::BadRequest(_))
+    ));
+    assert!(matches!(metadata.mkdir("a"), Err(SdfsError::BadRequest(_))));
+    assert!(matches!(metadata.mkdir(""), Err(SdfsError::BadRequest(_))));
+    assert_eq!(metadata.mkdir("d"), Ok(()));
+
+    // Only an empty directory is deleted
+    assert!(matches!(
+        metadata.remove_dir("d"),
+        Err(SdfsError::BadRequest(_))
+    ));
+    metadata.mkdir("e/f").unwrap();
+    assert!(matches!(
+        metadata.remove_dir("e"),
+        Err(SdfsError::BadRequest(_))
+    ));
+    assert_eq!(metadata.remove_dir("e/f"), Ok(()));
+    assert!(!metadata.namespace.is_dir("e"));
+}
+
+#[test]
+fn files_are_listed_by_prefix_and_glob() {
+    let metadata = with_files(&[("job/a", 1), ("job/b", 1), ("job_1_a", 1), ("jobs/c", 1)]);
+
+    assert_eq!(metadata.list_files("", "").len(), 4);
+    assert_eq!(metadata.list_files("job/", ""), vec!["job/a", "job/b"]);
+    assert_eq!(metadata.list_files("job", "job_*"), vec!["job_1_a"]);
+    assert_eq!(
+        metadata.list_files("", "*/?"),
+        vec!["job/a", "job/b", "jobs/c"]
+    );
+}
+
+#[test]
+fn rename_moves_files_with_their_blocks() {
+    let mut metadata = with_files(&[("job/a", 10), ("job/sub/b", 20), ("other", 1)]);
+    metadata.mkdir("job/empty").unwrap();
+    let stat_before = metadata.namespace.version_stats["job/a"].clone();
+
+    // The destination must be free, and not under what is moved
+    assert!(matches!(
+        metadata.rename("job", "other"),
+        Err(SdfsError::BadRequest(_))
+    ));
+    assert!(matches!(
+        metadata.rename("job", "job/inside"),
+        Err(SdfsError::BadRequest(_))
+    ));
+    assert!(matches!(
+        metadata.rename("missing", "x"),
+        Err(SdfsError::NotFound(_))
+    ));
+    assert_eq!(
+        metadata.check_rename("job", "done/job"),
+        Ok(vec![
+            (String::from("job/a"), String::from("done/job/a")),
+            (String::from("job/sub/b"), String::from("done/job/sub/b")),
+        ])
+    );
+
+    metadata.rename("job", "done/job").unwrap();
+    assert!(!metadata.namespace.is_dir("job"));
+    assert_eq!(
+        names(&metadata.list_dir("done/job").unwrap()),
+        vec![("a", false), ("empty", true), ("sub", true)]
+    );
+    assert_eq!(metadata.latest_version("done/job/a"), 1);
+    assert_eq!(metadata.namespace.version_stats["done/job/a"], stat_before);
+    let blocks = metadata.get_blocks("done/job/sub/b", 1);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].block, block_name("done/job/sub/b", 1, 0));
+    assert_eq!(blocks[0].nodes, vec![1, 2, 3]);
+    assert!(metadata.namespace.file_blocks("job/a").is_empty());
+    assert!(metadata.datanode_usage[&1].contains(&block_name("done/job/a", 1, 1)));
+    assert!(metadata.files_sync.contains_key("done/job/a"));
+    assert!(!metadata.files_sync.contains_key("job/a"));
+
+    // A single file moves too
+    metadata.rename("other", "done/other").unwrap();
+    assert_eq!(metadata.list_files("done/", "").len(), 3);
+}
+
+#[test]
+fn directories_and_stats_survive_a_restart() {
+    let dir = env::temp_dir().join(format!("sdfs-namespace-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let checkpoint_path = dir.join("metadata").to_str().unwrap().to_string();
+    let log_path = dir.join("metadata.log").to_str().unwrap().to_string();
+
+    let records = [
+        LogRecord::MakeDir {
+            path: String::from("a/b"),
+        },
+        LogRecord::AddReplica {
+            block: block_name("a/f", 1, 0),
+            node: 1,
+        },
+        LogRecord::AddVersion {
+            filename: String::from("a/f"),
+            version: 1,
+            checksums: vec![3],
+            size: 42,
+            mtime: 1_700_000_000,
+        },
+        LogRecord::Rename {
+            from: String::from("a"),
+            to: String::from("c"),
+        },
+    ];
+    let mut expected = Namespace::default();
+    let (mut log, _) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    for record in records.iter() {
+        assert_eq!(LogRecord::decode(&record.encode()).as_ref(), Some(record));
+        log.append(record).unwrap();
+        record.apply(&mut expected);
+    }
+    assert!(expected.dirs.contains("c/b"));
+    assert_eq!(expected.version_stats["c/f"][&1].size, 42);
+    drop(log);
+
+    // Replayed from the log, then loaded from a checkpoint
+    let (mut log, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(namespace, expected);
+    log.checkpoint(&namespace).unwrap();
+    drop(log);
+    let (_, namespace) = MetadataLog::open(&checkpoint_path, &log_path).unwrap();
+    assert_eq!(namespace, expected);
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,161 @@
+use sdfs::{
+    accept_request, read_frame, read_hello, write_frame, write_hello, Connection, Decoder, Encoder,
+    Frame, SdfsError, ACCEPT_TIMEOUT, CM_PUT_REQ, PROTOCOL_VERSION, VM_LIST,
+};
+
+use std::io::{Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+#[test]
+fn request_survives_the_wire() {
+    // Longer than the one-byte lengths names used to be sent with
+    let filename = "dir/".repeat(100) + "é.txt";
+    let mut body = Encoder::new();
+    body.str(&filename)
+        .u32(7)
+        .u64(u64::MAX)
+        .nodes(&[1, VM_LIST.len() as u8]);
+    let request = Frame {
+        id: 42,
+        kind: CM_PUT_REQ,
+        body: body.into_bytes(),
+    };
+
+    let mut wire: Vec<u8> = Vec::new();
+    write_frame(&mut wire, &request).unwrap();
+    let received = read_frame(Cursor::new(wire)).unwrap();
+    assert_eq!(received, request);
+
+    let mut body = Decoder::new(received.body);
+    assert_eq!(body.str(), Ok(filename));
+    assert_eq!(body.u32(), Ok(7));
+    assert_eq!(body.u64(), Ok(u64::MAX));
+    assert_eq!(body.nodes(), Ok(vec![1, VM_LIST.len() as u8]));
+    assert!(matches!(body.u8(), Err(SdfsError::BadRequest(_))));
+}
+
+#[test]
+fn malformed_bodies_are_bad_requests() {
+    // A node that is not a VM
+    let mut body = Encoder::new();
+    body.u32(VM_LIST.len() as u32 + 1);
+    assert!(matches!(
+        Decoder::new(body.into_bytes()).node(),
+        Err(SdfsError::BadRequest(_))
+    ));
+
+    // A count larger than what follows
+    let mut body = Encoder::new();
+    body.u64(u64::MAX).u8(0);
+    assert!(matches!(
+        Decoder::new(body.into_bytes()).nodes(),
+        Err(SdfsError::BadRequest(_))
+    ));
+
+    // A name that is not UTF-8
+    let mut body = Encoder::new();
+    body.bytes(&[0xFF, 0xFE]);
+    assert!(matches!(
+        Decoder::new(body.into_bytes()).str(),
+        Err(SdfsError::BadRequest(_))
+    ));
+}
+
+// Shakes hands with a listener on localhost speaking PROTOCOL_VERSION, as a peer speaking
+// version. Returns whether the listener accepted it, and whether the listener's side of the
+// connection was set up
+fn shake_hands(version: u16) -> (bool, bool) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        Connection::accept(stream).is_ok()
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_hello(&mut stream, version).unwrap();
+    assert_eq!(read_hello(&mut stream), Ok(PROTOCOL_VERSION));
+    let mut accepted: [u8; 1] = [0];
+    stream.read_exact(&mut accepted).unwrap();
+    (accepted[0] == 1, server.join().unwrap())
+}
+
+#[test]
+fn handshake_rejects_other_versions() {
+    assert_eq!(shake_hands(PROTOCOL_VERSION), (true, true));
+    assert_eq!(shake_hands(PROTOCOL_VERSION + 1), (false, false));
+}
+
+#[test]
+fn stalled_peer_is_dropped_at_the_deadline() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Shakes hands, then sends its request a byte at a time, too slowly to finish
+    thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_hello(&mut stream, PROTOCOL_VERSION).unwrap();
+        let mut request: Vec<u8> = Vec::new();
+        let frame = Frame {
+            id: 1,
+            kind: CM_PUT_REQ,
+            body: Vec::new(),
+        };
+        write_frame(&mut request, &frame).unwrap();
+        for byte in request {
+            if stream.write_all(&[byte]).is_err() {
+                break;
+            }
+            thread::sleep(ACCEPT_TIMEOUT / 4);
+        }
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let start = Instant::now();
+    assert!(accept_request(stream).is_err());
+    assert!(start.elapsed() >= ACCEPT_TIMEOUT);
+    assert!(start.elapsed() < ACCEPT_TIMEOUT * 2);
+}
+
+#[test]
+fn block_contents_arrive_a_frame_at_a_time() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (read, was_read) = mpsc::channel::<()>();
+
+    // Sends the rest of a block only once its first part was received, then a failed reply
+    thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_hello(&mut stream, PROTOCOL_VERSION).unwrap();
+        let mut reply: [u8; 7] = [0; 7];
+        stream.read_exact(&mut reply).unwrap();
+        write_frame(&mut stream, &Frame::data(1, b"ab")).unwrap();
+        was_read.recv().unwrap();
+        write_frame(&mut stream, &Frame::data(1, b"c")).unwrap();
+        write_frame(&mut stream, &Frame::end(1)).unwrap();
+        let missing = Frame::reply(2, Err(SdfsError::NotFound(String::from("b"))));
+        write_frame(&mut stream, &missing).unwrap();
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut conn = Connection::accept(stream).unwrap();
+    assert_eq!(conn.receive_chunk(1), Ok(Some(b"ab".to_vec())));
+    read.send(()).unwrap();
+    assert_eq!(conn.receive_chunk(1), Ok(Some(b"c".to_vec())));
+    assert_eq!(conn.receive_chunk(1), Ok(None));
+    assert_eq!(
+        conn.receive_chunk(2),
+        Err(SdfsError::NotFound(String::from("b")))
+    );
+}
+
+#[test]
+fn handshake_rejects_other_protocols() {
+    assert!(matches!(
+        read_hello(Cursor::new(b"GET / HTTP/1.1".to_vec())),
+        Err(SdfsError::BadRequest(_))
+    ));
+}
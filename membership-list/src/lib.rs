@@ -1,3 +1,6 @@
+pub mod membership;
+pub use membership::{Config, Member, Membership};
+
 use std::time::Duration;
 
 pub const HEARTBEAT_PORT: u16 = 50001;
@@ -10,22 +13,90 @@ pub const TCLEANUP: Duration = Duration::new(4, 0);
 pub const TSUSTIMEOUT: Duration = Duration::new(5, 0);
 pub const TSWITCH: Duration = Duration::new(5, 0);
 
+// how often the timeout checker runs; a timeout is noticed at most this late
+pub const TCHECK: Duration = Duration::from_millis(100);
+
+// how long a removed member's ID is remembered, so late gossip cannot resurrect it
+pub const TTOMBSTONE: Duration = Duration::new(30, 0);
+
 pub const MODE_CHANGE_COOLDOWN: Duration = Duration::new(10, 0);
 
+// automatic suspicion switching, measured over windows of TSWITCH
+// loss is the fraction of expected gossip datagrams that never arrived, latency is the
+// mean time between fresh heartbeats for a peer; enable/disable thresholds give hysteresis
+// every member advertises its measurements, and the worst reported by a live member decides
+pub const LOSS_ENABLE_THRESHOLD: f32 = 0.3;
+pub const LOSS_DISABLE_THRESHOLD: f32 = 0.1;
+pub const LATENCY_ENABLE_THRESHOLD: Duration = Duration::from_millis(1500);
+pub const LATENCY_DISABLE_THRESHOLD: Duration = Duration::from_millis(800);
+
+// partition detection - if at least PARTITION_FRACTION of the other members (and at least
+// PARTITION_MIN_FAILURES) time out in the same check, hold off failing them for TPARTITION_HOLD
+pub const PARTITION_FRACTION: f32 = 0.5;
+pub const PARTITION_MIN_FAILURES: usize = 2;
+pub const TPARTITION_HOLD: Duration = Duration::new(10, 0);
+
 pub const HOSTNAME_LENGTH: i64 = 100;
 
 // number of peers to gossip to at a time (fixed)
 pub const GOSSIP_NUM: usize = 3;
 
+// with delta gossip, every FULL_SYNC_ROUNDS-th message to a peer is a full sync
+pub const FULL_SYNC_ROUNDS: u32 = 10;
+
 // parameters for each datagram
-pub const DATAGRAM_LENGTH: usize = 70;
+pub const DATAGRAM_LENGTH: usize = 78;
 pub const HOSTNAME_OFFSET: usize = 0;
 pub const PORTNUM_OFFSET: usize = 50;
 pub const TIMESTAMP_OFFSET: usize = 52;
 pub const HEARTBEAT_OFFSET: usize = 60;
 pub const STATUS_OFFSET: usize = 64;
 pub const INCNUM_OFFSET: usize = 65;
-pub const MODE_OFFSET: usize = 69;
+pub const NONCE_OFFSET: usize = 69;
+pub const META_VERSION_OFFSET: usize = 73;
+pub const MODE_OFFSET: usize = 77;
+
+// each entry is followed by its metadata: a 2 byte length, then a (key length, key,
+// value length, value) record for each pair, with lengths of 1 byte each
+pub const METADATA_MAX_LENGTH: usize = 512;
+// metadata length sent when the metadata is left out (the receiver keeps its copy)
+pub const METADATA_OMITTED: u16 = u16::MAX;
+
+// parameters for each signed packet: a header identifying the sender, up to MAX_ENTRIES
+// entries with their metadata, then an HMAC-SHA256 over everything before it
+pub const MAX_ENTRIES: usize = 10;
+pub const HEADER_LENGTH: usize = 72;
+pub const SENDER_HOSTNAME_OFFSET: usize = 0;
+pub const SENDER_PORT_OFFSET: usize = 50;
+pub const SENDER_TIMESTAMP_OFFSET: usize = 52;
+pub const SENDER_NONCE_OFFSET: usize = 60;
+pub const SEQUENCE_OFFSET: usize = 64;
+pub const MAC_LENGTH: usize = 32;
+pub const MIN_PACKET_LENGTH: usize = HEADER_LENGTH + MAC_LENGTH;
+pub const MAX_PACKET_LENGTH: usize =
+    HEADER_LENGTH + MAX_ENTRIES * (DATAGRAM_LENGTH + 2 + METADATA_MAX_LENGTH) + MAC_LENGTH;
+
+// sequence numbers this far behind the highest seen from a sender are rejected as replays.
+// Once a sender has been quiet for TTOMBSTONE only its highest is kept, and only later ones
+// are accepted from it
+pub const REPLAY_WINDOW: u64 = 64;
+
+// file holding the secret shared by every member of the cluster
+pub const CLUSTER_SECRET_PATH: &str = "cluster_secret.txt";
+
+// metadata each member advertises about itself, read as key=value lines on startup
+pub const SELF_METADATA_PATH: &str = "self_metadata.txt";
+
+// well-known metadata keys; roles is a comma separated list (e.g. "datanode,worker")
+pub const META_ROLES: &str = "roles";
+pub const META_FREE_DISK: &str = "free_disk_kb";
+pub const META_CPU_LOAD: &str = "cpu_load";
+// loss and mean heartbeat latency (ms) each member measured over its last TSWITCH window
+pub const META_LOSS: &str = "net_loss";
+pub const META_LATENCY: &str = "net_latency_ms";
+
+// how often free disk and CPU load are re-measured
+pub const TCAPACITY: Duration = Duration::new(10, 0);
 
 // parameter for debugging - rate of messages to "drop"
 pub const MESSAGE_DROP_RATE: f32 = 0.0;
@@ -0,0 +1,1822 @@
+use lazy_static::lazy_static;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Read;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+struct MemListEntry {
+    hostname: String,
+    port: u16,
+    timestamp: u64,
+    nonce: u32,
+    heartbeat: u32,
+    local_time: Instant,
+    status: u8, // 0 - Alive, 1 - Failed, 2 - Suspected
+    inc_num: u32,
+    // Advertised roles, service ports, capacity etc. Bumping meta_version spreads a change
+    metadata: BTreeMap<String, String>,
+    meta_version: u32,
+}
+
+struct Mode {
+    // 0 - Normal, 1 - Suspicion
+    mode: u8,
+    last_changed: Instant,
+    // Whether the network monitor may switch modes on its own
+    auto: bool,
+}
+
+// Observations of gossip arrivals, used for automatic mode switching
+struct NetworkStats {
+    window_start: Instant,
+    datagrams: u32,
+    interval_total: Duration,
+    interval_count: u32,
+    // Results of the last completed window
+    loss: f32,
+    latency: Duration,
+    // Set while mass timeouts are being held off as a likely partition
+    partition_since: Option<Instant>,
+    // Packets dropped by authentication
+    dropped_unsigned: u64,
+    dropped_bad_mac: u64,
+    dropped_replayed: u64,
+    // How long after its deadline each timeout was acted on
+    detections: u32,
+    detection_lateness_total: Duration,
+    detection_lateness_max: Duration,
+}
+
+// Sliding window of sequence numbers already accepted from one sender
+struct ReplayWindow {
+    highest: u64,
+    // Bit i set means highest - i has been seen
+    seen: u64,
+    last_seen: Instant,
+    // Set once the sender has been quiet for TTOMBSTONE: nothing up to highest is accepted
+    tombstoned: bool,
+}
+
+// Reasons a received packet is dropped before its entries are read
+#[derive(Debug, PartialEq)]
+enum PacketRejection {
+    Unsigned,
+    BadMac,
+    Replayed,
+}
+
+// What was last sent to one peer about each entry, for delta gossip
+struct PeerSync {
+    rounds_since_full: u32,
+    sent: HashMap<String, SentState>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct SentState {
+    heartbeat: u32,
+    status: u8,
+    inc_num: u32,
+    meta_version: u32,
+}
+
+// Bytes this process has gossiped, with the cost of the strategy not in use estimated
+// from the same rounds, so both can be compared on live traffic
+struct GossipTraffic {
+    delta: bool,
+    since: Instant,
+    full_bytes: u64,
+    delta_bytes: u64,
+}
+
+// A signed packet waiting to be sent by the sender thread
+struct Outgoing {
+    hostname: String,
+    port: u16,
+    buf: Vec<u8>,
+}
+
+// Remembers a deleted member so that stale gossip about it is not re-inserted
+struct Tombstone {
+    heartbeat: u32,
+    removed_at: Instant,
+}
+
+lazy_static! {
+    static ref LOG_FILE: Option<File> = get_logfile();
+    static ref CLUSTER_KEY: Vec<u8> = get_cluster_secret();
+}
+
+// Sequence number of the last packet this process sent
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// Where a member listens and how it joins the group
+pub struct Config {
+    pub hostname: String,
+    pub port: u16,
+    // Member that new members first gossip to
+    pub intro_hostname: String,
+    pub intro_port: u16,
+    pub metadata: BTreeMap<String, String>,
+}
+
+// Snapshot of one entry of the membership list
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub id: String,
+    pub hostname: String,
+    pub port: u16,
+    pub status: u8, // 0 - Alive, 1 - Failed, 2 - Suspected
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Member {
+    // Whether the member advertises the given role in its metadata
+    pub fn has_role(&self, role: &str) -> bool {
+        self.metadata
+            .get(META_ROLES)
+            .is_some_and(|roles| roles.split(',').any(|curr| curr.trim() == role))
+    }
+}
+
+// A running member of the gossip group, and the threads keeping its membership list
+pub struct Membership {
+    self_id: String,
+    mem_list: Arc<Mutex<HashMap<String, MemListEntry>>>,
+    mode: Arc<Mutex<Mode>>,
+    stats: Arc<Mutex<NetworkStats>>,
+    traffic: Arc<Mutex<GossipTraffic>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Membership {
+    // Joins the group through the introducer and starts gossiping
+    // Panics if the cluster secret is missing
+    pub fn start(config: Config) -> Self {
+        // Fail early if the cluster secret is missing
+        lazy_static::initialize(&CLUSTER_KEY);
+
+        // Initialise self ID
+        let curr_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let timestamp: u64 = curr_time.as_secs();
+        // Random nonce keeps IDs unique even when restarting within the same second
+        let nonce: u32 = rand::random();
+        let self_id = generate_id(&config.hostname, config.port, timestamp, nonce);
+        log("Created Self ID: ".to_string() + &self_id);
+
+        // Creating membership list
+        let mut mem_list_hm: HashMap<String, MemListEntry> = HashMap::with_capacity(14); //  With 10 as 75% load
+
+        // Creating mem list entry for this machine
+        let self_entry = MemListEntry {
+            hostname: config.hostname.clone(),
+            port: config.port,
+            timestamp,
+            nonce,
+            heartbeat: 1,
+            local_time: Instant::now(),
+            status: 0,
+            inc_num: 1,
+            metadata: config.metadata,
+            meta_version: 1,
+        };
+        mem_list_hm.insert(self_id.clone(), self_entry);
+
+        // Creating thread-safe wrapper for mem_list
+        let mem_list: Arc<Mutex<HashMap<String, MemListEntry>>> = Arc::new(Mutex::new(mem_list_hm));
+
+        // IDs of recently deleted members, shared by the listener and the timeout checker
+        let tombstones: Arc<Mutex<HashMap<String, Tombstone>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+
+        // Gossip vs Gossip-Suspicion mode
+        let mode: Mode = Mode {
+            mode: 0,
+            last_changed: Instant::now(),
+            auto: true,
+        };
+        let mode: Arc<Mutex<Mode>> = Arc::new(Mutex::new(mode));
+
+        // Gossip strategy and bandwidth accounting, shared by gossiper and user commands
+        let traffic: GossipTraffic = GossipTraffic {
+            delta: true,
+            since: Instant::now(),
+            full_bytes: 0,
+            delta_bytes: 0,
+        };
+        let traffic: Arc<Mutex<GossipTraffic>> = Arc::new(Mutex::new(traffic));
+
+        // Loss/latency measurements, shared by listener, monitor and timeout checker
+        let stats: NetworkStats = NetworkStats {
+            window_start: Instant::now(),
+            datagrams: 0,
+            interval_total: Duration::ZERO,
+            interval_count: 0,
+            loss: 0.0,
+            latency: Duration::ZERO,
+            partition_since: None,
+            dropped_unsigned: 0,
+            dropped_bad_mac: 0,
+            dropped_replayed: 0,
+            detections: 0,
+            detection_lateness_total: Duration::ZERO,
+            detection_lateness_max: Duration::ZERO,
+        };
+        let stats: Arc<Mutex<NetworkStats>> = Arc::new(Mutex::new(stats));
+
+        // Packets are queued here and sent by their own thread, so that no lock is held during I/O
+        let (outbox, outbox_receiver): (Sender<Outgoing>, Receiver<Outgoing>) = mpsc::channel();
+
+        // Check if this member is the introducer
+        if config.intro_hostname != config.hostname || config.intro_port != config.port {
+            // Reach out to introducer if currently NOT the introducer, send mem_list that only has self
+            let mem_list_hm = mem_list.lock().unwrap();
+            let entries: Vec<(&MemListEntry, bool)> =
+                mem_list_hm.values().map(|entry| (entry, true)).collect();
+            let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1;
+            let buf = build_packet(&mem_list_hm[&self_id], seq, &entries, 0, &CLUSTER_KEY);
+            let _ = outbox.send(Outgoing {
+                hostname: config.intro_hostname.clone(),
+                port: config.intro_port,
+                buf,
+            });
+        }
+
+        let mut handles: Vec<JoinHandle<()>> = Vec::new();
+
+        // Spawn sender thread, which stops once the gossiper drops the outbox
+        handles.push(thread::spawn(move || send_outgoing(outbox_receiver)));
+
+        // Spawn fail timeout checker thread
+        let mem_list_clone_for_timeout = mem_list.clone();
+        let tombstones_clone_for_timeout = tombstones.clone();
+        let mode_clone_timeout = mode.clone();
+        let stats_clone_timeout = stats.clone();
+        let self_id_clone_for_timeout = self_id.clone();
+        handles.push(thread::spawn(move || {
+            check_timeout(
+                mem_list_clone_for_timeout,
+                tombstones_clone_for_timeout,
+                mode_clone_timeout,
+                stats_clone_timeout,
+                self_id_clone_for_timeout,
+            )
+        }));
+
+        // Spawn UDP listener thread
+        let mem_list_clone_for_listen = mem_list.clone();
+        let tombstones_clone_for_listen = tombstones.clone();
+        let mode_clone_listen = mode.clone();
+        let stats_clone_listen = stats.clone();
+        let self_id_clone_for_listen = self_id.clone();
+        let port = config.port;
+        handles.push(thread::spawn(move || {
+            update_membership(
+                mem_list_clone_for_listen,
+                tombstones_clone_for_listen,
+                mode_clone_listen,
+                stats_clone_listen,
+                port,
+                self_id_clone_for_listen,
+            )
+        }));
+
+        // Spawn network monitor thread
+        let mem_list_clone_for_monitor = mem_list.clone();
+        let mode_clone_monitor = mode.clone();
+        let stats_clone_monitor = stats.clone();
+        let self_id_clone_for_monitor = self_id.clone();
+        handles.push(thread::spawn(move || {
+            monitor_network(
+                mem_list_clone_for_monitor,
+                mode_clone_monitor,
+                stats_clone_monitor,
+                self_id_clone_for_monitor,
+            )
+        }));
+
+        // Spawn gossiper thread
+        let mem_list_clone_for_gossiper = mem_list.clone();
+        let mode_clone_gossiper = mode.clone();
+        let traffic_clone_gossiper = traffic.clone();
+        let self_id_clone_for_gossiper = self_id.clone();
+        handles.push(thread::spawn(move || {
+            gossip(
+                mem_list_clone_for_gossiper,
+                mode_clone_gossiper,
+                traffic_clone_gossiper,
+                outbox,
+                self_id_clone_for_gossiper,
+            )
+        }));
+
+        Membership {
+            self_id,
+            mem_list,
+            mode,
+            stats,
+            traffic,
+            handles,
+        }
+    }
+
+    pub fn self_id(&self) -> &str {
+        &self.self_id
+    }
+
+    // Snapshot of the current membership list, including self
+    pub fn members(&self) -> Vec<Member> {
+        let mem_list = self.mem_list.lock().unwrap();
+        mem_list
+            .iter()
+            .map(|(key, entry)| Member {
+                id: key.clone(),
+                hostname: entry.hostname.clone(),
+                port: entry.port,
+                status: entry.status,
+                metadata: entry.metadata.clone(),
+            })
+            .collect()
+    }
+
+    // Leaves the group gracefully and waits for the threads to terminate
+    pub fn leave(self) {
+        log("Leave function called".to_string());
+
+        // Self self heartbeat to 0
+        {
+            let mut mem_list = self.mem_list.lock().unwrap();
+            let self_entry = mem_list.get_mut(&self.self_id).unwrap();
+            self_entry.heartbeat = 0;
+        }
+
+        log("Peer has left the network gracefully.".to_string());
+        println!("Peer has left the network gracefully.");
+
+        // Wait for threads to terminate following leave
+        for handle in self.handles {
+            handle.join().unwrap();
+        }
+    }
+
+    // <------------------------ USER COMMAND FUNCTIONS ------------------------>
+
+    pub fn list_mem(&self) {
+        // Print current mode
+        let mode = self.mode.lock().unwrap();
+        println!("Mode: {}", mode.mode);
+
+        println!("Here are the elements in the current membership list: ");
+
+        println!(
+            "{0: <50} | {1: <10} | {2: <10} | {3: <10} | {4: <10}",
+            "ID", "Heartbeat", "Last seen", "Status", "Inc Num"
+        );
+
+        // Acquire a lock on the Mutex
+        let locked_mem_list = self.mem_list.lock().unwrap();
+
+        // Iterate over the HashMap and print its elements
+        for (key, value) in locked_mem_list.iter() {
+            // Seconds since the last update, measured on the local monotonic clock
+            let local_time = value.local_time.elapsed().as_secs();
+            println!(
+                "{0: <50} | {1: <10} | {2: <10} | {3: <10} | {4: <10}",
+                key, value.heartbeat, local_time, value.status, value.inc_num
+            );
+        }
+    }
+
+    pub fn list_self(&self) {
+        println!("The current machine's ID is {}", self.self_id);
+    }
+
+    pub fn list_meta(&self) {
+        println!("Metadata advertised by each member: ");
+
+        let locked_mem_list = self.mem_list.lock().unwrap();
+        for (key, value) in locked_mem_list.iter() {
+            let pairs: Vec<String> = value
+                .metadata
+                .iter()
+                .map(|(meta_key, meta_value)| format!("{}={}", meta_key, meta_value))
+                .collect();
+            println!(
+                "{0: <50} | v{1: <5} | {2}",
+                key,
+                value.meta_version,
+                pairs.join(", ")
+            );
+        }
+    }
+
+    // Sets (or with None, removes) a key in this member's metadata
+    pub fn set_self_metadata(&self, key: &str, value: Option<&str>) {
+        let mut mem_list = self.mem_list.lock().unwrap();
+        let self_entry = mem_list.get_mut(&self.self_id).unwrap();
+        match update_metadata(self_entry, key, value) {
+            Ok(true) => {
+                log(format!("Metadata {} set to {:?}", key, value));
+                println!("Metadata updated.");
+            }
+            Ok(false) => println!("Metadata unchanged."),
+            Err(err) => println!("Could not update metadata: {}", err),
+        }
+    }
+
+    pub fn list_stats(&self) {
+        let auto = self.mode.lock().unwrap().auto;
+        let stats = self.stats.lock().unwrap();
+        println!("Automatic switching: {}", if auto { "on" } else { "off" });
+        println!("Observed loss: {:.2}", stats.loss);
+        println!("Observed heartbeat latency: {:?}", stats.latency);
+        match stats.partition_since {
+            Some(since) => println!(
+                "Possible partition, failures held for {}s",
+                since.elapsed().as_secs()
+            ),
+            None => println!("No partition detected"),
+        }
+        println!(
+            "Dropped packets: {} unsigned, {} bad signature, {} replayed",
+            stats.dropped_unsigned, stats.dropped_bad_mac, stats.dropped_replayed
+        );
+        if stats.detections > 0 {
+            println!(
+                "Timeouts acted on {:?} late on average, {:?} at most ({} timeouts)",
+                stats.detection_lateness_total / stats.detections,
+                stats.detection_lateness_max,
+                stats.detections
+            );
+        }
+    }
+
+    pub fn set_delta_gossip(&self, delta: bool) {
+        self.traffic.lock().unwrap().delta = delta;
+        if delta {
+            log("Gossiping deltas.".to_string());
+            println!("Gossiping deltas.");
+        } else {
+            log("Gossiping full membership lists.".to_string());
+            println!("Gossiping full membership lists.");
+        }
+    }
+
+    // Measures bytes per second sent by this node under both strategies for the given duration
+    pub fn bench(&self, duration: Duration) {
+        {
+            let mut traffic = self.traffic.lock().unwrap();
+            traffic.since = Instant::now();
+            traffic.full_bytes = 0;
+            traffic.delta_bytes = 0;
+        }
+        println!("Benchmarking gossip bandwidth for {:?}...", duration);
+        thread::sleep(duration);
+
+        let traffic = self.traffic.lock().unwrap();
+        let elapsed = traffic.since.elapsed().as_secs_f64();
+        println!("Sending: {}", if traffic.delta { "delta" } else { "full" });
+        println!(
+            "Full gossip: {:.0} bytes/s",
+            traffic.full_bytes as f64 / elapsed
+        );
+        println!(
+            "Delta gossip: {:.0} bytes/s",
+            traffic.delta_bytes as f64 / elapsed
+        );
+    }
+
+    // User override of suspicion mode, which also turns off automatic switching
+    pub fn set_suspicion_manually(&self, to_set: u8) {
+        self.set_auto_switch(false);
+        toggle_suspicion(&self.mode, to_set);
+        if to_set == 0 {
+            println!("Suspicion disabled.");
+        } else {
+            println!("Suspicion enabled.");
+        }
+    }
+
+    pub fn set_auto_switch(&self, auto: bool) {
+        let mut mode = self.mode.lock().unwrap();
+        mode.auto = auto;
+        if auto {
+            log("Automatic suspicion switching enabled.".to_string());
+            println!("Automatic suspicion switching enabled.");
+        } else {
+            log("Automatic suspicion switching disabled.".to_string());
+            println!("Automatic suspicion switching disabled.");
+        }
+    }
+}
+
+// Toggle suspicion mode, by the user or from the background threads
+fn toggle_suspicion(mode: &Arc<Mutex<Mode>>, to_set: u8) {
+    let mut mode = mode.lock().unwrap();
+
+    mode.mode = to_set;
+    mode.last_changed = Instant::now();
+    if to_set == 0 {
+        log("Suspicion disabled.".to_string());
+    } else if to_set == 1 {
+        log("Suspicion enabled".to_string());
+    }
+}
+
+// <------------------------ UTILITY FUNCTIONS ------------------------>
+// Creates file for logging
+// Requires the file "../../self_logname.txt" to hold logging file's pathname
+// Logging is skipped (with a warning) if the file cannot be opened
+fn get_logfile() -> Option<File> {
+    let mut logname = String::new();
+    if let Ok(mut file) = File::open("self_logname.txt") {
+        let _ = file.read_to_string(&mut logname);
+    }
+    match File::create(logname.trim()) {
+        Ok(file) => Some(file),
+        Err(err) => {
+            eprintln!("Unable to open file for logging: {}", err);
+            None
+        }
+    }
+}
+
+// Reads the shared cluster secret from CLUSTER_SECRET_PATH
+fn get_cluster_secret() -> Vec<u8> {
+    let mut file = File::open(CLUSTER_SECRET_PATH)
+        .expect("cluster_secret.txt must hold the secret shared by the cluster");
+    let mut secret = String::new();
+    file.read_to_string(&mut secret).unwrap();
+    let secret = secret.trim();
+    assert!(!secret.is_empty(), "cluster_secret.txt is empty");
+    Vec::from(secret.as_bytes())
+}
+
+// Reads this member's initial metadata from SELF_METADATA_PATH, as key=value lines
+// Returns an empty map if the file does not exist
+// Panics if the metadata could not be sent, as update_metadata would refuse it
+pub fn get_self_metadata() -> BTreeMap<String, String> {
+    let data = fs::read_to_string(SELF_METADATA_PATH).unwrap_or_default();
+    parse_metadata(&data).unwrap_or_else(|err| panic!("self_metadata.txt: {}", err))
+}
+
+// Parses key=value lines, with the same limits as update_metadata
+fn parse_metadata(data: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut metadata: BTreeMap<String, String> = BTreeMap::new();
+    for line in data.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let (key, value) = (key.trim(), value.trim());
+            check_metadata_pair(key, value)?;
+            metadata.insert(String::from(key), String::from(value));
+        }
+    }
+    if metadata_length(&metadata) > METADATA_MAX_LENGTH {
+        return Err("metadata is larger than METADATA_MAX_LENGTH".to_string());
+    }
+    Ok(metadata)
+}
+
+// Keys and values are sent with 1 byte lengths
+fn check_metadata_pair(key: &str, value: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > 255 || value.len() > 255 {
+        return Err("keys and values must be 1 to 255 bytes".to_string());
+    }
+    Ok(())
+}
+
+// Measures current free disk (under the working directory) and CPU load
+// Values that cannot be measured on this machine are left out
+fn measure_capacity() -> Vec<(&'static str, String)> {
+    let mut capacity: Vec<(&'static str, String)> = Vec::new();
+
+    // Available kilobytes is the 4th column of the 2nd line of `df -Pk .`
+    if let Ok(output) = Command::new("df").arg("-Pk").arg(".").output() {
+        let output = String::from_utf8_lossy(&output.stdout);
+        if let Some(line) = output.lines().nth(1) {
+            if let Some(available) = line.split_whitespace().nth(3) {
+                capacity.push((META_FREE_DISK, String::from(available)));
+            }
+        }
+    }
+
+    // 1 minute load average
+    if let Ok(loadavg) = fs::read_to_string("/proc/loadavg") {
+        if let Some(load) = loadavg.split_whitespace().next() {
+            capacity.push((META_CPU_LOAD, String::from(load)));
+        }
+    }
+
+    capacity
+}
+
+// Sets or removes a metadata key, bumping meta_version if anything changed
+fn update_metadata(
+    entry: &mut MemListEntry,
+    key: &str,
+    value: Option<&str>,
+) -> Result<bool, String> {
+    if entry.metadata.get(key).map(|curr| curr.as_str()) == value {
+        return Ok(false);
+    }
+
+    let mut metadata = entry.metadata.clone();
+    match value {
+        Some(value) => {
+            check_metadata_pair(key, value)?;
+            metadata.insert(String::from(key), String::from(value));
+        }
+        None => {
+            metadata.remove(key);
+        }
+    }
+    if metadata_length(&metadata) > METADATA_MAX_LENGTH {
+        return Err("metadata would exceed METADATA_MAX_LENGTH".to_string());
+    }
+
+    entry.metadata = metadata;
+    entry.meta_version += 1;
+    Ok(true)
+}
+
+// Reads hostname from "self_hostname.txt"
+// Requires the file "../../self_hostname.txt" to hold own hostname
+pub fn get_hostname() -> String {
+    let mut file = File::open("self_hostname.txt").unwrap();
+    let mut self_hostname = String::new();
+    file.read_to_string(&mut self_hostname).unwrap();
+    let hostname: String = String::from(self_hostname.trim());
+    hostname
+}
+
+// Generate unique ID
+fn generate_id(hostname: &str, port: u16, timestamp: u64, nonce: u32) -> String {
+    format!("{}:{}:{}:{:08x}", hostname, port, timestamp, nonce)
+}
+
+// Logging utility function, write logging message to logfile
+fn log(msg: String) {
+    let curr_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let timestamp: u64 = curr_time.as_secs();
+    if let Some(log_file) = LOG_FILE.as_ref() {
+        let write_res = writeln!(&*log_file, "{}: {}", timestamp, msg);
+        write_res.expect("Failed writing to logs");
+    }
+}
+
+// Logging utility function, print out each message, for debugging
+#[allow(dead_code)]
+fn print_message(buf: &[u8; DATAGRAM_LENGTH]) {
+    let mut curr: usize = 0;
+    while (curr + 10) < DATAGRAM_LENGTH {
+        let int_string = format!(
+            "[{}]",
+            buf[curr..curr + 10]
+                .iter()
+                .map(|&byte| byte.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        log(int_string);
+        curr += 10;
+    }
+    let int_string = format!(
+        "[{}]",
+        buf[curr..DATAGRAM_LENGTH]
+            .iter()
+            .map(|&byte| byte.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    log(int_string);
+}
+
+// Utility function to Modify/Toggle suspicion mode if cooldown fulfilled
+fn toggle_suspicion_with_cooldown(mode: &Arc<Mutex<Mode>>, new_mode: u8) {
+    let mode_lock = mode.lock().unwrap();
+    let time_diff = mode_lock.last_changed.elapsed();
+    drop(mode_lock);
+    if time_diff >= MODE_CHANGE_COOLDOWN {
+        toggle_suspicion(mode, new_mode);
+    }
+}
+
+// HMAC-SHA256 (RFC 2104) of data under key
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; MAC_LENGTH] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..MAC_LENGTH].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let ipad: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+    let opad: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+
+    let inner = Sha256::new()
+        .chain_update(ipad)
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(opad)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+// Compares without returning early, so timing does not reveal the matching prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Populate the packet header with the sender's ID and sequence number
+fn populate_header_bytes(buf: &mut [u8], sender: &MemListEntry, seq: u64) {
+    let length = sender.hostname.len();
+    buf[SENDER_HOSTNAME_OFFSET..SENDER_HOSTNAME_OFFSET + length]
+        .copy_from_slice(sender.hostname.as_bytes());
+    buf[SENDER_PORT_OFFSET..SENDER_PORT_OFFSET + 2].copy_from_slice(&sender.port.to_le_bytes());
+    buf[SENDER_TIMESTAMP_OFFSET..SENDER_TIMESTAMP_OFFSET + 8]
+        .copy_from_slice(&sender.timestamp.to_le_bytes());
+    buf[SENDER_NONCE_OFFSET..SENDER_NONCE_OFFSET + 4].copy_from_slice(&sender.nonce.to_le_bytes());
+    buf[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 8].copy_from_slice(&seq.to_le_bytes());
+}
+
+// Return the sender ID and sequence number from a packet header
+fn read_header_bytes(buf: &[u8]) -> (String, u64) {
+    let hostname_bytes = &buf[SENDER_HOSTNAME_OFFSET..SENDER_PORT_OFFSET];
+    let hostname_end = hostname_bytes
+        .iter()
+        .position(|&c| c == b'\0')
+        .unwrap_or(hostname_bytes.len());
+    let hostname = String::from_utf8_lossy(&hostname_bytes[..hostname_end]);
+    let port = u16::from_le_bytes(
+        buf[SENDER_PORT_OFFSET..SENDER_PORT_OFFSET + 2]
+            .try_into()
+            .unwrap(),
+    );
+    let timestamp = u64::from_le_bytes(
+        buf[SENDER_TIMESTAMP_OFFSET..SENDER_TIMESTAMP_OFFSET + 8]
+            .try_into()
+            .unwrap(),
+    );
+    let nonce = u32::from_le_bytes(
+        buf[SENDER_NONCE_OFFSET..SENDER_NONCE_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let seq = u64::from_le_bytes(
+        buf[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 8]
+            .try_into()
+            .unwrap(),
+    );
+    (generate_id(&hostname, port, timestamp, nonce), seq)
+}
+
+// Sign everything so far, appending the MAC
+fn sign_packet(buf: &mut Vec<u8>, key: &[u8]) {
+    let mac = hmac_sha256(key, buf);
+    buf.extend_from_slice(&mac);
+}
+
+// Builds a signed packet carrying the given entries, each with a flag for whether to
+// include its metadata
+fn build_packet(
+    sender: &MemListEntry,
+    seq: u64,
+    entries: &[(&MemListEntry, bool)],
+    mode: u8,
+    key: &[u8],
+) -> Vec<u8> {
+    let mut buf: Vec<u8> = vec![0; HEADER_LENGTH];
+    populate_header_bytes(&mut buf, sender, seq);
+    for (entry, with_metadata) in entries.iter().take(MAX_ENTRIES) {
+        let mut buf_subset: [u8; DATAGRAM_LENGTH] = [0; DATAGRAM_LENGTH];
+        populate_entry_bytes(&mut buf_subset, entry, mode);
+        buf.extend_from_slice(&buf_subset);
+        if *with_metadata {
+            populate_metadata_bytes(&mut buf, &entry.metadata);
+        } else {
+            buf.extend_from_slice(&METADATA_OMITTED.to_le_bytes());
+        }
+    }
+    sign_packet(&mut buf, key);
+    buf
+}
+
+// Builds as many signed packets as it takes to carry all the entries, MAX_ENTRIES to a
+// packet. The sender's own entry goes first, so the receiver can tell the first packet of
+// a round from the rest.
+fn build_packets(
+    sender: &MemListEntry,
+    entries: &[(&MemListEntry, bool)],
+    mode: u8,
+    key: &[u8],
+) -> Vec<Vec<u8>> {
+    let mut entries: Vec<(&MemListEntry, bool)> = entries.to_vec();
+    entries.sort_by_key(|(entry, _)| !std::ptr::eq(*entry, sender));
+    entries
+        .chunks(MAX_ENTRIES)
+        .map(|chunk| {
+            let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1;
+            build_packet(sender, seq, chunk, mode, key)
+        })
+        .collect()
+}
+
+// Total size build_packets would produce for the same entries
+fn packet_length(entries: &[(&MemListEntry, bool)]) -> usize {
+    entries
+        .chunks(MAX_ENTRIES)
+        .map(|chunk| {
+            let entries_length: usize = chunk
+                .iter()
+                .map(|(entry, with_metadata)| {
+                    let metadata = if *with_metadata {
+                        metadata_length(&entry.metadata)
+                    } else {
+                        0
+                    };
+                    DATAGRAM_LENGTH + 2 + metadata
+                })
+                .sum();
+            HEADER_LENGTH + entries_length + MAC_LENGTH
+        })
+        .sum()
+}
+
+// Returns the entries, each with the sender's mode, from a verified packet
+fn read_packet_entries(packet: &[u8]) -> Vec<(u8, MemListEntry)> {
+    let body = &packet[HEADER_LENGTH..packet.len() - MAC_LENGTH];
+    let mut entries: Vec<(u8, MemListEntry)> = Vec::new();
+    let mut offset: usize = 0;
+    while offset + DATAGRAM_LENGTH <= body.len() {
+        let buf_subset: [u8; DATAGRAM_LENGTH] =
+            body[offset..offset + DATAGRAM_LENGTH].try_into().unwrap();
+        offset += DATAGRAM_LENGTH;
+
+        let (mode, mut entry) = read_entry_bytes(&buf_subset);
+        match read_metadata_bytes(&body[offset..]) {
+            Some((Some(metadata), length)) => {
+                entry.metadata = metadata;
+                offset += length;
+            }
+            // Version 0 is never newer, so the receiver keeps the metadata it has
+            Some((None, length)) => {
+                entry.meta_version = 0;
+                offset += length;
+            }
+            None => break,
+        }
+
+        // No more entries
+        if entry.hostname.is_empty() {
+            break;
+        }
+        entries.push((mode, entry));
+    }
+    entries
+}
+
+// Checks the packet's signature and sequence number, recording the sequence number if accepted
+fn verify_packet(
+    packet: &[u8],
+    key: &[u8],
+    replay_windows: &mut HashMap<String, ReplayWindow>,
+) -> Result<(), PacketRejection> {
+    if packet.len() < MIN_PACKET_LENGTH || packet.len() > MAX_PACKET_LENGTH {
+        return Err(PacketRejection::Unsigned);
+    }
+    let (body, mac) = packet.split_at(packet.len() - MAC_LENGTH);
+    if mac.iter().all(|&byte| byte == 0) {
+        return Err(PacketRejection::Unsigned);
+    }
+    if !constant_time_eq(&hmac_sha256(key, body), mac) {
+        return Err(PacketRejection::BadMac);
+    }
+
+    let (sender_id, seq) = read_header_bytes(packet);
+    let now = Instant::now();
+    if !replay_windows.contains_key(&sender_id) {
+        // Tombstone senders that have been quiet long enough to be deleted
+        for window in replay_windows.values_mut() {
+            if now.duration_since(window.last_seen) >= TTOMBSTONE {
+                window.seen = 0;
+                window.tombstoned = true;
+            }
+        }
+        replay_windows.insert(
+            sender_id,
+            ReplayWindow {
+                highest: seq,
+                seen: 1,
+                last_seen: now,
+                tombstoned: false,
+            },
+        );
+        return Ok(());
+    }
+
+    let window = replay_windows.get_mut(&sender_id).unwrap();
+    if window.tombstoned {
+        if seq <= window.highest {
+            return Err(PacketRejection::Replayed);
+        }
+        window.tombstoned = false;
+    }
+    if seq > window.highest {
+        let shift = seq - window.highest;
+        window.seen = if shift >= REPLAY_WINDOW {
+            1
+        } else {
+            (window.seen << shift) | 1
+        };
+        window.highest = seq;
+    } else {
+        let age = window.highest - seq;
+        if age >= REPLAY_WINDOW || window.seen & (1 << age) != 0 {
+            return Err(PacketRejection::Replayed);
+        }
+        window.seen |= 1 << age;
+    }
+    window.last_seen = now;
+    Ok(())
+}
+
+// Populate given buffer with enheartbeatNUM_OFFSET..heartbeatNUM_OFFSET + 2try using pre-defined protocol
+fn populate_entry_bytes(buf: &mut [u8; DATAGRAM_LENGTH], entry: &MemListEntry, mode: u8) {
+    // Populate hostname
+    let length = entry.hostname.len(); // Note that this is not recommended, but it's okay as long as stay in ASCII
+    buf[HOSTNAME_OFFSET..length].copy_from_slice(entry.hostname.as_bytes());
+    buf[PORTNUM_OFFSET..PORTNUM_OFFSET + 2].copy_from_slice(&entry.port.to_le_bytes());
+    buf[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 8].copy_from_slice(&entry.timestamp.to_le_bytes());
+    buf[HEARTBEAT_OFFSET..HEARTBEAT_OFFSET + 4].copy_from_slice(&entry.heartbeat.to_le_bytes());
+    buf[STATUS_OFFSET..STATUS_OFFSET + 1].copy_from_slice(&entry.status.to_le_bytes());
+    buf[INCNUM_OFFSET..INCNUM_OFFSET + 4].copy_from_slice(&entry.inc_num.to_le_bytes());
+    buf[NONCE_OFFSET..NONCE_OFFSET + 4].copy_from_slice(&entry.nonce.to_le_bytes());
+    buf[META_VERSION_OFFSET..META_VERSION_OFFSET + 4]
+        .copy_from_slice(&entry.meta_version.to_le_bytes());
+    buf[MODE_OFFSET..MODE_OFFSET + 1].copy_from_slice(&mode.to_le_bytes());
+}
+
+// Return MemListEntry and mode heartbeatNUM_OFFSET..heartbeatNUM_OFFSET + 2based on data from buffer
+fn read_entry_bytes(buf: &[u8; DATAGRAM_LENGTH]) -> (u8, MemListEntry) {
+    // Extract hostname
+    let hostname_end = buf.iter().position(|&c| c == b'\0').unwrap();
+    let hostname_bytes: Vec<u8> = Vec::from(&buf[HOSTNAME_OFFSET..HOSTNAME_OFFSET + hostname_end]);
+    let hostname: String = String::from_utf8(hostname_bytes).unwrap();
+
+    // Extract port number
+    let mut port_bytes: [u8; 2] = [0; 2];
+    port_bytes.copy_from_slice(&buf[PORTNUM_OFFSET..PORTNUM_OFFSET + 2]);
+    let port: u16 = u16::from_le_bytes(port_bytes);
+
+    // Extract timestamp
+    let mut timestamp_bytes: [u8; 8] = [0; 8];
+    timestamp_bytes.copy_from_slice(&buf[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 8]);
+    let timestamp: u64 = u64::from_le_bytes(timestamp_bytes);
+
+    // Extract heartbeat counter
+    let mut heartbeat_bytes: [u8; 4] = [0; 4];
+    heartbeat_bytes.copy_from_slice(&buf[HEARTBEAT_OFFSET..HEARTBEAT_OFFSET + 4]);
+    let heartbeat: u32 = u32::from_le_bytes(heartbeat_bytes);
+
+    // Extract status
+    let status: u8 = buf[STATUS_OFFSET];
+
+    // Extract inc_num
+    let mut inc_num_bytes: [u8; 4] = [0; 4];
+    inc_num_bytes.copy_from_slice(&buf[INCNUM_OFFSET..INCNUM_OFFSET + 4]);
+    let inc_num: u32 = u32::from_le_bytes(inc_num_bytes);
+
+    // Extract nonce
+    let mut nonce_bytes: [u8; 4] = [0; 4];
+    nonce_bytes.copy_from_slice(&buf[NONCE_OFFSET..NONCE_OFFSET + 4]);
+    let nonce: u32 = u32::from_le_bytes(nonce_bytes);
+
+    // Extract metadata version, the metadata itself follows the datagram
+    let mut meta_version_bytes: [u8; 4] = [0; 4];
+    meta_version_bytes.copy_from_slice(&buf[META_VERSION_OFFSET..META_VERSION_OFFSET + 4]);
+    let meta_version: u32 = u32::from_le_bytes(meta_version_bytes);
+
+    // Extract mode
+    let mode: u8 = buf[MODE_OFFSET];
+
+    (
+        mode,
+        MemListEntry {
+            hostname,
+            port,
+            timestamp,
+            nonce,
+            heartbeat,
+            local_time: Instant::now(),
+            status,
+            inc_num,
+            metadata: BTreeMap::new(),
+            meta_version,
+        },
+    )
+}
+
+// Number of bytes the metadata takes up on the wire, excluding the 2 byte length
+fn metadata_length(metadata: &BTreeMap<String, String>) -> usize {
+    metadata
+        .iter()
+        .map(|(key, value)| 2 + key.len() + value.len())
+        .sum()
+}
+
+// Append metadata to buffer using pre-defined protocol
+fn populate_metadata_bytes(buf: &mut Vec<u8>, metadata: &BTreeMap<String, String>) {
+    buf.extend_from_slice(&(metadata_length(metadata) as u16).to_le_bytes());
+    for (key, value) in metadata.iter() {
+        buf.push(key.len() as u8);
+        buf.extend_from_slice(key.as_bytes());
+        buf.push(value.len() as u8);
+        buf.extend_from_slice(value.as_bytes());
+    }
+}
+
+// Return metadata (None if it was left out) and the number of bytes it took up,
+// or None if the buffer is malformed
+fn read_metadata_bytes(buf: &[u8]) -> Option<(Option<BTreeMap<String, String>>, usize)> {
+    let length = u16::from_le_bytes(buf.get(0..2)?.try_into().unwrap());
+    if length == METADATA_OMITTED {
+        return Some((None, 2));
+    }
+    let length = length as usize;
+    let records = buf.get(2..2 + length)?;
+
+    let mut metadata: BTreeMap<String, String> = BTreeMap::new();
+    let mut offset: usize = 0;
+    while offset < records.len() {
+        let key_length = *records.get(offset)? as usize;
+        let key = records.get(offset + 1..offset + 1 + key_length)?;
+        offset += 1 + key_length;
+        let value_length = *records.get(offset)? as usize;
+        let value = records.get(offset + 1..offset + 1 + value_length)?;
+        offset += 1 + value_length;
+        metadata.insert(
+            String::from_utf8_lossy(key).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        );
+    }
+    Some((Some(metadata), 2 + length))
+}
+
+// <------------------------ NETWORKING LOGIC FUNCTIONS ------------------------>
+
+// Listens for UDP messages, updates membership list
+fn update_membership(
+    mem_list: Arc<Mutex<HashMap<String, MemListEntry>>>,
+    tombstones: Arc<Mutex<HashMap<String, Tombstone>>>,
+    mode: Arc<Mutex<Mode>>,
+    stats: Arc<Mutex<NetworkStats>>,
+    port: u16,
+    self_id: String,
+) {
+    // Socket for UDP listening
+    let socket_addr = String::from("0.0.0.0") + ":" + port.to_string().as_str();
+    let socket = UdpSocket::bind(socket_addr).unwrap();
+
+    // Sequence numbers accepted so far, by sender ID
+    let mut replay_windows: HashMap<String, ReplayWindow> = HashMap::new();
+
+    // Thread loop
+    loop {
+        // Gracefully terminate thread if peer has requested to leave
+        {
+            let mem_list = mem_list.lock().unwrap();
+            let heartbeat = mem_list.get(&self_id).unwrap().heartbeat;
+            if heartbeat == 0 {
+                return;
+            }
+        }
+
+        let mut buf: [u8; MAX_PACKET_LENGTH] = [0; MAX_PACKET_LENGTH];
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => {
+                // Skip reading messages at rate of MESSAGE_DROP_RATE
+                if rand::thread_rng().gen_range(0.0..1.0) < MESSAGE_DROP_RATE {
+                    continue;
+                }
+                size
+            }
+            Err(_) => {
+                continue;
+            }
+        };
+
+        // Drop anything not signed with the cluster secret, or seen before
+        if let Err(rejection) = verify_packet(&buf[..size], &CLUSTER_KEY, &mut replay_windows) {
+            let mut stats = stats.lock().unwrap();
+            match rejection {
+                PacketRejection::Unsigned => stats.dropped_unsigned += 1,
+                PacketRejection::BadMac => stats.dropped_bad_mac += 1,
+                PacketRejection::Replayed => stats.dropped_replayed += 1,
+            }
+            log(format!("Dropped packet: {:?}", rejection));
+            continue;
+        }
+
+        // Decode everything and settle the mode before taking the membership lock
+        let entries = read_packet_entries(&buf[..size]);
+
+        // A round of gossip may take several packets, but only the first leads with the
+        // sender's own entry, and the loss estimate expects one arrival per round
+        let (sender_id, _) = read_header_bytes(&buf[..size]);
+        if entries.first().is_some_and(|(_, entry)| {
+            generate_id(&entry.hostname, entry.port, entry.timestamp, entry.nonce) == sender_id
+        }) {
+            stats.lock().unwrap().datagrams += 1;
+        }
+        let mut curr_mode: u8;
+        {
+            let mode = mode.lock().unwrap();
+            curr_mode = mode.mode;
+        }
+        // Every entry in a packet carries the sender's mode
+        if let Some((new_mode, _)) = entries.first() {
+            if *new_mode != curr_mode {
+                toggle_suspicion_with_cooldown(&mode, *new_mode);
+                {
+                    let mode = mode.lock().unwrap();
+                    curr_mode = mode.mode;
+                }
+            }
+        }
+
+        // Merge the whole packet under one lock
+        let mut intervals: Vec<Duration> = Vec::new();
+        {
+            let mut mem_list = mem_list.lock().unwrap();
+            let mut tombstones = tombstones.lock().unwrap();
+            for (_, entry) in entries {
+                let id: String =
+                    generate_id(&entry.hostname, entry.port, entry.timestamp, entry.nonce);
+                log("Received entry: ".to_string() + id.as_str());
+                if let Some(interval) = merge_entry(
+                    &mut mem_list,
+                    &mut tombstones,
+                    id,
+                    entry,
+                    curr_mode,
+                    &self_id,
+                ) {
+                    intervals.push(interval);
+                }
+            }
+        }
+
+        // Record how long it took for fresh heartbeats to reach us
+        if !intervals.is_empty() {
+            let mut stats = stats.lock().unwrap();
+            for interval in intervals {
+                stats.interval_total += interval;
+                stats.interval_count += 1;
+            }
+        }
+    }
+}
+
+// Merges a single received entry into the membership list
+// Entries for recently deleted IDs are dropped unless they carry a newer heartbeat
+// Returns the time since the previous heartbeat if this one advanced a peer's counter
+fn merge_entry(
+    mem_list: &mut HashMap<String, MemListEntry>,
+    tombstones: &mut HashMap<String, Tombstone>,
+    id: String,
+    entry: MemListEntry,
+    curr_mode: u8,
+    self_id: &String,
+) -> Option<Duration> {
+    let mut interval: Option<Duration> = None;
+
+    // Add new entry
+    if !mem_list.contains_key(&id) {
+        if let Some(tombstone) = tombstones.get(&id) {
+            // Stale gossip about a member we already deleted
+            if entry.heartbeat <= tombstone.heartbeat && tombstone.removed_at.elapsed() < TTOMBSTONE
+            {
+                log("Rejected stale entry for deleted member: ".to_string() + &id);
+                return None;
+            }
+            tombstones.remove(&id);
+        }
+        // A member that is leaving should not be re-added
+        if entry.heartbeat == 0 {
+            return None;
+        }
+        mem_list.insert(id.clone(), entry);
+        log("Entry added: ".to_string() + &id);
+    }
+    // Entry already exists
+    else {
+        let curr_entry = mem_list.get_mut(&id).unwrap();
+
+        // Non-suspicion mode
+        // Metadata is versioned separately, and only the member itself changes its own
+        if entry.meta_version > curr_entry.meta_version && id != *self_id {
+            curr_entry.metadata = entry.metadata;
+            curr_entry.meta_version = entry.meta_version;
+            log("Updated metadata for: ".to_string() + &id);
+        }
+
+        if entry.heartbeat > curr_entry.heartbeat || entry.heartbeat == 0 {
+            if id != *self_id && entry.heartbeat != 0 {
+                interval = Some(curr_entry.local_time.elapsed());
+            }
+            curr_entry.heartbeat = entry.heartbeat;
+            curr_entry.local_time = Instant::now();
+            // if entry.heartbeat != 0 {
+            //     curr_entry.status = entry.status;
+            // }
+        }
+        // // Update failed entries received from other nodes
+        // if entry.status == 1 {
+        //     curr_entry.status = 1;
+        // }
+
+        // Suspicion mode
+        if curr_mode == 1 {
+            // Correct any entries that say that self is suspected
+            if id == *self_id && entry.status == 2 {
+                curr_entry.inc_num += 1;
+            }
+            // Update failed entries received from other node, regardless of inc num
+            else if entry.status == 1 {
+                curr_entry.status = 1;
+            }
+            // // Within inc num, suspected wins
+            // else if entry.inc_num == curr_entry.inc_num && entry.status == 2 {
+            //     curr_entry.status = 2;
+            //     // TODO: add a print statement here
+            // }
+            // // Higher inc num
+            // else if entry.inc_num > curr_entry.inc_num {
+            //     curr_entry.inc_num = entry.inc_num;
+            //     curr_entry.status = 0;
+            // }
+
+            // Update local status to suspected, only if inc num is greater and not currently failed
+            else if entry.inc_num == curr_entry.inc_num
+                && entry.status == 2
+                && curr_entry.status != 1
+            {
+                // Log only when newly changing suspicion status
+                if curr_entry.status != 2 {
+                    log("Received and updated suspected status for: ".to_string() + &entry.hostname);
+                }
+                curr_entry.status = 2;
+            }
+            // Recover local suspected state is message inc num is greater
+            else if entry.inc_num > curr_entry.inc_num && curr_entry.status != 1 {
+                curr_entry.status = entry.status
+            }
+        }
+    }
+
+    interval
+}
+
+// Continuously checks if members have failed
+fn check_timeout(
+    mem_list_arc: Arc<Mutex<HashMap<String, MemListEntry>>>,
+    tombstones_arc: Arc<Mutex<HashMap<String, Tombstone>>>,
+    mode_arc: Arc<Mutex<Mode>>,
+    stats_arc: Arc<Mutex<NetworkStats>>,
+    self_id: String,
+) {
+    loop {
+        let curr_mode: u8;
+        {
+            let mode = mode_arc.lock().unwrap();
+            curr_mode = mode.mode;
+        }
+        let mut mem_list = mem_list_arc.lock().unwrap();
+
+        // Gracefully terminate thread if peer has requested to leave
+        let self_entry = mem_list.get_mut(&self_id).unwrap();
+        if self_entry.heartbeat == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+
+        // Count how many of the other live members would be failed by this check
+        let mut others: usize = 0;
+        let mut timing_out: usize = 0;
+        for (key, entry) in mem_list.iter() {
+            if key == &self_id || entry.status == 1 {
+                continue;
+            }
+            others += 1;
+            if times_out(entry, curr_mode, now) {
+                timing_out += 1;
+            }
+        }
+        let hold_failures: bool;
+        {
+            let mut stats = stats_arc.lock().unwrap();
+            hold_failures = detect_partition(&mut stats.partition_since, others, timing_out, now);
+        }
+
+        let mut to_remove: Vec<String> = Vec::new();
+        let mut lateness: Vec<Duration> = Vec::new();
+        for (key, entry) in mem_list.iter_mut() {
+            // Skip if it's self
+            if key == &self_id {
+                continue;
+            }
+
+            // Monotonic clock, so this cannot go negative on wall clock adjustments
+            let time_diff = now.duration_since(entry.local_time);
+
+            // Non-suspicion mode
+            if curr_mode == 0 {
+                // Failed, completed T_cleanup
+                if entry.status == 1 && time_diff >= (TFAIL + TCLEANUP) {
+                    to_remove.push(key.clone());
+                    log("Going to delete entry: ".to_string()
+                        + key
+                        + " time_diff="
+                        + &time_diff.as_secs().to_string());
+                } else if entry.status == 0 && time_diff >= TFAIL && !hold_failures {
+                    entry.status = 1;
+                    lateness.push(time_diff - TFAIL);
+                    log("Entry failed, pending T_cleanup: ".to_string()
+                        + key
+                        + time_diff.as_secs().to_string().as_str());
+                } else if entry.heartbeat == 0 {
+                    entry.status = 1;
+                    log("Entry left, pending T_cleanup: ".to_string() + key);
+                }
+            }
+            // Suspicion mode
+            else if curr_mode == 1 {
+                // Timed out, suspect it
+                if entry.status == 0 && time_diff >= TFAIL {
+                    entry.status = 2;
+                    lateness.push(time_diff - TFAIL);
+                    log("Entry timed out, now suspected: ".to_string() + key);
+                }
+                // Alrd suspected, timed out, now failed
+                else if entry.status == 2 && time_diff >= (TFAIL + TSUSTIMEOUT) && !hold_failures
+                {
+                    entry.status = 1;
+                    log("Entry failed, pending T_cleanup: ".to_string() + key);
+                }
+                // Failed, completed T_cleanup
+                else if entry.status == 1 && time_diff >= (TFAIL + TSUSTIMEOUT + TCLEANUP) {
+                    to_remove.push(key.clone());
+                    log("Going to delete entry: ".to_string()
+                        + key
+                        + " time_diff="
+                        + &time_diff.as_secs().to_string());
+                }
+                // Entry gave leave command
+                else if entry.heartbeat == 0 {
+                    entry.status = 1;
+                    log("Entry left, pending T_cleanup: ".to_string() + key);
+                }
+            }
+        }
+
+        let mut tombstones = tombstones_arc.lock().unwrap();
+        delete_entries(&mut mem_list, &mut tombstones, &to_remove, now);
+
+        // Forget tombstones once stale gossip has had time to die out
+        tombstones.retain(|_, tombstone| now.duration_since(tombstone.removed_at) < TTOMBSTONE);
+        drop(tombstones);
+        drop(mem_list);
+
+        if !lateness.is_empty() {
+            let mut stats = stats_arc.lock().unwrap();
+            for late in lateness {
+                record_detection(&mut stats, late);
+            }
+        }
+        thread::sleep(TCHECK);
+    }
+}
+
+// Deletes entries that completed T_cleanup, leaving a tombstone for each
+fn delete_entries(
+    mem_list: &mut HashMap<String, MemListEntry>,
+    tombstones: &mut HashMap<String, Tombstone>,
+    to_remove: &[String],
+    now: Instant,
+) {
+    for item in to_remove.iter() {
+        if let Some(entry) = mem_list.remove(item) {
+            // A member that left never comes back under the same ID
+            let heartbeat = if entry.heartbeat == 0 {
+                u32::MAX
+            } else {
+                entry.heartbeat
+            };
+            tombstones.insert(
+                item.clone(),
+                Tombstone {
+                    heartbeat,
+                    removed_at: now,
+                },
+            );
+        }
+        log("Deleted entry: ".to_string() + item);
+    }
+}
+
+// Adds one timeout's delay past its deadline to the detection statistics
+fn record_detection(stats: &mut NetworkStats, lateness: Duration) {
+    stats.detections += 1;
+    stats.detection_lateness_total += lateness;
+    stats.detection_lateness_max = stats.detection_lateness_max.max(lateness);
+}
+
+// Whether a check at time now would fail this entry because of a timeout
+fn times_out(entry: &MemListEntry, curr_mode: u8, now: Instant) -> bool {
+    let time_diff = now.duration_since(entry.local_time);
+    if entry.heartbeat == 0 {
+        return false;
+    }
+    if curr_mode == 1 {
+        entry.status == 2 && time_diff >= (TFAIL + TSUSTIMEOUT)
+    } else {
+        entry.status == 0 && time_diff >= TFAIL
+    }
+}
+
+// Decides whether failures should be held off because too many members timed out at once
+// Holding lasts at most TPARTITION_HOLD, after which the members are failed as usual
+fn detect_partition(
+    partition_since: &mut Option<Instant>,
+    others: usize,
+    timing_out: usize,
+    now: Instant,
+) -> bool {
+    let mass_failure = timing_out >= PARTITION_MIN_FAILURES
+        && timing_out as f32 >= PARTITION_FRACTION * others as f32;
+    if !mass_failure {
+        if partition_since.take().is_some() {
+            log("Partition cleared".to_string());
+        }
+        return false;
+    }
+
+    let since = *partition_since.get_or_insert_with(|| {
+        log(format!(
+            "Possible partition: {} of {} members timed out, holding off failures",
+            timing_out, others
+        ));
+        now
+    });
+    if now.duration_since(since) < TPARTITION_HOLD {
+        return true;
+    }
+    log("Partition outlasted TPARTITION_HOLD, declaring members failed".to_string());
+    false
+}
+
+// Periodically estimates loss and latency of gossip and advertises them in this member's
+// metadata. Suspicion mode is switched on the worst conditions any live member reports, so
+// members that measure differently still settle on the same mode.
+fn monitor_network(
+    mem_list_arc: Arc<Mutex<HashMap<String, MemListEntry>>>,
+    mode_arc: Arc<Mutex<Mode>>,
+    stats_arc: Arc<Mutex<NetworkStats>>,
+    self_id: String,
+) {
+    loop {
+        thread::sleep(TSWITCH);
+
+        let peers: usize;
+        {
+            let mem_list = mem_list_arc.lock().unwrap();
+
+            // Gracefully terminate thread if peer has requested to leave
+            if mem_list.get(&self_id).unwrap().heartbeat == 0 {
+                return;
+            }
+            peers = mem_list
+                .iter()
+                .filter(|(key, entry)| **key != self_id && entry.status != 1)
+                .count();
+        }
+
+        // Close the current measurement window
+        let loss: f32;
+        let latency: Duration;
+        {
+            let mut stats = stats_arc.lock().unwrap();
+            let now = Instant::now();
+            loss = estimate_loss(
+                stats.datagrams,
+                peers,
+                now.duration_since(stats.window_start),
+            );
+            latency = if stats.interval_count > 0 {
+                stats.interval_total / stats.interval_count
+            } else {
+                Duration::ZERO
+            };
+            stats.loss = loss;
+            stats.latency = latency;
+            stats.window_start = now;
+            stats.datagrams = 0;
+            stats.interval_total = Duration::ZERO;
+            stats.interval_count = 0;
+        }
+
+        // Nothing to measure against when alone
+        if peers == 0 {
+            continue;
+        }
+
+        let (cluster_loss, cluster_latency) = {
+            let mut mem_list = mem_list_arc.lock().unwrap();
+            let self_entry = mem_list.get_mut(&self_id).unwrap();
+            let _ = update_metadata(self_entry, META_LOSS, Some(&format!("{:.2}", loss)));
+            let _ = update_metadata(
+                self_entry,
+                META_LATENCY,
+                Some(&latency.as_millis().to_string()),
+            );
+            cluster_conditions(&mem_list)
+        };
+
+        let (curr_mode, auto) = {
+            let mode = mode_arc.lock().unwrap();
+            (mode.mode, mode.auto)
+        };
+        if !auto {
+            continue;
+        }
+        if let Some(new_mode) = choose_mode(curr_mode, cluster_loss, cluster_latency) {
+            log(format!(
+                "Network monitor switching to mode {} (cluster loss {:.2}, latency {:?})",
+                new_mode, cluster_loss, cluster_latency
+            ));
+            toggle_suspicion_with_cooldown(&mode_arc, new_mode);
+        }
+    }
+}
+
+// Fraction of expected datagrams that did not arrive over a window of the given length
+// Each peer gossips to GOSSIP_NUM random members (possibly itself) every TGOSSIP, or to
+// everyone when the list is small, which sets the expected number of arrivals
+fn estimate_loss(received: u32, peers: usize, elapsed: Duration) -> f32 {
+    let per_round: f32 = if peers <= GOSSIP_NUM {
+        peers as f32
+    } else {
+        (peers * GOSSIP_NUM) as f32 / (peers + 1) as f32
+    };
+    let expected = per_round * elapsed.as_secs_f32() / TGOSSIP.as_secs_f32();
+    if expected <= 0.0 {
+        return 0.0;
+    }
+    (1.0 - received as f32 / expected).clamp(0.0, 1.0)
+}
+
+// Worst loss and latency advertised by any live member, including this one
+// Members that have not measured anything yet are left out
+fn cluster_conditions(mem_list: &HashMap<String, MemListEntry>) -> (f32, Duration) {
+    let mut loss: f32 = 0.0;
+    let mut latency = Duration::ZERO;
+    for entry in mem_list.values() {
+        if entry.status == 1 {
+            continue;
+        }
+        if let Some(reported) = entry.metadata.get(META_LOSS) {
+            loss = loss.max(reported.parse().unwrap_or(0.0));
+        }
+        if let Some(reported) = entry.metadata.get(META_LATENCY) {
+            latency = latency.max(Duration::from_millis(reported.parse().unwrap_or(0)));
+        }
+    }
+    (loss, latency)
+}
+
+// Mode the network monitor wants, or None to stay in the current one
+fn choose_mode(curr_mode: u8, loss: f32, latency: Duration) -> Option<u8> {
+    if curr_mode == 0 && (loss >= LOSS_ENABLE_THRESHOLD || latency >= LATENCY_ENABLE_THRESHOLD) {
+        Some(1)
+    } else if curr_mode == 1
+        && loss <= LOSS_DISABLE_THRESHOLD
+        && latency <= LATENCY_DISABLE_THRESHOLD
+    {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+// Sends queued packets until every sender has been dropped
+// Runs on its own thread so slow sends never hold up gossip, merging or timeout checks
+fn send_outgoing(outbox: Receiver<Outgoing>) {
+    // Let OS assign a port
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    for outgoing in outbox {
+        send_packet(&socket, &outgoing.hostname, outgoing.port, &outgoing.buf);
+    }
+}
+
+// Sends a packet to the specified peer
+fn send_packet(socket: &UdpSocket, dest_hostname: &str, dest_port: u16, buf: &[u8]) {
+    let dest_socket_addr: String =
+        String::from(dest_hostname) + ":" + dest_port.to_string().as_str();
+
+    // Send whole buffer
+    match socket.send_to(buf, dest_socket_addr) {
+        Ok(_) => {
+            log("Gossipped to: ".to_string()
+                + dest_hostname
+                + ":"
+                + dest_port.to_string().as_str());
+        }
+        Err(_) => {
+            log("Send to failed : ".to_string()
+                + dest_hostname
+                + ":"
+                + dest_port.to_string().as_str());
+        }
+    }
+}
+
+// Picks the entries to send to one peer, with whether to include their metadata
+// A full sync sends every live entry with metadata. Otherwise only entries whose heartbeat,
+// status or incarnation changed since the last send to this peer go out, and metadata
+// only if its version changed. Updates the peer's sync state to match.
+fn select_entries<'a>(
+    mem_list: &'a HashMap<String, MemListEntry>,
+    sync: &mut PeerSync,
+    full: bool,
+) -> Vec<(&'a MemListEntry, bool)> {
+    let mut entries: Vec<(&MemListEntry, bool)> = Vec::new();
+    for (key, entry) in mem_list.iter() {
+        // Do not send failed nodes
+        if entry.status == 1 {
+            continue;
+        }
+
+        let state = SentState {
+            heartbeat: entry.heartbeat,
+            status: entry.status,
+            inc_num: entry.inc_num,
+            meta_version: entry.meta_version,
+        };
+        let previous = sync.sent.insert(key.clone(), state);
+        if full {
+            entries.push((entry, true));
+        } else if previous != Some(state) {
+            let with_metadata = previous.is_none_or(|sent| sent.meta_version != entry.meta_version);
+            entries.push((entry, with_metadata));
+        }
+    }
+
+    // Forget entries that are gone, so they are resent in full if they return
+    sync.sent
+        .retain(|key, _| mem_list.get(key).is_some_and(|entry| entry.status != 1));
+
+    if full {
+        sync.rounds_since_full = 0;
+    } else {
+        sync.rounds_since_full += 1;
+    }
+    entries
+}
+
+// Gossips membership list to random subset of peers on set interval
+fn gossip(
+    mem_list_arc: Arc<Mutex<HashMap<String, MemListEntry>>>,
+    mode: Arc<Mutex<Mode>>,
+    traffic_arc: Arc<Mutex<GossipTraffic>>,
+    outbox: Sender<Outgoing>,
+    self_id: String,
+) {
+    let mut last_capacity: Option<Instant> = None;
+    let mut peer_syncs: HashMap<String, PeerSync> = HashMap::new();
+    loop {
+        let curr_mode: u8;
+        {
+            curr_mode = mode.lock().unwrap().mode;
+        }
+        let delta: bool;
+        {
+            delta = traffic_arc.lock().unwrap().delta;
+        }
+
+        // Measure capacity before taking the lock, as it runs a command
+        let mut capacity: Vec<(&str, String)> = Vec::new();
+        if last_capacity.is_none_or(|measured| measured.elapsed() >= TCAPACITY) {
+            capacity = measure_capacity();
+            last_capacity = Some(Instant::now());
+        }
+
+        let mut mem_list = mem_list_arc.lock().unwrap();
+
+        // Increase self heartbeat counter
+        let self_entry = mem_list.get_mut(&self_id).unwrap();
+        self_entry.heartbeat += 1;
+        for (key, value) in capacity.iter() {
+            let _ = update_metadata(self_entry, key, Some(value));
+        }
+
+        let mut to_gossip_to: Vec<String> = Vec::new();
+        // For case where membership list is smaller than desired
+        if mem_list.len() - 1 <= GOSSIP_NUM {
+            for key in mem_list.keys() {
+                // Skip sending to self
+                if key == &self_id {
+                    continue;
+                }
+                to_gossip_to.push(key.clone());
+            }
+        }
+        // Case where membership list is larger and we can choose
+        else {
+            // Create random subset of membership list of length GOSSIP_NUM
+            let mut rng = rand::thread_rng();
+            let mut keys: Vec<_> = mem_list.keys().collect();
+            while to_gossip_to.len() < GOSSIP_NUM {
+                let random_index = rng.gen_range(0..keys.len());
+                let random_key = keys[random_index];
+                to_gossip_to.push(random_key.to_string());
+                keys.remove(random_index);
+            }
+        }
+
+        // Packets are built under the lock but sent after it is released
+        let mut packets: Vec<Outgoing> = Vec::new();
+        let mut full_bytes: usize = 0;
+        let mut delta_bytes: usize = 0;
+        for to_gossip_key in to_gossip_to.iter() {
+            let sync = peer_syncs
+                .entry(to_gossip_key.clone())
+                .or_insert_with(|| PeerSync {
+                    rounds_since_full: 0,
+                    sent: HashMap::new(),
+                });
+            let full_sync = sync.sent.is_empty() || sync.rounds_since_full + 1 >= FULL_SYNC_ROUNDS;
+            let delta_entries = select_entries(&mem_list, sync, full_sync);
+            let full_entries: Vec<(&MemListEntry, bool)> = mem_list
+                .values()
+                .filter(|entry| entry.status != 1)
+                .map(|entry| (entry, true))
+                .collect();
+            full_bytes += packet_length(&full_entries);
+            delta_bytes += packet_length(&delta_entries);
+
+            let entries = if delta { delta_entries } else { full_entries };
+            let peer = &mem_list[to_gossip_key];
+            for buf in build_packets(&mem_list[&self_id], &entries, curr_mode, &CLUSTER_KEY) {
+                packets.push(Outgoing {
+                    hostname: peer.hostname.clone(),
+                    port: peer.port,
+                    buf,
+                });
+            }
+        }
+
+        // Forget peers that have been removed
+        peer_syncs.retain(|key, _| mem_list.contains_key(key));
+
+        {
+            let mut traffic = traffic_arc.lock().unwrap();
+            traffic.full_bytes += full_bytes as u64;
+            traffic.delta_bytes += delta_bytes as u64;
+        }
+
+        let heartbeat = mem_list.get(&self_id).unwrap().heartbeat;
+        drop(mem_list);
+
+        for packet in packets {
+            let _ = outbox.send(packet);
+        }
+
+        // Gracefully leave after gossipping
+        if heartbeat == 0 {
+            return;
+        }
+
+        thread::sleep(TGOSSIP);
+    }
+}
+
+#[cfg(test)]
+#[path = "tests.rs"]
+mod tests;
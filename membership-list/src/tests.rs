@@ -0,0 +1,642 @@
+use super::*;
+
+fn entry(hostname: &str, timestamp: u64, nonce: u32, heartbeat: u32) -> MemListEntry {
+    MemListEntry {
+        hostname: String::from(hostname),
+        port: HEARTBEAT_PORT,
+        timestamp,
+        nonce,
+        heartbeat,
+        local_time: Instant::now(),
+        status: 0,
+        inc_num: 1,
+        metadata: BTreeMap::new(),
+        meta_version: 1,
+    }
+}
+
+fn merge(
+    mem_list: &mut HashMap<String, MemListEntry>,
+    tombstones: &mut HashMap<String, Tombstone>,
+    new_entry: MemListEntry,
+) -> String {
+    let id = generate_id(
+        &new_entry.hostname,
+        new_entry.port,
+        new_entry.timestamp,
+        new_entry.nonce,
+    );
+    merge_entry(
+        mem_list,
+        tombstones,
+        id.clone(),
+        new_entry,
+        0,
+        &String::from("self"),
+    );
+    id
+}
+
+#[test]
+fn restart_within_same_second_gets_new_id() {
+    let mut mem_list = HashMap::new();
+    let mut tombstones = HashMap::new();
+
+    // The member fails and is deleted, leaving a tombstone
+    let old_id = merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 5),
+    );
+    mem_list.get_mut(&old_id).unwrap().status = 1;
+    delete_entries(
+        &mut mem_list,
+        &mut tombstones,
+        std::slice::from_ref(&old_id),
+        Instant::now(),
+    );
+    assert!(mem_list.is_empty());
+    assert_eq!(tombstones[&old_id].heartbeat, 5);
+
+    // It restarts on the same host and port within the same second, with a fresh nonce
+    let new_id = merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 2, 1),
+    );
+    assert_ne!(new_id, old_id);
+    assert_eq!(mem_list[&new_id].status, 0);
+
+    // A late heartbeat of the previous incarnation is still rejected by its tombstone
+    merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 5),
+    );
+    assert!(!mem_list.contains_key(&old_id));
+    assert!(tombstones.contains_key(&old_id));
+    assert_eq!(mem_list.len(), 1);
+}
+
+#[test]
+fn entry_bytes_round_trip_nonce() {
+    let mut buf: [u8; DATAGRAM_LENGTH] = [0; DATAGRAM_LENGTH];
+    populate_entry_bytes(&mut buf, &entry("vm2", 1700000000, 0xCAFEBABE, 7), 1);
+    let (mode, decoded) = read_entry_bytes(&buf);
+    assert_eq!(mode, 1);
+    assert_eq!(decoded.hostname, "vm2");
+    assert_eq!(decoded.nonce, 0xCAFEBABE);
+    assert_eq!(decoded.heartbeat, 7);
+}
+
+#[test]
+fn tombstone_rejects_stale_heartbeat() {
+    let mut mem_list = HashMap::new();
+    let mut tombstones = HashMap::new();
+    let id = generate_id("vm2", HEARTBEAT_PORT, 1700000000, 1);
+    tombstones.insert(
+        id.clone(),
+        Tombstone {
+            heartbeat: 10,
+            removed_at: Instant::now(),
+        },
+    );
+
+    merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 10),
+    );
+    assert!(!mem_list.contains_key(&id));
+    assert!(tombstones.contains_key(&id));
+
+    // A newer heartbeat means the member really is alive
+    merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 11),
+    );
+    assert!(mem_list.contains_key(&id));
+    assert!(!tombstones.contains_key(&id));
+}
+
+#[test]
+fn restart_within_same_second_not_blocked_by_tombstone() {
+    let mut mem_list = HashMap::new();
+    let mut tombstones = HashMap::new();
+    let old_id = generate_id("vm2", HEARTBEAT_PORT, 1700000000, 1);
+    tombstones.insert(
+        old_id.clone(),
+        Tombstone {
+            heartbeat: u32::MAX,
+            removed_at: Instant::now(),
+        },
+    );
+
+    // Same hostname, port and second, but a fresh nonce
+    let new_id = merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 2, 1),
+    );
+    assert!(mem_list.contains_key(&new_id));
+
+    // Late gossip about the previous incarnation is still rejected
+    merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 50),
+    );
+    assert!(!mem_list.contains_key(&old_id));
+    assert_eq!(mem_list.len(), 1);
+}
+
+#[test]
+fn expired_tombstone_allows_reinsert() {
+    let mut mem_list = HashMap::new();
+    let mut tombstones = HashMap::new();
+    let id = generate_id("vm2", HEARTBEAT_PORT, 1700000000, 1);
+    tombstones.insert(
+        id.clone(),
+        Tombstone {
+            heartbeat: 10,
+            removed_at: Instant::now() - TTOMBSTONE,
+        },
+    );
+
+    merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 5),
+    );
+    assert!(mem_list.contains_key(&id));
+}
+
+#[test]
+fn loss_estimate_from_arrivals() {
+    // Two peers gossip to everyone, so ten rounds should bring twenty datagrams
+    let ten_rounds = TGOSSIP * 10;
+    assert!(estimate_loss(20, 2, ten_rounds) < 0.01);
+    assert!((estimate_loss(10, 2, ten_rounds) - 0.5).abs() < 0.01);
+    assert_eq!(estimate_loss(0, 0, ten_rounds), 0.0);
+}
+
+#[test]
+fn mode_switching_has_hysteresis() {
+    let fast = Duration::from_millis(400);
+    assert_eq!(choose_mode(0, 0.5, fast), Some(1));
+    assert_eq!(choose_mode(0, 0.0, LATENCY_ENABLE_THRESHOLD), Some(1));
+    // Between the thresholds neither mode changes
+    assert_eq!(choose_mode(0, 0.2, fast), None);
+    assert_eq!(choose_mode(1, 0.2, fast), None);
+    assert_eq!(choose_mode(1, 0.0, fast), Some(0));
+}
+
+#[test]
+fn mode_follows_worst_reported_conditions() {
+    let fast = Duration::from_millis(400);
+    let mut mem_list = HashMap::new();
+    for (i, (hostname, loss)) in [("vm1", "0.05"), ("vm2", "0.40"), ("vm3", "0.00")]
+        .iter()
+        .enumerate()
+    {
+        let mut member = entry(hostname, 1700000000, i as u32, 1);
+        update_metadata(&mut member, META_LOSS, Some(loss)).unwrap();
+        update_metadata(&mut member, META_LATENCY, Some("400")).unwrap();
+        mem_list.insert(member.hostname.clone(), member);
+    }
+    // Failed members and members yet to measure do not count
+    let mut failed = entry("vm4", 1700000000, 4, 1);
+    update_metadata(&mut failed, META_LOSS, Some("0.90")).unwrap();
+    failed.status = 1;
+    mem_list.insert(failed.hostname.clone(), failed);
+    mem_list.insert(String::from("vm5"), entry("vm5", 1700000000, 5, 1));
+
+    // vm1 and vm3 alone would stay in normal mode, but every member sees vm2's loss
+    assert_eq!(choose_mode(0, 0.05, fast), None);
+    let (loss, latency) = cluster_conditions(&mem_list);
+    assert!((loss - 0.4).abs() < 0.01);
+    assert_eq!(latency, fast);
+    assert_eq!(choose_mode(0, loss, latency), Some(1));
+
+    // Once in suspicion mode, the members that see no loss do not switch back while vm2
+    // still reports loss between the thresholds
+    assert_eq!(choose_mode(1, 0.0, fast), Some(0));
+    update_metadata(mem_list.get_mut("vm2").unwrap(), META_LOSS, Some("0.20")).unwrap();
+    let (loss, latency) = cluster_conditions(&mem_list);
+    assert_eq!(choose_mode(1, loss, latency), None);
+
+    update_metadata(mem_list.get_mut("vm2").unwrap(), META_LOSS, Some("0.00")).unwrap();
+    let (loss, latency) = cluster_conditions(&mem_list);
+    assert_eq!(choose_mode(1, loss, latency), Some(0));
+}
+
+#[test]
+fn mass_timeout_is_held_as_partition() {
+    let mut partition_since = None;
+    let start = Instant::now();
+
+    // A single timeout is an ordinary failure
+    assert!(!detect_partition(&mut partition_since, 8, 1, start));
+    assert!(partition_since.is_none());
+
+    // Most members timing out together is held off...
+    assert!(detect_partition(&mut partition_since, 8, 5, start));
+    assert!(detect_partition(&mut partition_since, 8, 5, start + TFAIL));
+
+    // ...but only until TPARTITION_HOLD has passed
+    assert!(!detect_partition(
+        &mut partition_since,
+        8,
+        5,
+        start + TPARTITION_HOLD
+    ));
+
+    // Recovery clears the partition state
+    assert!(!detect_partition(&mut partition_since, 8, 0, start));
+    assert!(partition_since.is_none());
+}
+
+#[test]
+fn fresh_heartbeat_reports_interval() {
+    let mut mem_list = HashMap::new();
+    let mut tombstones = HashMap::new();
+    let id = merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 1),
+    );
+
+    let new_entry = entry("vm2", 1700000000, 1, 2);
+    let interval = merge_entry(
+        &mut mem_list,
+        &mut tombstones,
+        id.clone(),
+        new_entry,
+        0,
+        &String::from("self"),
+    );
+    assert!(interval.is_some());
+
+    // Repeating an old heartbeat says nothing about latency
+    let old_entry = entry("vm2", 1700000000, 1, 2);
+    let interval = merge_entry(
+        &mut mem_list,
+        &mut tombstones,
+        id,
+        old_entry,
+        0,
+        &String::from("self"),
+    );
+    assert!(interval.is_none());
+}
+
+#[test]
+fn hmac_matches_rfc_4231() {
+    let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+    let hex: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+    assert_eq!(
+        hex,
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+}
+
+fn signed_packet(key: &[u8], seq: u64) -> Vec<u8> {
+    let sender = entry("vm2", 1700000000, 1, 1);
+    build_packet(&sender, seq, &[(&sender, true)], 0, key)
+}
+
+#[test]
+fn unsigned_and_forged_packets_are_rejected() {
+    let mut windows = HashMap::new();
+
+    // Truncated packets and packets without a MAC
+    let unsigned: [u8; MIN_PACKET_LENGTH] = [0; MIN_PACKET_LENGTH];
+    assert_eq!(
+        verify_packet(&unsigned[..HEADER_LENGTH], b"secret", &mut windows),
+        Err(PacketRejection::Unsigned)
+    );
+    assert_eq!(
+        verify_packet(&unsigned, b"secret", &mut windows),
+        Err(PacketRejection::Unsigned)
+    );
+
+    // Signed with the wrong key
+    let forged = signed_packet(b"not the secret", 1);
+    assert_eq!(
+        verify_packet(&forged, b"secret", &mut windows),
+        Err(PacketRejection::BadMac)
+    );
+
+    // Tampered after signing
+    let mut tampered = signed_packet(b"secret", 1);
+    tampered[HEADER_LENGTH + STATUS_OFFSET] = 1;
+    assert_eq!(
+        verify_packet(&tampered, b"secret", &mut windows),
+        Err(PacketRejection::BadMac)
+    );
+}
+
+#[test]
+fn replayed_packets_are_rejected() {
+    let mut windows = HashMap::new();
+    let first = signed_packet(b"secret", 5);
+    assert_eq!(verify_packet(&first, b"secret", &mut windows), Ok(()));
+    assert_eq!(
+        verify_packet(&first, b"secret", &mut windows),
+        Err(PacketRejection::Replayed)
+    );
+
+    // Reordered but unseen packets inside the window are still accepted, once
+    let later = signed_packet(b"secret", 9);
+    let reordered = signed_packet(b"secret", 7);
+    assert_eq!(verify_packet(&later, b"secret", &mut windows), Ok(()));
+    assert_eq!(verify_packet(&reordered, b"secret", &mut windows), Ok(()));
+    assert_eq!(
+        verify_packet(&reordered, b"secret", &mut windows),
+        Err(PacketRejection::Replayed)
+    );
+
+    // Anything older than the window is refused
+    let newest = signed_packet(b"secret", 9 + REPLAY_WINDOW);
+    assert_eq!(verify_packet(&newest, b"secret", &mut windows), Ok(()));
+    let ancient = signed_packet(b"secret", 8);
+    assert_eq!(
+        verify_packet(&ancient, b"secret", &mut windows),
+        Err(PacketRejection::Replayed)
+    );
+}
+
+#[test]
+fn tombstoned_senders_only_get_later_sequence_numbers_through() {
+    let mut windows = HashMap::new();
+    let captured = signed_packet(b"secret", 5);
+    assert_eq!(verify_packet(&captured, b"secret", &mut windows), Ok(()));
+
+    // The sender goes quiet for longer than TTOMBSTONE, then another sender is heard from
+    let quiet = windows.values_mut().next().unwrap();
+    quiet.last_seen -= TTOMBSTONE;
+    let other = entry("vm3", 1700000000, 1, 1);
+    let heard = build_packet(&other, 1, &[(&other, true)], 0, b"secret");
+    assert_eq!(verify_packet(&heard, b"secret", &mut windows), Ok(()));
+
+    // Packets from before the tombstone stay refused, even ones never seen
+    assert_eq!(
+        verify_packet(&captured, b"secret", &mut windows),
+        Err(PacketRejection::Replayed)
+    );
+    let unseen = signed_packet(b"secret", 4);
+    assert_eq!(
+        verify_packet(&unseen, b"secret", &mut windows),
+        Err(PacketRejection::Replayed)
+    );
+
+    // A later packet brings the window back, reordering included
+    let later = signed_packet(b"secret", 6);
+    assert_eq!(verify_packet(&later, b"secret", &mut windows), Ok(()));
+    let newer = signed_packet(b"secret", 8);
+    let reordered = signed_packet(b"secret", 7);
+    assert_eq!(verify_packet(&newer, b"secret", &mut windows), Ok(()));
+    assert_eq!(verify_packet(&reordered, b"secret", &mut windows), Ok(()));
+    assert_eq!(
+        verify_packet(&later, b"secret", &mut windows),
+        Err(PacketRejection::Replayed)
+    );
+}
+
+#[test]
+fn metadata_round_trips_through_packet() {
+    let mut sender = entry("vm2", 1700000000, 1, 3);
+    update_metadata(&mut sender, META_ROLES, Some("datanode,worker")).unwrap();
+    update_metadata(&mut sender, META_FREE_DISK, Some("1048576")).unwrap();
+    let other = entry("vm3", 1700000000, 2, 8);
+
+    let packet = build_packet(&sender, 1, &[(&sender, true), (&other, true)], 1, b"secret");
+    let entries = read_packet_entries(&packet);
+    assert_eq!(entries.len(), 2);
+
+    let (mode, decoded) = &entries[0];
+    assert_eq!(*mode, 1);
+    assert_eq!(decoded.meta_version, 3);
+    assert_eq!(decoded.metadata[META_ROLES], "datanode,worker");
+    assert_eq!(decoded.metadata[META_FREE_DISK], "1048576");
+    assert!(entries[1].1.metadata.is_empty());
+}
+
+#[test]
+fn metadata_updates_bump_version_and_respect_limits() {
+    let mut member = entry("vm2", 1700000000, 1, 1);
+    assert_eq!(
+        update_metadata(&mut member, "port.sdfs", Some("38333")),
+        Ok(true)
+    );
+    assert_eq!(member.meta_version, 2);
+
+    // Setting the same value is not a change
+    assert_eq!(
+        update_metadata(&mut member, "port.sdfs", Some("38333")),
+        Ok(false)
+    );
+    assert_eq!(member.meta_version, 2);
+
+    let long_value = "x".repeat(255);
+    for i in 0..METADATA_MAX_LENGTH / 255 - 1 {
+        update_metadata(&mut member, &format!("k{}", i), Some(&long_value)).unwrap();
+    }
+    assert!(update_metadata(&mut member, "overflow", Some(&long_value)).is_err());
+    assert!(metadata_length(&member.metadata) <= METADATA_MAX_LENGTH);
+
+    assert_eq!(update_metadata(&mut member, "port.sdfs", None), Ok(true));
+    assert!(!member.metadata.contains_key("port.sdfs"));
+}
+
+#[test]
+fn self_metadata_file_respects_limits() {
+    let metadata =
+        parse_metadata("roles = datanode,worker\nno separator\nport.sdfs=38333\n").unwrap();
+    assert_eq!(metadata.len(), 2);
+    assert_eq!(metadata[META_ROLES], "datanode,worker");
+
+    // Lengths are sent in a single byte, so longer keys or values cannot be loaded
+    let long = "x".repeat(256);
+    assert!(parse_metadata(&format!("{}=1", long)).is_err());
+    assert!(parse_metadata(&format!("key={}", long)).is_err());
+    assert!(parse_metadata("=value").is_err());
+    assert!(parse_metadata(&format!("key={}", "x".repeat(255))).is_ok());
+}
+
+#[test]
+fn newer_metadata_replaces_older() {
+    let mut mem_list = HashMap::new();
+    let mut tombstones = HashMap::new();
+    let id = merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 1),
+    );
+
+    let mut updated = entry("vm2", 1700000000, 1, 1);
+    update_metadata(&mut updated, META_ROLES, Some("master")).unwrap();
+    merge(&mut mem_list, &mut tombstones, updated);
+    assert_eq!(mem_list[&id].metadata[META_ROLES], "master");
+
+    // An older version arriving late is ignored, even with a newer heartbeat
+    merge(
+        &mut mem_list,
+        &mut tombstones,
+        entry("vm2", 1700000000, 1, 9),
+    );
+    assert_eq!(mem_list[&id].metadata[META_ROLES], "master");
+    assert_eq!(mem_list[&id].heartbeat, 9);
+}
+
+#[test]
+fn delta_sends_only_changed_entries() {
+    let mut mem_list = HashMap::new();
+    for (i, hostname) in ["vm1", "vm2", "vm3"].iter().enumerate() {
+        let member = entry(hostname, 1700000000, i as u32, 1);
+        mem_list.insert(member.hostname.clone(), member);
+    }
+    let mut sync = PeerSync {
+        rounds_since_full: 0,
+        sent: HashMap::new(),
+    };
+
+    // First contact sends everything with metadata
+    assert_eq!(select_entries(&mem_list, &mut sync, true).len(), 3);
+    assert!(select_entries(&mem_list, &mut sync, false).is_empty());
+
+    // A heartbeat alone goes out without metadata
+    mem_list.get_mut("vm2").unwrap().heartbeat += 1;
+    let entries = select_entries(&mem_list, &mut sync, false);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0.hostname, "vm2");
+    assert!(!entries[0].1);
+
+    // Metadata is included once its version changes
+    update_metadata(mem_list.get_mut("vm3").unwrap(), META_ROLES, Some("worker")).unwrap();
+    let entries = select_entries(&mem_list, &mut sync, false);
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].1);
+    assert_eq!(sync.rounds_since_full, 3);
+
+    // Omitted metadata decodes as version 0 so the receiver keeps its copy
+    let packet = build_packet(
+        &mem_list["vm1"],
+        1,
+        &[(&mem_list["vm3"], false)],
+        0,
+        b"secret",
+    );
+    assert_eq!(packet.len(), packet_length(&[(&mem_list["vm3"], false)]));
+    let entries = read_packet_entries(&packet);
+    assert_eq!(entries[0].1.meta_version, 0);
+    assert!(entries[0].1.metadata.is_empty());
+}
+
+#[test]
+fn long_lists_are_split_across_packets() {
+    let mut mem_list = HashMap::new();
+    for i in 0..2 * MAX_ENTRIES + 5 {
+        let member = entry(&format!("vm{}", i), 1700000000, i as u32, 1);
+        mem_list.insert(member.hostname.clone(), member);
+    }
+    let mut sync = PeerSync {
+        rounds_since_full: 0,
+        sent: HashMap::new(),
+    };
+
+    // Every entry recorded as sent goes out, with the sender leading the first packet
+    let entries = select_entries(&mem_list, &mut sync, true);
+    assert_eq!(sync.sent.len(), mem_list.len());
+    let sender = &mem_list["vm7"];
+    let packets = build_packets(sender, &entries, 0, b"secret");
+    assert_eq!(packets.len(), 3);
+    assert_eq!(
+        packets.iter().map(|packet| packet.len()).sum::<usize>(),
+        packet_length(&entries)
+    );
+
+    let mut windows = HashMap::new();
+    let mut received: Vec<String> = Vec::new();
+    for packet in packets.iter() {
+        assert_eq!(verify_packet(packet, b"secret", &mut windows), Ok(()));
+        let entries = read_packet_entries(packet);
+        assert!(entries.len() <= MAX_ENTRIES);
+        received.extend(entries.into_iter().map(|(_, entry)| entry.hostname));
+    }
+    assert_eq!(received[0], "vm7");
+    received.sort();
+    received.dedup();
+    assert_eq!(received.len(), mem_list.len());
+}
+
+#[test]
+fn outbox_thread_sends_queued_packets() {
+    let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let port = receiver.local_addr().unwrap().port();
+
+    let (outbox, outbox_receiver) = mpsc::channel();
+    let sender = thread::spawn(move || send_outgoing(outbox_receiver));
+    let packet = signed_packet(b"secret", 1);
+    outbox
+        .send(Outgoing {
+            hostname: "127.0.0.1".to_string(),
+            port,
+            buf: packet.clone(),
+        })
+        .unwrap();
+
+    let mut buf = [0; MAX_PACKET_LENGTH];
+    let size = receiver.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..size], packet.as_slice());
+
+    drop(outbox);
+    sender.join().unwrap();
+}
+
+#[test]
+fn detection_lateness_is_tracked() {
+    let mut stats = NetworkStats {
+        window_start: Instant::now(),
+        datagrams: 0,
+        interval_total: Duration::ZERO,
+        interval_count: 0,
+        loss: 0.0,
+        latency: Duration::ZERO,
+        partition_since: None,
+        dropped_unsigned: 0,
+        dropped_bad_mac: 0,
+        dropped_replayed: 0,
+        detections: 0,
+        detection_lateness_total: Duration::ZERO,
+        detection_lateness_max: Duration::ZERO,
+    };
+    record_detection(&mut stats, Duration::from_millis(20));
+    record_detection(&mut stats, Duration::from_millis(80));
+    assert_eq!(stats.detections, 2);
+    assert_eq!(
+        stats.detection_lateness_total / stats.detections,
+        Duration::from_millis(50)
+    );
+    assert_eq!(stats.detection_lateness_max, Duration::from_millis(80));
+}
+
+#[test]
+fn member_roles_are_read_from_metadata() {
+    let mut metadata = BTreeMap::new();
+    metadata.insert(META_ROLES.to_string(), "master, datanode".to_string());
+    let member = Member {
+        id: "vm2:50001:1700000000:00000001".to_string(),
+        hostname: "vm2".to_string(),
+        port: HEARTBEAT_PORT,
+        status: 0,
+        metadata,
+    };
+    assert!(member.has_role("datanode"));
+    assert!(member.has_role("master"));
+    assert!(!member.has_role("worker"));
+}